@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS quiz_attempt (
+    id            BIGSERIAL PRIMARY KEY,
+    quiz_type     TEXT NOT NULL,
+    channel_id    BIGINT NOT NULL,
+    user_id       BIGINT NOT NULL,
+    question      TEXT NOT NULL,
+    answer        TEXT NOT NULL,
+    correct       BOOLEAN NOT NULL,
+    solve_time_ms INT CHECK (solve_time_ms >= 0),
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS quiz_attempt_user_idx ON quiz_attempt (user_id, created_at);
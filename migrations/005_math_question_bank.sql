@@ -0,0 +1,13 @@
+-- 001 created the table as "mathquestion" while the code always used "math_question"
+-- (the name pgloader carries over from SQLite). Make sure the bank exists either way.
+CREATE TABLE IF NOT EXISTS math_question (
+    id       BIGSERIAL        PRIMARY KEY,
+    question TEXT             NOT NULL,
+    answer   DOUBLE PRECISION NOT NULL
+);
+
+ALTER TABLE math_question ADD COLUMN IF NOT EXISTS difficulty TEXT NOT NULL DEFAULT 'easy';
+ALTER TABLE math_question ADD COLUMN IF NOT EXISTS source     TEXT NOT NULL DEFAULT 'llm';
+
+CREATE INDEX IF NOT EXISTS math_question_question_idx ON math_question (question);
+CREATE INDEX IF NOT EXISTS math_question_difficulty_idx ON math_question (difficulty, source);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS channel_settings (
+    channel_id           BIGINT  PRIMARY KEY,
+    quiz_chance          INT     NOT NULL DEFAULT 500 CHECK (quiz_chance >= 0),
+    quiz_timeout_penalty BOOLEAN NOT NULL DEFAULT false
+);
@@ -0,0 +1,2 @@
+-- Answers that arrived faster than a person could type them
+ALTER TABLE quiz_attempt ADD COLUMN IF NOT EXISTS flagged BOOLEAN NOT NULL DEFAULT false;
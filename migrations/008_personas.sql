@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS persona (
+    id           BIGSERIAL PRIMARY KEY,
+    name         TEXT    NOT NULL UNIQUE,
+    -- Tera template rendered into the system prompt
+    template     TEXT    NOT NULL,
+    -- NULL uses the configured chat model / the provider default
+    model        TEXT,
+    temperature  REAL,
+    use_memories BOOLEAN NOT NULL DEFAULT true,
+    use_examples BOOLEAN NOT NULL DEFAULT true
+);
+
+-- Channel and guild ids are both snowflakes, so one table holds both kinds of assignment.
+CREATE TABLE IF NOT EXISTS persona_assignment (
+    target_id  BIGINT PRIMARY KEY,
+    kind       TEXT   NOT NULL CHECK (kind IN ('channel', 'guild')),
+    persona_id BIGINT NOT NULL REFERENCES persona(id) ON DELETE CASCADE
+);
@@ -0,0 +1,21 @@
+ALTER TABLE channel_settings ADD COLUMN IF NOT EXISTS ai_threads BOOLEAN NOT NULL DEFAULT false;
+
+-- Threads the bot started to hold an AI conversation. The parent channel decides the persona.
+CREATE TABLE IF NOT EXISTS ai_thread (
+    thread_id  BIGINT PRIMARY KEY,
+    parent_id  BIGINT NOT NULL,
+    started_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS ai_thread_message (
+    message_id  BIGINT PRIMARY KEY,
+    thread_id   BIGINT  NOT NULL REFERENCES ai_thread(thread_id) ON DELETE CASCADE,
+    author_name TEXT    NOT NULL,
+    from_bot    BOOLEAN NOT NULL DEFAULT false,
+    content     TEXT    NOT NULL,
+    reply_to    BIGINT,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS ai_thread_message_thread_idx ON ai_thread_message (thread_id, message_id);
@@ -0,0 +1,15 @@
+-- Thumbs up/down on AI replies, with what was asked and answered. One vote per user and reply.
+CREATE TABLE IF NOT EXISTS ai_feedback (
+    message_id    BIGINT   NOT NULL,
+    user_id       BIGINT   NOT NULL,
+    rating        SMALLINT NOT NULL CHECK (rating IN (-1, 1)),
+    persona       TEXT     NOT NULL,
+    model         TEXT     NOT NULL,
+    system_prompt TEXT     NOT NULL,
+    user_message  TEXT     NOT NULL,
+    response      TEXT     NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (message_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS ai_feedback_persona_idx ON ai_feedback (persona, model);
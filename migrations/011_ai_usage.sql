@@ -0,0 +1,17 @@
+-- One row per model request. model is the one that answered, which may be a fallback. Tokens and cost (USD) are
+-- the provider's when it reports them, otherwise tokens are estimated from the text and priced per model.
+CREATE TABLE IF NOT EXISTS ai_usage (
+    id                BIGSERIAL PRIMARY KEY,
+    feature           TEXT   NOT NULL,
+    user_id           BIGINT,
+    guild_id          BIGINT,
+    model             TEXT   NOT NULL,
+    prompt_tokens     INT    NOT NULL CHECK (prompt_tokens >= 0),
+    completion_tokens INT    NOT NULL CHECK (completion_tokens >= 0),
+    cost              DOUBLE PRECISION NOT NULL DEFAULT 0,
+    created_at        TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS ai_usage_user_idx ON ai_usage (user_id, created_at);
+CREATE INDEX IF NOT EXISTS ai_usage_guild_idx ON ai_usage (guild_id, created_at);
+CREATE INDEX IF NOT EXISTS ai_usage_created_idx ON ai_usage (created_at);
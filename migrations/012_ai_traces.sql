@@ -0,0 +1,21 @@
+-- The prompt, reply and timing of AI calls, recorded only when tracing is turned on. Pruned by age.
+CREATE TABLE IF NOT EXISTS ai_trace (
+    id            BIGSERIAL PRIMARY KEY,
+    user_id       BIGINT,
+    channel_id    BIGINT,
+    guild_id      BIGINT,
+    persona       TEXT    NOT NULL,
+    model         TEXT    NOT NULL,
+    temperature   REAL,
+    system_prompt TEXT    NOT NULL,
+    user_message  TEXT    NOT NULL,
+    response      TEXT    NOT NULL,
+    -- Set when the call failed or the reply was suppressed
+    error         TEXT,
+    latency_ms    INT     NOT NULL CHECK (latency_ms >= 0),
+    -- The trace this one re-ran from the web panel
+    rerun_of      BIGINT  REFERENCES ai_trace(id) ON DELETE SET NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS ai_trace_created_idx ON ai_trace (created_at);
@@ -0,0 +1,22 @@
+-- Unprompted AI replies: 1 in `interjection_chance` messages (0 never), at most once per cooldown (seconds), and
+-- never during the quiet hours (UTC, may wrap around midnight).
+ALTER TABLE channel_settings ADD COLUMN IF NOT EXISTS interjection_chance INT NOT NULL DEFAULT 200
+    CHECK (interjection_chance >= 0);
+ALTER TABLE channel_settings ADD COLUMN IF NOT EXISTS interjection_cooldown INT NOT NULL DEFAULT 600
+    CHECK (interjection_cooldown >= 0);
+ALTER TABLE channel_settings ADD COLUMN IF NOT EXISTS quiet_hours_start SMALLINT
+    CHECK (quiet_hours_start BETWEEN 0 AND 23);
+ALTER TABLE channel_settings ADD COLUMN IF NOT EXISTS quiet_hours_end SMALLINT
+    CHECK (quiet_hours_end BETWEEN 0 AND 23);
+
+-- Messages that passed the chance roll, and whether the relevance check let the bot reply. For tuning.
+CREATE TABLE IF NOT EXISTS ai_interjection (
+    id          BIGSERIAL PRIMARY KEY,
+    channel_id  BIGINT  NOT NULL,
+    message_id  BIGINT  NOT NULL,
+    interjected BOOLEAN NOT NULL,
+    reason      TEXT    NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS ai_interjection_channel_idx ON ai_interjection (channel_id, created_at);
@@ -0,0 +1,10 @@
+-- A rolling summary of what each channel talked about, kept up to date by the memory model so the chat model knows
+-- more than the cached messages.
+CREATE TABLE IF NOT EXISTS channel_summary (
+    channel_id    BIGINT PRIMARY KEY,
+    guild_id      BIGINT,
+    summary       TEXT NOT NULL,
+    -- Messages folded into the summary since it was started or reset
+    message_count INT  NOT NULL DEFAULT 0,
+    updated_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
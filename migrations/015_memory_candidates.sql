@@ -0,0 +1,12 @@
+-- Memories people asked the bot to keep with "Remember this", waiting for approval in the web panel.
+CREATE TABLE IF NOT EXISTS memory_candidate (
+    id         BIGSERIAL PRIMARY KEY,
+    user_id    BIGINT NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+    key        TEXT   NOT NULL,
+    content    TEXT   NOT NULL,
+    -- Who asked, and the message they asked about
+    staged_by  BIGINT NOT NULL,
+    message_id BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (user_id, message_id)
+);
@@ -0,0 +1,9 @@
+-- Embeddings of memories, for picking the memories relevant to a message. `source` is the text that was embedded,
+-- so edited memories are embedded again.
+CREATE TABLE IF NOT EXISTS memory_embedding (
+    memory_id  BIGINT PRIMARY KEY REFERENCES memory(id) ON DELETE CASCADE,
+    model      TEXT   NOT NULL,
+    source     TEXT   NOT NULL,
+    embedding  REAL[] NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
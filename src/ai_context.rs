@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_model::id::{marker::ChannelMarker, Id};
+
+/// Longest slice of a single message kept in the context, in bytes
+pub const MAX_MESSAGE_BYTES: usize = 2400;
+/// How many cached messages are considered at most
+const MAX_SCAN: usize = 100;
+/// Rough per-line overhead for the speaker name and separators
+const LINE_OVERHEAD_TOKENS: usize = 4;
+
+/// A message that may end up in the AI context.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextMessage {
+    pub id: u64,
+    pub author: String,
+    pub content: String,
+    /// The message this one replies to
+    pub reply_to: Option<u64>,
+    /// Mentions the bot or the user being answered
+    pub mentions_participant: bool,
+}
+
+impl ContextMessage {
+    fn line(&self) -> String {
+        format!("{}: {}", self.author, self.content)
+    }
+}
+
+/// Cuts `text` to at most `max_bytes` without splitting a character.
+pub fn truncate(text: &str, max_bytes: usize) -> &str {
+    &text[..text.floor_char_boundary(max_bytes.min(text.len()))]
+}
+
+/// A cheap token estimate, about four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4) + LINE_OVERHEAD_TOKENS
+}
+
+/// Builds the transcript for the AI from `messages` (newest first), staying within `token_budget`.
+///
+/// The reply chain leading to `current` and messages mentioning a participant are kept first, then the rest is
+/// filled from newest to oldest. The transcript is returned oldest first, one message per line.
+pub fn build_context(messages: &[ContextMessage], current: u64, token_budget: usize) -> String {
+    let mut reply_chain = HashSet::new();
+    let mut next = Some(current);
+    while let Some(id) = next {
+        if !reply_chain.insert(id) {
+            break;
+        }
+        next = messages.iter().find(|m| m.id == id).and_then(|m| m.reply_to);
+    }
+
+    // The reply chain first, then mentions, then everything else; the stable sort keeps each newest first
+    let tier = |message: &ContextMessage| {
+        if reply_chain.contains(&message.id) {
+            0
+        } else if message.mentions_participant {
+            1
+        } else {
+            2
+        }
+    };
+    let mut order = (0..messages.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| tier(&messages[i]));
+
+    let mut kept = vec![false; messages.len()];
+    let mut used = 0;
+    for i in order {
+        let cost = estimate_tokens(&messages[i].line());
+        if used + cost > token_budget {
+            // Older plain messages only matter if the newer ones fit
+            if tier(&messages[i]) == 2 {
+                break;
+            }
+            continue;
+        }
+        used += cost;
+        kept[i] = true;
+    }
+
+    messages
+        .iter()
+        .zip(kept)
+        .filter(|(_, kept)| *kept)
+        .map(|(message, _)| message.line())
+        .rev()
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The cached messages of `channel_id`, newest first. The bot is called "The Trickster", and mentions of
+/// `bot_id` or `user_id` mark a message as mentioning a participant.
+pub fn cached_messages(
+    cache: &InMemoryCache,
+    channel_id: Id<ChannelMarker>,
+    bot_id: u64,
+    user_id: u64,
+) -> Vec<ContextMessage> {
+    let Some(ids) = cache.channel_messages(channel_id) else {
+        return Vec::new();
+    };
+
+    ids.iter()
+        .take(MAX_SCAN)
+        .filter_map(|id| {
+            let message = cache.message(*id)?;
+            let author = match message.author().get() {
+                id if id == bot_id => "The Trickster".to_string(),
+                _ => cache
+                    .user(message.author())
+                    .map(|user| user.name.clone())
+                    .unwrap_or_default(),
+            };
+            let content = truncate(message.content(), MAX_MESSAGE_BYTES).replace(&bot_id.to_string(), "The Trickster");
+            Some(ContextMessage {
+                id: id.get(),
+                author,
+                content,
+                reply_to: message
+                    .reference()
+                    .and_then(|reference| reference.message_id)
+                    .map(Id::get),
+                mentions_participant: message
+                    .mentions()
+                    .iter()
+                    .any(|mention| mention.get() == bot_id || mention.get() == user_id),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, content: &str) -> ContextMessage {
+        ContextMessage {
+            id,
+            author: format!("user{}", id),
+            content: content.to_string(),
+            reply_to: None,
+            mentions_participant: false,
+        }
+    }
+
+    #[test]
+    fn truncation_respects_char_boundaries() {
+        assert_eq!(truncate("héllo", 2), "h");
+        assert_eq!(truncate("héllo", 3), "hé");
+        assert_eq!(truncate("hi", 2400), "hi");
+    }
+
+    #[test]
+    fn budget_fills_newest_first_and_keeps_the_reply_chain() {
+        let line_cost = estimate_tokens("userN: 12345678");
+        // Newest first: 5 replies to 1, which is the oldest message
+        let messages = vec![
+            ContextMessage {
+                reply_to: Some(1),
+                ..message(5, "12345678")
+            },
+            message(4, "12345678"),
+            message(3, "12345678"),
+            message(2, "12345678"),
+            message(1, "12345678"),
+        ];
+
+        assert_eq!(
+            build_context(&messages, 5, line_cost * 3),
+            "user1: 12345678\nuser4: 12345678\nuser5: 12345678"
+        );
+        assert_eq!(build_context(&messages, 5, 0), "");
+    }
+
+    #[test]
+    fn mentions_come_before_plain_messages() {
+        let line_cost = estimate_tokens("userN: 12345678");
+        let messages = vec![
+            message(3, "12345678"),
+            message(2, "12345678"),
+            ContextMessage {
+                mentions_participant: true,
+                ..message(1, "12345678")
+            },
+        ];
+
+        assert_eq!(
+            build_context(&messages, 3, line_cost * 2),
+            "user1: 12345678\nuser3: 12345678"
+        );
+    }
+}
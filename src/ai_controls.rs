@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use color_eyre::Result;
+use deadpool_postgres::Pool;
+use tokio::task::AbortHandle;
+use twilight_http::Client as HttpClient;
+use twilight_model::{
+    application::interaction::{Interaction, InteractionData},
+    channel::message::{
+        component::{ActionRow, Button, ButtonStyle},
+        Component, MessageFlags,
+    },
+    http::interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+    id::{marker::MessageMarker, Id},
+};
+
+use crate::{ai_message::Generation, database::AiFeedback, db, message_handler::handle_streaming_response};
+
+/// Prefix of the custom ids of the reply buttons
+const PREFIX: &str = "ai:";
+/// Replies whose buttons keep working, older ones are forgotten
+const MAX_TRACKED_REPLIES: usize = 200;
+
+/// An AI reply whose buttons are live.
+#[derive(Debug)]
+pub struct ActiveReply {
+    pub generation: Generation,
+    /// Set while the reply is streaming
+    pub abort: Option<AbortHandle>,
+    /// The user who asked. Only they can stop or regenerate the reply.
+    pub requester: u64,
+    /// The message that was answered
+    pub reply_to: Option<Id<MessageMarker>>,
+    /// The text of the reply once it has finished streaming
+    pub content: String,
+}
+
+/// AI replies that can still be stopped, regenerated or rated, by message id.
+#[derive(Debug, Clone, Default)]
+pub struct AiReplies(Arc<Mutex<HashMap<u64, ActiveReply>>>);
+
+impl AiReplies {
+    fn insert(&self, message_id: u64, reply: ActiveReply) {
+        let mut replies = self.0.lock().unwrap();
+        replies.insert(message_id, reply);
+        // Snowflakes grow over time, so the smallest id is the oldest reply
+        while replies.len() > MAX_TRACKED_REPLIES {
+            if let Some(oldest) = replies.keys().min().copied() {
+                replies.remove(&oldest);
+            }
+        }
+    }
+
+    fn finish(&self, message_id: u64, content: &str) {
+        if let Some(reply) = self.0.lock().unwrap().get_mut(&message_id) {
+            reply.abort = None;
+            reply.content = content.to_string();
+        }
+    }
+}
+
+/// Wires the buttons of a streamed reply to the registry of live replies.
+#[derive(Debug)]
+pub struct ReplyControls {
+    replies: AiReplies,
+    /// Registered once the reply message has been sent
+    pending: Option<ActiveReply>,
+    /// Set when regenerating into an existing message
+    message_id: Option<Id<MessageMarker>>,
+}
+
+impl ReplyControls {
+    /// Controls for a reply that has yet to be sent.
+    pub fn new(replies: AiReplies, reply: ActiveReply) -> Self {
+        Self {
+            replies,
+            pending: Some(reply),
+            message_id: None,
+        }
+    }
+
+    /// The message the reply is streamed into, when it already exists.
+    pub fn message_id(&self) -> Option<Id<MessageMarker>> {
+        self.message_id
+    }
+
+    pub fn sent(&mut self, message_id: Id<MessageMarker>) {
+        if let Some(reply) = self.pending.take() {
+            self.replies.insert(message_id.get(), reply);
+        }
+    }
+
+    pub fn finished(&self, message_id: Id<MessageMarker>, content: &str) {
+        self.replies.finish(message_id.get(), content);
+    }
+}
+
+fn button(action: &str, label: &str, style: ButtonStyle) -> Component {
+    Component::Button(Button {
+        custom_id: Some(format!("{}{}", PREFIX, action)),
+        disabled: false,
+        emoji: None,
+        label: Some(label.to_string()),
+        style,
+        url: None,
+    })
+}
+
+/// The buttons under a reply: stop while it streams, regenerate and rating once it's done.
+pub fn buttons(streaming: bool) -> Vec<Component> {
+    let components = match streaming {
+        true => vec![button("stop", "Stop", ButtonStyle::Danger)],
+        false => vec![
+            button("regenerate", "Regenerate", ButtonStyle::Secondary),
+            button("up", "👍", ButtonStyle::Secondary),
+            button("down", "👎", ButtonStyle::Secondary),
+        ],
+    };
+    vec![Component::ActionRow(ActionRow { components })]
+}
+
+/// Whether `interaction` is a press of one of the reply buttons.
+pub fn is_reply_button(interaction: &Interaction) -> bool {
+    matches!(&interaction.data, Some(InteractionData::MessageComponent(data)) if data.custom_id.starts_with(PREFIX))
+}
+
+fn ephemeral(message: &str) -> InteractionResponse {
+    InteractionResponse {
+        kind: InteractionResponseType::ChannelMessageWithSource,
+        data: Some(InteractionResponseData {
+            content: Some(message.to_string()),
+            flags: Some(MessageFlags::EPHEMERAL),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Stops, regenerates or rates the reply whose button was pressed.
+pub async fn handle_button(
+    http: Arc<HttpClient>,
+    database: Pool,
+    replies: AiReplies,
+    interaction: Interaction,
+) -> Result<()> {
+    let (Some(InteractionData::MessageComponent(data)), Some(message), Some(user_id)) =
+        (&interaction.data, &interaction.message, interaction.author_id())
+    else {
+        return Ok(());
+    };
+    let action = data.custom_id.trim_start_matches(PREFIX);
+
+    let response = match action {
+        "stop" | "regenerate" => {
+            let mut tracked = replies.0.lock().unwrap();
+            match tracked.get_mut(&message.id.get()) {
+                None => ephemeral("This reply is too old to change."),
+                Some(reply) if reply.requester != user_id.get() => {
+                    ephemeral("Only the person who asked can stop or regenerate this reply.")
+                }
+                Some(reply) if action == "stop" => {
+                    // Ending the stream makes the streaming task post what was written so far
+                    if let Some(abort) = reply.abort.take() {
+                        abort.abort();
+                    }
+                    InteractionResponse {
+                        kind: InteractionResponseType::DeferredUpdateMessage,
+                        data: None,
+                    }
+                }
+                Some(reply) if reply.abort.is_some() => ephemeral("This reply is still being written."),
+                Some(reply) => {
+                    let (updates, abort) = reply.generation.start();
+                    reply.abort = Some(abort);
+                    let controls = ReplyControls {
+                        replies: replies.clone(),
+                        pending: None,
+                        message_id: Some(message.id),
+                    };
+                    tokio::spawn(handle_streaming_response(
+                        updates,
+                        message.channel_id,
+                        reply.reply_to,
+                        Arc::clone(&http),
+                        Some(controls),
+                    ));
+                    InteractionResponse {
+                        kind: InteractionResponseType::UpdateMessage,
+                        data: Some(InteractionResponseData {
+                            components: Some(buttons(true)),
+                            ..Default::default()
+                        }),
+                    }
+                }
+            }
+        }
+        "up" | "down" => {
+            let feedback = replies.0.lock().unwrap().get(&message.id.get()).map(|reply| {
+                let request = &reply.generation.request;
+                let text = |i: usize| request.messages.get(i).map(|m| m.content.clone()).unwrap_or_default();
+                (
+                    reply.abort.is_some(),
+                    AiFeedback {
+                        message_id: message.id.get() as i64,
+                        user_id: user_id.get() as i64,
+                        rating: if action == "up" { 1 } else { -1 },
+                        persona: reply.generation.persona.clone(),
+                        model: reply.generation.model(),
+                        system_prompt: text(0),
+                        user_message: text(1),
+                        response: reply.content.clone(),
+                    },
+                )
+            });
+            match feedback {
+                None => ephemeral("This reply is too old to rate."),
+                Some((true, _)) => ephemeral("Wait for the reply to finish before rating it."),
+                Some((false, feedback)) => {
+                    db::save_ai_feedback(&database, &feedback).await?;
+                    ephemeral("Thanks for the feedback!")
+                }
+            }
+        }
+        _ => return Ok(()),
+    };
+
+    http.interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &response)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons_switch_once_streaming_ends() {
+        let custom_ids = |streaming| {
+            let Component::ActionRow(row) = &buttons(streaming)[0] else {
+                panic!("expected an action row");
+            };
+            row.components
+                .iter()
+                .map(|component| match component {
+                    Component::Button(button) => button.custom_id.clone().unwrap(),
+                    _ => panic!("expected a button"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(custom_ids(true), ["ai:stop"]);
+        assert_eq!(custom_ids(false), ["ai:regenerate", "ai:up", "ai:down"]);
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{Report, Result};
+use futures::{
+    channel::mpsc,
+    future::BoxFuture,
+    stream::{BoxStream, StreamExt},
+    FutureExt,
+};
+
+use crate::llm::{ChatRequest, Completion, LlmProvider, StreamEvent};
+
+/// Consecutive failures after which a model is skipped
+const BREAKER_THRESHOLD: u32 = 3;
+/// How long a model is skipped once its breaker opens
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(120);
+
+/// A model answered, but with nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmptyReply;
+
+impl fmt::Display for EmptyReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Model returned no content")
+    }
+}
+
+impl std::error::Error for EmptyReply {}
+
+/// Why a model request failed, as far as deciding what to do next goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    RateLimited,
+    Unavailable,
+    Timeout,
+    Empty,
+    /// The request itself was refused or broken, another model won't do better
+    Other,
+}
+
+impl Failure {
+    pub fn classify(error: &Report) -> Self {
+        if error.downcast_ref::<EmptyReply>().is_some() {
+            return Failure::Empty;
+        }
+        if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+            return match error.status() {
+                Some(status) if status.as_u16() == 429 => Failure::RateLimited,
+                Some(status) if status.is_server_error() => Failure::Unavailable,
+                Some(_) => Failure::Other,
+                None if error.is_timeout() => Failure::Timeout,
+                None if error.is_connect() => Failure::Unavailable,
+                None => Failure::Other,
+            };
+        }
+        // Errors reported inside a stream only carry the status code in their text. Only the messages are looked at,
+        // the location and backtrace in the debug output are full of stray numbers.
+        let text = error.chain().map(ToString::to_string).collect::<Vec<_>>().join(": ");
+        Self::classify_text(&text)
+    }
+
+    fn classify_text(text: &str) -> Self {
+        let text = text.to_lowercase();
+        let codes = text
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter_map(|word| word.parse::<u16>().ok())
+            .collect::<Vec<_>>();
+        if codes.contains(&429) || text.contains("rate limit") || text.contains("too many requests") {
+            Failure::RateLimited
+        } else if text.contains("timed out") || text.contains("timeout") {
+            Failure::Timeout
+        } else if codes.iter().any(|code| (500..600).contains(code)) || text.contains("overloaded") {
+            Failure::Unavailable
+        } else {
+            Failure::Other
+        }
+    }
+
+    /// Whether the next model in the chain should be tried.
+    pub fn should_fail_over(self) -> bool {
+        self != Failure::Other
+    }
+
+    pub fn user_message(self) -> &'static str {
+        match self {
+            Failure::RateLimited => "Too many people want my attention right now. Try again in a minute.",
+            Failure::Unavailable => "My brain is offline at the moment. Try again later.",
+            Failure::Timeout => "I took too long to think of something clever. Try again.",
+            Failure::Empty => "I had nothing to say to that, which is a first. Try again.",
+            Failure::Other => "Something went wrong on my end. Try again later.",
+        }
+    }
+}
+
+/// A short message for users about `error`. The full error should go to the log.
+pub fn user_message(error: &Report) -> &'static str {
+    Failure::classify(error).user_message()
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Tries the requested model, then the configured fallbacks in order, when a model is rate limited, down or
+/// answers with nothing. Models that keep failing are skipped for a while.
+#[derive(Debug)]
+pub struct Fallback {
+    inner: Arc<dyn LlmProvider>,
+    fallbacks: Vec<String>,
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
+}
+
+impl Fallback {
+    pub fn new(inner: Arc<dyn LlmProvider>, fallbacks: &[String]) -> Self {
+        Self {
+            inner,
+            fallbacks: fallbacks
+                .iter()
+                .map(|model| model.trim().to_string())
+                .filter(|model| !model.is_empty())
+                .collect(),
+            breakers: Default::default(),
+        }
+    }
+
+    /// The models to try for `model`, without those whose breaker is open. If every breaker is open the whole
+    /// chain is tried anyway, failing fast helps no one.
+    fn chain(&self, model: &str) -> Vec<String> {
+        let mut chain = vec![model.to_string()];
+        for fallback in &self.fallbacks {
+            if !chain.contains(fallback) {
+                chain.push(fallback.clone());
+            }
+        }
+
+        let now = Instant::now();
+        let breakers = self.breakers.lock().unwrap();
+        let available = chain
+            .iter()
+            .filter(|model| {
+                breakers
+                    .get(*model)
+                    .and_then(|breaker| breaker.open_until)
+                    .is_none_or(|until| until <= now)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        match available.is_empty() {
+            true => chain,
+            false => available,
+        }
+    }
+
+    /// Runs `call` on each model of the chain until one succeeds or fails in a way another model won't fix.
+    async fn with_fallbacks<'a, T: Send>(
+        &'a self,
+        request: ChatRequest,
+        call: impl Fn(ChatRequest) -> BoxFuture<'a, Result<T>> + Send,
+        is_empty: impl Fn(&T) -> bool + Send,
+    ) -> Result<T> {
+        let mut last_error = None;
+        for model in self.chain(&request.model) {
+            let result = call(ChatRequest {
+                model: model.clone(),
+                ..request.clone()
+            })
+            .await
+            .and_then(|reply| match is_empty(&reply) {
+                true => Err(EmptyReply.into()),
+                false => Ok(reply),
+            });
+            match result {
+                Ok(reply) => {
+                    record_success(&self.breakers, &model);
+                    return Ok(reply);
+                }
+                Err(e) => {
+                    let failure = Failure::classify(&e);
+                    if !failure.should_fail_over() {
+                        return Err(e);
+                    }
+                    log::warn!("Model {} failed ({:?}), trying the next one: {:?}", model, failure, e);
+                    record_failure(&self.breakers, &model);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| EmptyReply.into()))
+    }
+}
+
+fn record_success(breakers: &Mutex<HashMap<String, Breaker>>, model: &str) {
+    breakers.lock().unwrap().remove(model);
+}
+
+fn record_failure(breakers: &Mutex<HashMap<String, Breaker>>, model: &str) {
+    let mut breakers = breakers.lock().unwrap();
+    let breaker = breakers.entry(model.to_string()).or_default();
+    breaker.failures += 1;
+    if breaker.failures >= BREAKER_THRESHOLD {
+        log::warn!(
+            "Skipping model {} for {:?} after {} failures",
+            model,
+            BREAKER_COOLDOWN,
+            breaker.failures
+        );
+        breaker.failures = 0;
+        breaker.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+    }
+}
+
+impl LlmProvider for Fallback {
+    fn complete(&self, request: ChatRequest) -> BoxFuture<'_, Result<Completion>> {
+        self.with_fallbacks(request, |request| self.inner.complete(request), Completion::is_empty)
+            .boxed()
+    }
+
+    /// Fails over only until the first text or tool call arrives; a reply that breaks off midway is passed on as
+    /// is.
+    fn stream(&self, request: ChatRequest) -> BoxStream<'static, Result<StreamEvent>> {
+        let (tx, rx) = mpsc::unbounded();
+        let inner = Arc::clone(&self.inner);
+        let breakers = Arc::clone(&self.breakers);
+        let chain = self.chain(&request.model);
+        tokio::spawn(async move {
+            let mut last_error = None;
+            for model in chain {
+                let mut stream = inner.stream(ChatRequest {
+                    model: model.clone(),
+                    ..request.clone()
+                });
+                // Skip empty deltas, some providers send those before the text
+                let first = loop {
+                    match stream.next().await {
+                        Some(Ok(StreamEvent::Delta(text))) if text.is_empty() => continue,
+                        Some(Ok(StreamEvent::Done(completion))) if completion.is_empty() => {
+                            break Err(EmptyReply.into())
+                        }
+                        Some(Ok(event)) => break Ok(event),
+                        Some(Err(e)) => break Err(e),
+                        None => break Err(EmptyReply.into()),
+                    }
+                };
+                let first = match first {
+                    Ok(event) => event,
+                    Err(e) => {
+                        let failure = Failure::classify(&e);
+                        if !failure.should_fail_over() {
+                            let _ = tx.unbounded_send(Err(e));
+                            return;
+                        }
+                        log::warn!("Model {} failed ({:?}), trying the next one: {:?}", model, failure, e);
+                        record_failure(&breakers, &model);
+                        last_error = Some(e);
+                        continue;
+                    }
+                };
+
+                record_success(&breakers, &model);
+                if tx.unbounded_send(Ok(first)).is_err() {
+                    return;
+                }
+                while let Some(event) = stream.next().await {
+                    if tx.unbounded_send(event).is_err() {
+                        return;
+                    }
+                }
+                return;
+            }
+            let _ = tx.unbounded_send(Err(last_error.unwrap_or_else(|| EmptyReply.into())));
+        });
+        rx.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+
+    /// Fails with `error` for the models in `failing`, answers with the model name otherwise.
+    #[derive(Debug, Default)]
+    struct Flaky {
+        failing: Vec<&'static str>,
+        error: &'static str,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Flaky {
+        fn reply(&self, request: &ChatRequest) -> Result<Completion> {
+            self.calls.lock().unwrap().push(request.model.clone());
+            match self.failing.contains(&request.model.as_str()) {
+                true => Err(eyre!("{}", self.error)),
+                false => Ok(Completion {
+                    content: request.model.clone(),
+                    model: request.model.clone(),
+                    ..Default::default()
+                }),
+            }
+        }
+    }
+
+    impl LlmProvider for Flaky {
+        fn complete(&self, request: ChatRequest) -> BoxFuture<'_, Result<Completion>> {
+            futures::future::ready(self.reply(&request)).boxed()
+        }
+
+        fn stream(&self, request: ChatRequest) -> BoxStream<'static, Result<StreamEvent>> {
+            let events = match self.reply(&request) {
+                Ok(completion) => vec![
+                    Ok(StreamEvent::Delta(completion.content.clone())),
+                    Ok(StreamEvent::Done(completion)),
+                ],
+                Err(e) => vec![Err(e)],
+            };
+            futures::stream::iter(events).boxed()
+        }
+    }
+
+    fn request(model: &str) -> ChatRequest {
+        ChatRequest {
+            model: model.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn errors_are_classified_from_their_text() {
+        assert_eq!(
+            Failure::classify_text("ApiError { code: 429, message: \"Rate limit exceeded\" }"),
+            Failure::RateLimited
+        );
+        assert_eq!(
+            Failure::classify_text("HTTP status server error (502 Bad Gateway)"),
+            Failure::Unavailable
+        );
+        assert_eq!(Failure::classify_text("operation timed out"), Failure::Timeout);
+        assert_eq!(Failure::classify_text("400 Bad Request: invalid model"), Failure::Other);
+        assert_eq!(Failure::classify(&EmptyReply.into()), Failure::Empty);
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_breaks_the_circuit() {
+        let flaky = Arc::new(Flaky {
+            failing: vec!["primary"],
+            error: "429 Too Many Requests",
+            ..Default::default()
+        });
+        let fallback = Fallback::new(flaky.clone(), &["backup".to_string(), String::new()]);
+
+        for _ in 0..BREAKER_THRESHOLD {
+            assert_eq!(fallback.chat(request("primary")).await.unwrap(), "backup");
+        }
+        flaky.calls.lock().unwrap().clear();
+
+        // The primary is skipped while its breaker is open
+        let streamed = fallback.stream(request("primary")).collect::<Vec<_>>().await;
+        assert_eq!(
+            streamed.into_iter().next().unwrap().unwrap(),
+            StreamEvent::Delta("backup".to_string())
+        );
+        assert_eq!(*flaky.calls.lock().unwrap(), ["backup"]);
+    }
+
+    #[tokio::test]
+    async fn refused_requests_do_not_fail_over() {
+        let flaky = Arc::new(Flaky {
+            failing: vec!["primary"],
+            error: "400 Bad Request",
+            ..Default::default()
+        });
+        let fallback = Fallback::new(flaky.clone(), &["backup".to_string()]);
+
+        assert!(fallback.chat(request("primary")).await.is_err());
+        assert_eq!(*flaky.calls.lock().unwrap(), ["primary"]);
+    }
+}
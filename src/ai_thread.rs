@@ -0,0 +1,163 @@
+use color_eyre::Result;
+use deadpool_postgres::Pool;
+use twilight_http::Client as HttpClient;
+use twilight_model::{
+    channel::Message,
+    id::{marker::ChannelMarker, Id},
+};
+
+use crate::{
+    ai_context::{self, ContextMessage},
+    database::ThreadMessage,
+    db,
+};
+
+/// How many stored messages of a thread are considered for the context
+const HISTORY_LIMIT: i64 = 500;
+/// Discord allows up to 100 characters, shorter names read better in the channel list
+const MAX_NAME_CHARS: usize = 60;
+
+/// A thread name from the message that started the conversation, with mentions left out.
+pub fn thread_name(content: &str, author: &str) -> String {
+    let words = content
+        .split_whitespace()
+        .filter(|word| !word.starts_with("<@"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let name = match words.chars().count() > MAX_NAME_CHARS {
+        true => format!("{}…", words.chars().take(MAX_NAME_CHARS - 1).collect::<String>()),
+        false => words,
+    };
+    match name.is_empty() {
+        true => format!("Chat with {}", author),
+        false => name,
+    }
+}
+
+/// Stores a message posted in an AI thread.
+pub async fn store_message(database: &Pool, thread_id: u64, message: &Message) -> Result<()> {
+    db::add_thread_message(
+        database,
+        &ThreadMessage {
+            message_id: message.id.get() as i64,
+            thread_id: thread_id as i64,
+            author_name: message.author.name.clone(),
+            from_bot: false,
+            content: ai_context::truncate(&message.content, ai_context::MAX_MESSAGE_BYTES).to_string(),
+            reply_to: message
+                .reference
+                .as_ref()
+                .and_then(|reference| reference.message_id)
+                .map(|id| id.get() as i64),
+        },
+    )
+    .await
+}
+
+/// Stores the bot's finished reply to `reply_to`.
+pub async fn store_reply(database: &Pool, thread_id: u64, message_id: u64, reply_to: u64, content: &str) -> Result<()> {
+    db::add_thread_message(
+        database,
+        &ThreadMessage {
+            message_id: message_id as i64,
+            thread_id: thread_id as i64,
+            author_name: "The Trickster".to_string(),
+            from_bot: true,
+            content: content.to_string(),
+            reply_to: Some(reply_to as i64),
+        },
+    )
+    .await
+}
+
+/// Starts a thread on `message` and stores the message as the first of the conversation.
+pub async fn start(http: &HttpClient, database: &Pool, message: &Message) -> Result<Id<ChannelMarker>> {
+    let name = thread_name(&message.content, &message.author.name);
+    let thread = http
+        .create_thread_from_message(message.channel_id, message.id, &name)?
+        .exec()
+        .await?
+        .model()
+        .await?;
+
+    db::create_ai_thread(
+        database,
+        thread.id.get(),
+        message.channel_id.get(),
+        message.author.id.get(),
+    )
+    .await?;
+    store_message(database, thread.id.get(), message).await?;
+    Ok(thread.id)
+}
+
+fn context_message(message: ThreadMessage, bot_id: u64, user_id: u64) -> ContextMessage {
+    let mentions_participant =
+        message.content.contains(&format!("<@{}>", bot_id)) || message.content.contains(&format!("<@{}>", user_id));
+    ContextMessage {
+        id: message.message_id as u64,
+        author: message.author_name,
+        content: message
+            .content
+            .replace(&format!("<@{}>", bot_id), "The Trickster")
+            .replace(&format!("<@!{}>", bot_id), "The Trickster")
+            .replace(&bot_id.to_string(), "The Trickster"),
+        reply_to: message.reply_to.map(|id| id as u64),
+        mentions_participant,
+    }
+}
+
+/// The stored conversation of `thread_id` as an AI context, built like the channel context.
+pub async fn context(
+    database: &Pool,
+    thread_id: u64,
+    current: u64,
+    bot_id: u64,
+    user_id: u64,
+    token_budget: usize,
+) -> Result<String> {
+    let messages = db::get_thread_messages(database, thread_id, HISTORY_LIMIT)
+        .await?
+        .into_iter()
+        .map(|message| context_message(message, bot_id, user_id))
+        .collect::<Vec<_>>();
+    Ok(ai_context::build_context(&messages, current, token_budget))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_names_skip_mentions_and_stay_short() {
+        assert_eq!(thread_name("<@123> what is rust?", "alice"), "what is rust?");
+        assert_eq!(thread_name("<@123>", "alice"), "Chat with alice");
+
+        let long = thread_name(&"word ".repeat(40), "alice");
+        assert_eq!(long.chars().count(), MAX_NAME_CHARS);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn stored_messages_become_context() {
+        let message = ThreadMessage {
+            message_id: 2,
+            thread_id: 1,
+            author_name: "alice".to_string(),
+            from_bot: false,
+            content: "<@42> hi".to_string(),
+            reply_to: Some(1),
+        };
+
+        assert_eq!(
+            context_message(message, 42, 7),
+            ContextMessage {
+                id: 2,
+                author: "alice".to_string(),
+                content: "The Trickster hi".to_string(),
+                reply_to: Some(1),
+                mentions_participant: true,
+            }
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+
+use color_eyre::{eyre::eyre, Result};
+use deadpool_postgres::Pool;
+use serde_json::{json, Value};
+
+use crate::{
+    brave::BraveApi,
+    db,
+    llm::{ChatMessage, ToolCall},
+    qalc,
+    structs::CurrencyRates,
+};
+
+/// Most rounds of tool calls before the model has to answer
+pub const MAX_TOOL_STEPS: usize = 4;
+/// Most tool calls run per round
+pub const MAX_CALLS_PER_STEP: usize = 3;
+/// Results passed to the model per search
+const RESULTS_PER_SEARCH: usize = 5;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ToolOutput {
+    pub text: String,
+    pub sources: Vec<String>,
+}
+
+impl From<String> for ToolOutput {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            sources: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    WebSearch,
+    Calculate,
+    ConvertCurrency,
+    UserLevel,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 4] = [Tool::WebSearch, Tool::Calculate, Tool::ConvertCurrency, Tool::UserLevel];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::WebSearch => "web_search",
+            Tool::Calculate => "calculate",
+            Tool::ConvertCurrency => "convert_currency",
+            Tool::UserLevel => "user_level",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Tool> {
+        Tool::ALL.into_iter().find(|tool| tool.name() == name)
+    }
+
+    /// The function definition sent to the model.
+    pub fn definition(self) -> Value {
+        let (description, parameters) = match self {
+            Tool::WebSearch => (
+                "Search the web. Use it for recent events, facts you are unsure about, or when asked to look something up.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string", "description": "The search query" }
+                    },
+                    "required": ["query"]
+                }),
+            ),
+            Tool::Calculate => (
+                "Evaluate a math expression or unit conversion with qalc. Use it instead of doing arithmetic yourself.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "expression": { "type": "string", "description": "A qalc expression, e.g. `sqrt(2) * 3` or `5 ft to cm`" }
+                    },
+                    "required": ["expression"]
+                }),
+            ),
+            Tool::ConvertCurrency => (
+                "Convert an amount between currencies using the bot's current exchange rates.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "amount": { "type": "number" },
+                        "from": { "type": "string", "description": "ISO currency code, e.g. USD" },
+                        "to": { "type": "string", "description": "ISO currency code, e.g. EUR" }
+                    },
+                    "required": ["amount", "from", "to"]
+                }),
+            ),
+            Tool::UserLevel => (
+                "Look up a chat member's level and XP by name.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string", "description": "The user's name as it appears in the chat" }
+                    },
+                    "required": ["name"]
+                }),
+            ),
+        };
+
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name(),
+                "description": description,
+                "parameters": parameters
+            }
+        })
+    }
+}
+
+/// The tools the chat model may call while answering one message.
+#[derive(Debug, Clone)]
+pub struct ToolRegistry {
+    db: Pool,
+    /// Only set when a Brave API key is configured
+    brave: Option<BraveApi>,
+    currency_rates: CurrencyRates,
+    /// Names of the people in the conversation, for `user_level`
+    users: HashMap<String, u64>,
+}
+
+impl ToolRegistry {
+    pub fn new(db: Pool, brave: Option<BraveApi>, currency_rates: CurrencyRates, users: HashMap<String, u64>) -> Self {
+        Self {
+            db,
+            brave,
+            currency_rates,
+            users,
+        }
+    }
+
+    pub fn tools(&self) -> Vec<Tool> {
+        Tool::ALL
+            .into_iter()
+            .filter(|tool| *tool != Tool::WebSearch || self.brave.is_some())
+            .collect()
+    }
+
+    pub fn definitions(&self) -> Vec<Value> {
+        self.tools().into_iter().map(Tool::definition).collect()
+    }
+
+    pub async fn call(&self, call: &ToolCall) -> Result<ToolOutput> {
+        let tool = Tool::parse(&call.function.name)
+            .filter(|tool| self.tools().contains(tool))
+            .ok_or_else(|| eyre!("Unknown tool `{}`", call.function.name))?;
+        let arguments: Value = serde_json::from_str(&call.function.arguments)?;
+        let string_argument = |key: &str| {
+            arguments[key]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| eyre!("Missing `{}` argument", key))
+        };
+
+        match tool {
+            Tool::WebSearch => {
+                let Some(brave) = &self.brave else {
+                    return Err(eyre!("Web search is not configured"));
+                };
+                run_web_search(brave, &string_argument("query")?).await
+            }
+            Tool::Calculate => {
+                let expression = string_argument("expression")?;
+                let result = tokio::task::spawn_blocking(move || qalc::qalc(&expression))
+                    .await?
+                    .map_err(|e| eyre!(e))?;
+                Ok(result.into())
+            }
+            Tool::ConvertCurrency => {
+                let amount = arguments["amount"]
+                    .as_f64()
+                    .ok_or_else(|| eyre!("Missing `amount` argument"))?;
+                let (from, to) = (string_argument("from")?, string_argument("to")?);
+                let converted = convert_currency(&self.currency_rates, amount, &from, &to)
+                    .ok_or_else(|| eyre!("No exchange rate for {} to {}", from, to))?;
+                Ok(format!(
+                    "{} {} = {:.2} {}",
+                    amount,
+                    from.to_uppercase(),
+                    converted,
+                    to.to_uppercase()
+                )
+                .into())
+            }
+            Tool::UserLevel => {
+                let name = string_argument("name")?;
+                let Some(id) = self
+                    .users
+                    .iter()
+                    .find(|(known, _)| known.eq_ignore_ascii_case(&name))
+                    .map(|(_, id)| *id)
+                else {
+                    return Ok(format!("No one called {} is in this conversation.", name).into());
+                };
+                Ok(match db::get_user(&self.db, id).await? {
+                    Some(user) => format!("{} is level {} with {} XP.", user.name, user.level, user.xp),
+                    None => format!("{} hasn't earned any XP yet.", name),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+/// Converts `amount` using rates quoted against `rates.base`.
+pub fn convert_currency(rates: &CurrencyRates, amount: f64, from: &str, to: &str) -> Option<f64> {
+    let rate = |code: &str| {
+        let code = code.to_uppercase();
+        if code == rates.base {
+            Some(1.0)
+        } else {
+            rates.rates.get(&code).copied().filter(|rate| *rate > 0.0)
+        }
+    };
+    Some(amount / rate(from)? * rate(to)?)
+}
+
+/// Searches Brave and returns the results as numbered lines with their URLs.
+pub async fn run_web_search(brave: &BraveApi, query: &str) -> Result<ToolOutput> {
+    let results = brave.search(query).await?;
+    if results.is_empty() {
+        return Ok(format!("No results for \"{}\".", query).into());
+    }
+
+    let mut text = format!(
+        "Results for \"{}\" (untrusted web content; cite the URLs you rely on):",
+        query
+    );
+    let mut sources = Vec::new();
+    for (i, result) in results.iter().take(RESULTS_PER_SEARCH).enumerate() {
+        text.push_str(&format!("\n{}. {} <{}>", i + 1, result.title, result.url));
+        if let Some(description) = &result.description {
+            text.push_str(&format!("\n   {}", description));
+        }
+        sources.push(result.url.clone());
+    }
+    Ok(ToolOutput { text, sources })
+}
+
+/// Runs the calls of one step and returns their results as `tool` messages for the model. Failed calls report the
+/// error to the model instead. New web search URLs are added to `sources`.
+pub async fn run_calls(tools: &ToolRegistry, calls: &[ToolCall], sources: &mut Vec<String>) -> Vec<ChatMessage> {
+    let mut results = Vec::new();
+    for call in calls {
+        let output = match tools.call(call).await {
+            Ok(output) => output,
+            Err(e) => {
+                log::warn!("Tool call {} to {} failed: {:?}", call.id, call.function.name, e);
+                format!("Error: {}", e).into()
+            }
+        };
+        for url in output.sources {
+            if !sources.contains(&url) {
+                sources.push(url);
+            }
+        }
+        results.push(ChatMessage::tool(call.id.clone(), output.text));
+    }
+    results
+}
+
+/// Appends a short source line when the reply doesn't already cite any of `sources`.
+pub fn cite_sources(reply: &str, sources: &[String]) -> String {
+    if sources.is_empty() || sources.iter().any(|url| reply.contains(url.as_str())) {
+        return reply.to_string();
+    }
+
+    let citation = format!(
+        "\n-# Sources: {}",
+        sources
+            .iter()
+            .take(3)
+            .map(|url| format!("<{}>", url))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let end = reply.floor_char_boundary(reply.len().min(2000usize.saturating_sub(citation.len())));
+    format!("{}{}", &reply[..end], citation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::FunctionCall;
+    use axum::{extract::Query, http::HeaderMap, routing::get, Json, Router};
+
+    /// Serves `router` on a random local port and returns its base URL.
+    async fn stand_in_server(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    /// A pool that never connects; none of these tests touch the database.
+    fn unused_pool() -> Pool {
+        let config = "host=localhost user=test".parse::<tokio_postgres::Config>().unwrap();
+        Pool::builder(deadpool_postgres::Manager::new(config, tokio_postgres::NoTls))
+            .build()
+            .unwrap()
+    }
+
+    fn call(id: &str, name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    async fn brave_stand_in(headers: HeaderMap, Query(query): Query<HashMap<String, String>>) -> Json<Value> {
+        assert_eq!(headers["x-subscription-token"], "brave-key");
+        Json(json!({
+            "web": {
+                "results": [
+                    {
+                        "title": format!("About {}", query["q"]),
+                        "url": "https://example.com/rust",
+                        "description": "Rust is a systems programming language."
+                    },
+                    { "title": "Second", "url": "https://example.org/second" }
+                ]
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn search_results_come_back_with_their_urls() {
+        let base_url = stand_in_server(Router::new().route("/web/search", get(brave_stand_in))).await;
+        let brave = BraveApi::new(reqwest::Client::new(), "brave-key", &base_url);
+        let tools = ToolRegistry::new(unused_pool(), Some(brave), CurrencyRates::default(), HashMap::new());
+        let mut sources = vec!["https://example.org/second".to_string()];
+
+        let results = run_calls(
+            &tools,
+            &[call("call_1", "web_search", "{\"query\":\"rust 2024 edition\"}")],
+            &mut sources,
+        )
+        .await;
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].tool_call_id.as_deref(), Some("call_1"));
+        assert!(results[0]
+            .content
+            .contains("About rust 2024 edition <https://example.com/rust>"));
+        assert_eq!(sources, vec!["https://example.org/second", "https://example.com/rust"]);
+    }
+
+    #[tokio::test]
+    async fn failed_calls_report_the_error() {
+        let tools = ToolRegistry::new(unused_pool(), None, CurrencyRates::default(), HashMap::new());
+
+        let results = run_calls(
+            &tools,
+            &[
+                call("call_1", "web_search", "{\"query\":\"rust\"}"),
+                call(
+                    "call_2",
+                    "convert_currency",
+                    "{\"amount\":10,\"from\":\"USD\",\"to\":\"XYZ\"}",
+                ),
+            ],
+            &mut Vec::new(),
+        )
+        .await;
+
+        assert_eq!(results[0].content, "Error: Unknown tool `web_search`");
+        assert_eq!(results[1].content, "Error: No exchange rate for USD to XYZ");
+    }
+
+    #[test]
+    fn currencies_convert_through_the_base() {
+        let rates = CurrencyRates {
+            rates: HashMap::from([("EUR".to_string(), 0.5), ("PLN".to_string(), 4.0)]),
+            ..Default::default()
+        };
+
+        assert_eq!(convert_currency(&rates, 10.0, "usd", "EUR"), Some(5.0));
+        assert_eq!(convert_currency(&rates, 5.0, "EUR", "PLN"), Some(40.0));
+        assert_eq!(convert_currency(&rates, 1.0, "USD", "XYZ"), None);
+    }
+
+    #[test]
+    fn uncited_replies_get_a_source_line() {
+        let sources = vec!["https://example.com/rust".to_string()];
+
+        assert_eq!(
+            cite_sources("Obviously.", &sources),
+            "Obviously.\n-# Sources: <https://example.com/rust>"
+        );
+        assert_eq!(
+            cite_sources("See https://example.com/rust", &sources),
+            "See https://example.com/rust"
+        );
+        assert_eq!(cite_sources("No search happened.", &[]), "No search happened.");
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use deadpool_postgres::Pool;
+
+use crate::{
+    ai_usage::{Caller, Feature},
+    config::Config,
+    database::AiTrace,
+    db,
+    llm::{ChatMessage, ChatRequest, LlmProvider},
+};
+
+/// Records the AI calls made for one conversation. Only exists when tracing is turned on.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    database: Pool,
+    retention_days: i32,
+    channel_id: u64,
+    persona: String,
+}
+
+impl Tracer {
+    pub fn new(database: &Pool, config: &Config, channel_id: u64, persona: &str) -> Option<Self> {
+        config.ai_traces.then(|| Self {
+            database: database.clone(),
+            retention_days: config.ai_trace_retention_days,
+            channel_id,
+            persona: persona.to_string(),
+        })
+    }
+
+    /// Stores the call in the background. `model` is the one that answered, which may be a fallback.
+    pub fn record(&self, request: &ChatRequest, model: &str, response: &str, error: Option<String>, latency: Duration) {
+        let trace = AiTrace {
+            channel_id: Some(self.channel_id as i64),
+            ..trace(request, model, &self.persona, response, error, latency)
+        };
+        let database = self.database.clone();
+        let retention_days = self.retention_days;
+        tokio::spawn(async move {
+            if let Err(e) = db::save_ai_trace(&database, &trace, retention_days).await {
+                log::warn!("Failed to record AI trace: {:?}", e);
+            }
+        });
+    }
+}
+
+fn trace(
+    request: &ChatRequest,
+    model: &str,
+    persona: &str,
+    response: &str,
+    error: Option<String>,
+    latency: Duration,
+) -> AiTrace {
+    let text = |i: usize| request.messages.get(i).map(|m| m.content.clone()).unwrap_or_default();
+    AiTrace {
+        user_id: request.caller.user_id.map(|id| id as i64),
+        guild_id: request.caller.guild_id.map(|id| id as i64),
+        persona: persona.to_string(),
+        model: model.to_string(),
+        temperature: request.temperature,
+        system_prompt: text(0),
+        user_message: text(1),
+        response: response.to_string(),
+        error,
+        latency_ms: latency.as_millis().min(i32::MAX as u128) as i32,
+        ..Default::default()
+    }
+}
+
+/// Sends the prompt of `original` to `model` and stores the answer as a new trace, returning its id. Tools and
+/// images are left out, so the reply shows what the prompt alone gets out of the model.
+pub async fn rerun(
+    database: &Pool,
+    llm: &dyn LlmProvider,
+    config: &Config,
+    original: &AiTrace,
+    model: &str,
+) -> Result<i64> {
+    let request = ChatRequest {
+        model: model.to_string(),
+        messages: vec![
+            ChatMessage::system(original.system_prompt.clone()),
+            ChatMessage::user(original.user_message.clone()),
+        ],
+        temperature: original.temperature,
+        max_tokens: Some(1024),
+        // Re-runs are the operator's, not the user's, so they don't count against the user's quota
+        caller: Caller::new(Feature::Chat, None, None),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let (response, served_model, error) = match llm.complete(request.clone()).await {
+        Ok(completion) => (completion.content, completion.model, None),
+        Err(e) => (String::new(), request.model.clone(), Some(format!("{:?}", e))),
+    };
+    let trace = AiTrace {
+        user_id: original.user_id,
+        channel_id: original.channel_id,
+        guild_id: original.guild_id,
+        rerun_of: Some(original.id),
+        ..trace(
+            &request,
+            &served_model,
+            &original.persona,
+            &response,
+            error,
+            started.elapsed(),
+        )
+    };
+    db::save_ai_trace(database, &trace, config.ai_trace_retention_days).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traces_keep_the_prompt_and_the_reply() {
+        let request = ChatRequest {
+            model: "model".to_string(),
+            messages: vec![ChatMessage::system("system"), ChatMessage::user("hi")],
+            temperature: Some(0.5),
+            caller: Caller::new(Feature::Chat, Some(1), Some(2)),
+            ..Default::default()
+        };
+
+        let trace = trace(
+            &request,
+            "fallback",
+            "Trickster",
+            "hello",
+            None,
+            Duration::from_millis(1500),
+        );
+
+        assert_eq!(trace.system_prompt, "system");
+        assert_eq!(trace.user_message, "hi");
+        assert_eq!(trace.response, "hello");
+        assert_eq!(trace.model, "fallback");
+        assert_eq!((trace.user_id, trace.guild_id), (Some(1), Some(2)));
+        assert_eq!(trace.latency_ms, 1500);
+        assert_eq!(trace.temperature, Some(0.5));
+    }
+}
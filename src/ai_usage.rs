@@ -0,0 +1,339 @@
+use std::{collections::HashMap, sync::Arc};
+
+use color_eyre::Result;
+use deadpool_postgres::Pool;
+use futures::{
+    channel::mpsc,
+    future::BoxFuture,
+    stream::{BoxStream, StreamExt},
+    FutureExt,
+};
+
+use crate::{
+    ai_context::estimate_tokens,
+    config::Config,
+    db,
+    llm::{ChatMessage, ChatRequest, Completion, LlmProvider, StreamEvent},
+};
+
+/// What an AI request was made for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Feature {
+    #[default]
+    Chat,
+    Memory,
+    Math,
+    Waifu,
+    Interjection,
+    Summary,
+}
+
+impl Feature {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Feature::Chat => "chat",
+            Feature::Memory => "memory",
+            Feature::Math => "math",
+            Feature::Waifu => "waifu",
+            Feature::Interjection => "interjection",
+            Feature::Summary => "summary",
+        }
+    }
+}
+
+/// Who an AI request is made for, so its usage can be accounted and limited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Caller {
+    pub feature: Feature,
+    pub user_id: Option<u64>,
+    pub guild_id: Option<u64>,
+}
+
+impl Caller {
+    pub fn new(feature: Feature, user_id: Option<u64>, guild_id: Option<u64>) -> Self {
+        Self {
+            feature,
+            user_id,
+            guild_id,
+        }
+    }
+}
+
+/// Token prices in USD per million tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Prices {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// The usage of one request. Tokens and cost are the provider's when it reports them, and estimated from the text
+/// otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    pub caller: Caller,
+    pub model: String,
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    /// In USD
+    pub cost: f64,
+}
+
+/// Prices for the model that answered, the configured defaults for models without their own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PriceList {
+    default: Prices,
+    models: Arc<HashMap<String, Prices>>,
+}
+
+impl PriceList {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            default: Prices {
+                prompt: config.llm_prompt_price,
+                completion: config.llm_completion_price,
+            },
+            models: Arc::clone(&config.llm_model_prices),
+        }
+    }
+
+    pub fn get(&self, model: &str) -> Prices {
+        self.models.get(model).copied().unwrap_or(self.default)
+    }
+}
+
+/// Estimated tokens of `message`, tool calls included.
+fn message_tokens(message: &ChatMessage) -> usize {
+    estimate_tokens(&message.content)
+        + message
+            .tool_calls
+            .iter()
+            .map(|call| estimate_tokens(&call.function.name) + estimate_tokens(&call.function.arguments))
+            .sum::<usize>()
+}
+
+impl UsageRecord {
+    pub fn new(request: &ChatRequest, completion: &Completion, prices: &PriceList) -> Self {
+        let model = match completion.model.is_empty() {
+            true => request.model.clone(),
+            false => completion.model.clone(),
+        };
+        let prices = prices.get(&model);
+        let (prompt_tokens, completion_tokens, cost) = match completion.usage {
+            Some(usage) => (
+                usage.prompt_tokens as usize,
+                usage.completion_tokens as usize,
+                usage.cost,
+            ),
+            None => {
+                let prompt_tokens = request.messages.iter().map(message_tokens).sum::<usize>()
+                    + request
+                        .tools
+                        .iter()
+                        .map(|tool| estimate_tokens(&tool.to_string()))
+                        .sum::<usize>();
+                let reply = ChatMessage::assistant(completion.content.clone(), completion.tool_calls.clone());
+                (prompt_tokens, message_tokens(&reply), None)
+            }
+        };
+        Self {
+            caller: request.caller,
+            model,
+            prompt_tokens: prompt_tokens as i32,
+            completion_tokens: completion_tokens as i32,
+            cost: cost.unwrap_or(
+                (prompt_tokens as f64 * prices.prompt + completion_tokens as f64 * prices.completion) / 1_000_000.0,
+            ),
+        }
+    }
+}
+
+fn save(database: Pool, record: UsageRecord) {
+    tokio::spawn(async move {
+        if let Err(e) = db::record_ai_usage(&database, &record).await {
+            log::warn!("Failed to record AI usage: {:?}", e);
+        }
+    });
+}
+
+/// Records the usage of every request made through the wrapped provider.
+#[derive(Debug)]
+pub struct Metered {
+    inner: Arc<dyn LlmProvider>,
+    db: Pool,
+    prices: PriceList,
+}
+
+impl Metered {
+    pub fn new(inner: Arc<dyn LlmProvider>, db: Pool, config: &Config) -> Self {
+        Self {
+            inner,
+            db,
+            prices: PriceList::new(config),
+        }
+    }
+}
+
+impl LlmProvider for Metered {
+    fn complete(&self, request: ChatRequest) -> BoxFuture<'_, Result<Completion>> {
+        async move {
+            let completion = self.inner.complete(request.clone()).await?;
+            save(self.db.clone(), UsageRecord::new(&request, &completion, &self.prices));
+            Ok(completion)
+        }
+        .boxed()
+    }
+
+    /// Records once the stream ends or is dropped, counting what was generated until then.
+    fn stream(&self, request: ChatRequest) -> BoxStream<'static, Result<StreamEvent>> {
+        let mut inner = self.inner.stream(request.clone());
+        let (tx, rx) = mpsc::unbounded();
+        let database = self.db.clone();
+        let prices = self.prices.clone();
+        tokio::spawn(async move {
+            let mut completion = Completion::default();
+            let mut failed = false;
+            while let Some(event) = inner.next().await {
+                match &event {
+                    Ok(StreamEvent::Delta(text)) => completion.content.push_str(text),
+                    Ok(StreamEvent::Done(done)) => completion = done.clone(),
+                    Err(_) => failed = true,
+                }
+                if tx.unbounded_send(event).is_err() {
+                    break;
+                }
+            }
+            // A request that failed before any text was rejected, most likely without being billed
+            if !(failed && completion.is_empty()) {
+                save(database, UsageRecord::new(&request, &completion, &prices));
+            }
+        });
+        rx.boxed()
+    }
+}
+
+/// The polite refusal for a user or guild past its daily token quota, if either is.
+fn over_quota_message(config: &Config, user_tokens: i64, guild_tokens: i64) -> Option<String> {
+    let over = |used: i64, quota: u64| quota > 0 && used >= quota as i64;
+    if over(user_tokens, config.ai_user_daily_tokens) {
+        Some(
+            "You've used up today's AI allowance, so my brilliance will have to wait until midnight UTC. \
+             Try not to miss me too much."
+                .to_string(),
+        )
+    } else if over(guild_tokens, config.ai_guild_daily_tokens) {
+        Some("This server has used up today's AI allowance. I'll be back at midnight UTC.".to_string())
+    } else {
+        None
+    }
+}
+
+/// Checks today's (UTC) usage of `user_id` and `guild_id` against the configured quotas. Database errors let the
+/// request through rather than silence the bot.
+pub async fn check_quota(database: &Pool, config: &Config, user_id: u64, guild_id: Option<u64>) -> Option<String> {
+    if config.ai_user_daily_tokens == 0 && config.ai_guild_daily_tokens == 0 {
+        return None;
+    }
+    match db::get_usage_today(database, user_id, guild_id).await {
+        Ok((user_tokens, guild_tokens)) => over_quota_message(config, user_tokens, guild_tokens),
+        Err(e) => {
+            log::warn!("Failed to check the AI quota: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::Usage;
+
+    #[test]
+    fn usage_is_priced_per_million_tokens() {
+        let request = ChatRequest {
+            model: "model".to_string(),
+            messages: vec![ChatMessage::user("a".repeat(400))],
+            caller: Caller::new(Feature::Chat, Some(1), None),
+            ..Default::default()
+        };
+        let config = Config {
+            llm_prompt_price: 1.0,
+            llm_completion_price: 2.0,
+            llm_model_prices: Arc::new(HashMap::from([(
+                "fallback".to_string(),
+                Prices {
+                    prompt: 10.0,
+                    completion: 20.0,
+                },
+            )])),
+            ..Default::default()
+        };
+        let prices = PriceList::new(&config);
+        let completion = Completion {
+            content: "b".repeat(40),
+            ..Default::default()
+        };
+
+        let record = UsageRecord::new(&request, &completion, &prices);
+        assert_eq!(record.model, "model");
+        assert_eq!(record.prompt_tokens, 104);
+        assert_eq!(record.completion_tokens, 14);
+        assert!((record.cost - (104.0 + 28.0) / 1_000_000.0).abs() < 1e-12);
+        assert_eq!(record.caller.user_id, Some(1));
+
+        // The model that answered is priced, with its own prices
+        let served = Completion {
+            model: "fallback".to_string(),
+            ..completion
+        };
+        let record = UsageRecord::new(&request, &served, &prices);
+        assert_eq!(record.model, "fallback");
+        assert!((record.cost - (1040.0 + 280.0) / 1_000_000.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn reported_usage_beats_the_estimate() {
+        let request = ChatRequest {
+            model: "model".to_string(),
+            messages: vec![ChatMessage::user("a".repeat(400))],
+            ..Default::default()
+        };
+        let prices = PriceList::new(&Config {
+            llm_prompt_price: 1.0,
+            llm_completion_price: 2.0,
+            ..Default::default()
+        });
+        let mut completion = Completion {
+            content: "b".repeat(40),
+            usage: Some(Usage {
+                prompt_tokens: 90,
+                completion_tokens: 30,
+                cost: None,
+            }),
+            ..Default::default()
+        };
+
+        let record = UsageRecord::new(&request, &completion, &prices);
+        assert_eq!((record.prompt_tokens, record.completion_tokens), (90, 30));
+        assert!((record.cost - (90.0 + 60.0) / 1_000_000.0).abs() < 1e-12);
+
+        completion.usage = completion.usage.map(|usage| Usage {
+            cost: Some(0.5),
+            ..usage
+        });
+        assert_eq!(UsageRecord::new(&request, &completion, &prices).cost, 0.5);
+    }
+
+    #[test]
+    fn quotas_apply_to_users_then_guilds() {
+        let config = Config {
+            ai_user_daily_tokens: 100,
+            ai_guild_daily_tokens: 1000,
+            ..Default::default()
+        };
+
+        assert_eq!(over_quota_message(&config, 99, 999), None);
+        assert!(over_quota_message(&config, 100, 0).unwrap().contains("You've used up"));
+        assert!(over_quota_message(&config, 0, 1000).unwrap().contains("This server"));
+        assert_eq!(over_quota_message(&Config::default(), 1_000_000, 1_000_000), None);
+    }
+}
@@ -23,18 +23,20 @@ pub struct BraveApi {
     #[serde(skip)]
     client: Client,
     api_key: String,
+    base_url: String,
 }
 
 impl BraveApi {
-    pub fn new(client: Client, api_key: &str) -> Self {
+    pub fn new(client: Client, api_key: &str, base_url: &str) -> Self {
         Self {
             client,
             api_key: api_key.to_owned(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
         }
     }
 
     pub async fn search(&self, query: &str) -> Result<Vec<BraveSearchResult>, reqwest::Error> {
-        let url = "https://api.search.brave.com/res/v1/web/search";
+        let url = format!("{}/web/search", self.base_url);
         let resp = self
             .client
             .get(url)
@@ -48,6 +50,7 @@ impl BraveApi {
             ])
             .send()
             .await?
+            .error_for_status()?
             .json::<BraveApiResponse>()
             .await?;
 
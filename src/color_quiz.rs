@@ -1,95 +1,527 @@
-use png::{BitDepth, ColorType, Encoder};
+use color_eyre::Result;
 use rand::Rng;
-use std::io::Cursor;
+use std::time::Duration;
+use twilight_model::http::attachment::Attachment;
+
+use crate::{
+    quiz::{Grade, Quiz, QuizContext, QuizKind},
+    quiz_difficulty::Difficulty,
+    structs::Command,
+    utils::image::encode_rgb_png,
+};
 
 #[derive(Debug, Clone)]
 pub struct ColorQuiz {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    pub difficulty: Difficulty,
+}
+
+/// How close a guess was, and which share of the full XP reward it earns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorScore {
+    pub delta_e: f64,
+    pub band: &'static str,
+    pub xp_fraction: f64,
 }
 
+/// Score bands as (share of the tolerance, label, share of the XP reward), tightest first.
+const SCORE_BANDS: [(f64, &str, f64); 4] = [
+    (0.25, "Perfect", 1.0),
+    (0.5, "Great", 0.75),
+    (0.75, "Close", 0.5),
+    (1.0, "Near miss", 0.25),
+];
+
 impl ColorQuiz {
-    pub fn generate<R: Rng>(rng: &mut R) -> Self {
+    pub fn random<R: Rng>(rng: &mut R, difficulty: Difficulty) -> Self {
         Self {
             r: rng.gen_range(0..=255),
             g: rng.gen_range(0..=255),
             b: rng.gen_range(0..=255),
+            difficulty,
         }
     }
 
-    pub fn generate_image(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    /// Largest CIEDE2000 distance that still earns XP. Easy is about the old 25-point sRGB tolerance.
+    pub fn tolerance(difficulty: Difficulty) -> f64 {
+        match difficulty {
+            Difficulty::Easy => 12.0,
+            Difficulty::Medium => 9.0,
+            Difficulty::Hard => 7.0,
+            Difficulty::Expert => 5.0,
+        }
+    }
+
+    pub fn generate_image(&self) -> Result<Vec<u8>> {
         let width = 640u32;
         let height = 360u32;
 
-        let mut buffer = Cursor::new(Vec::new());
+        let data = [self.r, self.g, self.b].repeat((width * height) as usize);
+        encode_rgb_png(width, height, &data)
+    }
+
+    /// Scores a guess in any supported notation. Returns `None` for messages that aren't a colour or are
+    /// further away than the tolerance.
+    pub fn score_answer(&self, user_answer: &str) -> Option<ColorScore> {
+        let guess = parse_color(user_answer)?;
+        let delta_e = ciede2000(srgb_to_lab((self.r, self.g, self.b)), srgb_to_lab(guess));
+        let tolerance = Self::tolerance(self.difficulty);
 
-        {
-            let mut encoder = Encoder::new(&mut buffer, width, height);
-            encoder.set_color(ColorType::Rgb);
-            encoder.set_depth(BitDepth::Eight);
+        SCORE_BANDS
+            .iter()
+            .find(|(share, _, _)| delta_e <= tolerance * share)
+            .map(|&(_, band, xp_fraction)| ColorScore {
+                delta_e,
+                band,
+                xp_fraction,
+            })
+    }
+}
 
-            let mut writer = encoder.write_header()?;
+impl Quiz for ColorQuiz {
+    async fn generate(ctx: QuizContext<'_>) -> Result<Self> {
+        Ok(Self::random(ctx.rng, ctx.difficulty))
+    }
 
-            let pixel_count = (width * height) as usize;
-            let mut data = Vec::with_capacity(pixel_count * 3);
+    fn kind(&self) -> QuizKind {
+        QuizKind::Color
+    }
 
-            for _ in 0..pixel_count {
-                data.push(self.r);
-                data.push(self.g);
-                data.push(self.b);
-            }
+    fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    fn time_limit(&self) -> Duration {
+        Duration::from_secs(60)
+    }
 
-            writer.write_image_data(&data)?;
+    fn render(&self, _user_id: u64) -> Result<Command> {
+        let image_data = self.generate_image()?;
+        Ok(Command::text(format!(
+            "**COLOR QUIZ TIME!** Guess this color in {} seconds!\nAny format works: `#RRGGBB`, `#RGB`, `rgb()`, `hsl()` or a CSS color name. Closer guesses earn more XP.",
+            self.time_limit().as_secs()
+        ))
+        .attachments(vec![Attachment::from_bytes("color.png".to_string(), image_data, 1)]))
+    }
+
+    fn validate(&self, answer: &str, _elapsed: Duration) -> Option<Grade> {
+        let score = self.score_answer(answer)?;
+        Some(Grade {
+            xp_fraction: score.xp_fraction,
+            summary: format!(
+                "{}! (ΔE {:.1}) The color was `{}` or `{}`.",
+                score.band,
+                score.delta_e,
+                self.question(),
+                self.answer()
+            ),
+        })
+    }
+
+    fn question(&self) -> String {
+        format!("rgb({}, {}, {})", self.r, self.g, self.b)
+    }
+
+    fn answer(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+
+    fn timeout_message(&self) -> String {
+        format!("Time's up! The color was `{}` or `{}`.", self.question(), self.answer())
+    }
+}
+
+/// Parses `#RRGGBB`, `#RGB`, `rgb()`/`rgba()`, `hsl()`/`hsla()` and CSS named colours.
+pub fn parse_color(input: &str) -> Option<(u8, u8, u8)> {
+    let input = input.trim().to_ascii_lowercase();
+
+    if let Some(hex) = input.strip_prefix('#') {
+        return parse_hex(hex);
+    }
+
+    if let Some((name, args)) = input.strip_suffix(')').and_then(|s| s.split_once('(')) {
+        let args = args
+            .split(|c: char| c == ',' || c == '/' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+        if args.len() < 3 {
+            return None;
         }
+        return match name.trim() {
+            "rgb" | "rgba" => Some((
+                parse_rgb_channel(args[0])?,
+                parse_rgb_channel(args[1])?,
+                parse_rgb_channel(args[2])?,
+            )),
+            "hsl" | "hsla" => {
+                let hue = args[0].trim_end_matches("deg").parse::<f64>().ok()?;
+                Some(hsl_to_rgb(hue, parse_percentage(args[1])?, parse_percentage(args[2])?))
+            }
+            _ => None,
+        };
+    }
+
+    NAMED_COLORS
+        .binary_search_by(|(name, _)| name.cmp(&input.as_str()))
+        .ok()
+        .map(|index| {
+            let value = NAMED_COLORS[index].1;
+            ((value >> 16) as u8, (value >> 8) as u8, value as u8)
+        })
+}
 
-        Ok(buffer.into_inner())
+fn parse_hex(hex: &str) -> Option<(u8, u8, u8)> {
+    if !hex.is_ascii() {
+        return None;
     }
+    match hex.len() {
+        3 => {
+            let digit = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok().map(|d| d * 17);
+            Some((digit(0)?, digit(1)?, digit(2)?))
+        }
+        6 => {
+            let pair = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            Some((pair(0)?, pair(2)?, pair(4)?))
+        }
+        _ => None,
+    }
+}
+
+fn parse_rgb_channel(value: &str) -> Option<u8> {
+    let value = match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().ok()? * 255.0 / 100.0,
+        None => value.parse::<f64>().ok()?,
+    };
+    Some(value.round().clamp(0.0, 255.0) as u8)
+}
+
+fn parse_percentage(value: &str) -> Option<f64> {
+    let value = value.strip_suffix('%').unwrap_or(value).parse::<f64>().ok()?;
+    Some((value / 100.0).clamp(0.0, 1.0))
+}
 
-    pub fn validate_answer(&self, user_answer: &str) -> bool {
-        let answer = user_answer.trim();
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> (u8, u8, u8) {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |v: f64| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    (channel(r), channel(g), channel(b))
+}
 
-        if !answer.starts_with('#') {
-            return false;
+/// Converts an sRGB colour to CIE L*a*b* under the D65 white point.
+fn srgb_to_lab((r, g, b): (u8, u8, u8)) -> (f64, f64, f64) {
+    let linear = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
         }
+    };
+    let (r, g, b) = (linear(r), linear(g), linear(b));
 
-        let hex = answer.trim_start_matches('#');
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
 
-        if hex.len() != 6 {
-            return false;
+    let f = |t: f64| {
+        const DELTA: f64 = 6.0 / 29.0;
+        if t > DELTA.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
         }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
 
-        let r = u8::from_str_radix(&hex[0..2], 16).ok();
-        let g = u8::from_str_radix(&hex[2..4], 16).ok();
-        let b = u8::from_str_radix(&hex[4..6], 16).ok();
+/// CIEDE2000 colour difference between two L*a*b* colours.
+fn ciede2000((l1, a1, b1): (f64, f64, f64), (l2, a2, b2): (f64, f64, f64)) -> f64 {
+    const POW25_7: f64 = 6_103_515_625.0;
 
-        if let (Some(r), Some(g), Some(b)) = (r, g, b) {
-            let diff = (self.r as i32 - r as i32).abs()
-                + (self.g as i32 - g as i32).abs()
-                + (self.b as i32 - b as i32).abs();
+    let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let g = 0.5 * (1.0 - (c_bar.powi(7) / (c_bar.powi(7) + POW25_7)).sqrt());
+    let (a1p, a2p) = ((1.0 + g) * a1, (1.0 + g) * a2);
+    let (c1p, c2p) = (a1p.hypot(b1), a2p.hypot(b2));
 
-            return diff <= 25;
+    let hue = |b: f64, ap: f64| {
+        if b == 0.0 && ap == 0.0 {
+            0.0
+        } else {
+            b.atan2(ap).to_degrees().rem_euclid(360.0)
         }
+    };
+    let (h1p, h2p) = (hue(b1, a1p), hue(b2, a2p));
+    let chroma_product = c1p * c2p;
 
-        false
-    }
+    let delta_lp = l2 - l1;
+    let delta_cp = c2p - c1p;
+    let delta_hp = if chroma_product == 0.0 {
+        0.0
+    } else {
+        match h2p - h1p {
+            d if d > 180.0 => d - 360.0,
+            d if d < -180.0 => d + 360.0,
+            d => d,
+        }
+    };
+    let delta_big_hp = 2.0 * chroma_product.sqrt() * (delta_hp / 2.0).to_radians().sin();
+
+    let l_bar_p = (l1 + l2) / 2.0;
+    let c_bar_p = (c1p + c2p) / 2.0;
+    let h_bar_p = if chroma_product == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_bar_p - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar_p).to_radians().cos()
+        + 0.32 * (3.0 * h_bar_p + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar_p - 63.0).to_radians().cos();
+    let delta_theta = 30.0 * (-((h_bar_p - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_bar_p.powi(7) / (c_bar_p.powi(7) + POW25_7)).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar_p - 50.0).powi(2) / (20.0 + (l_bar_p - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar_p;
+    let s_h = 1.0 + 0.015 * c_bar_p * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let (dl, dc, dh) = (delta_lp / s_l, delta_cp / s_c, delta_big_hp / s_h);
+    (dl * dl + dc * dc + dh * dh + r_t * dc * dh).sqrt()
 }
 
+/// CSS named colours, sorted by name for binary search.
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff),
+    ("antiquewhite", 0xfaebd7),
+    ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff),
+    ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff),
+    ("blueviolet", 0x8a2be2),
+    ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887),
+    ("cadetblue", 0x5f9ea0),
+    ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50),
+    ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc),
+    ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b),
+    ("darkcyan", 0x008b8b),
+    ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b),
+    ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a),
+    ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f),
+    ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493),
+    ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0),
+    ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff),
+    ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520),
+    ("gray", 0x808080),
+    ("green", 0x008000),
+    ("greenyellow", 0xadff2f),
+    ("grey", 0x808080),
+    ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4),
+    ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082),
+    ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5),
+    ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd),
+    ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2),
+    ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3),
+    ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0),
+    ("lime", 0x00ff00),
+    ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6),
+    ("magenta", 0xff00ff),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd),
+    ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc),
+    ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa),
+    ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead),
+    ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500),
+    ("orangered", 0xff4500),
+    ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98),
+    ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5),
+    ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f),
+    ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xff0000),
+    ("rosybrown", 0xbc8f8f),
+    ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072),
+    ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57),
+    ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0),
+    ("skyblue", 0x87ceeb),
+    ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4),
+    ("tan", 0xd2b48c),
+    ("teal", 0x008080),
+    ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0),
+    ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3),
+    ("white", 0xffffff),
+    ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_hex_validation() {
-        let quiz = ColorQuiz {
+    fn quiz(difficulty: Difficulty) -> ColorQuiz {
+        ColorQuiz {
             r: 200,
             g: 200,
             b: 200,
-        };
+            difficulty,
+        }
+    }
 
-        assert!(quiz.validate_answer("#c8c8c8"));
-        assert!(quiz.validate_answer("#bec8ca"));
-        assert!(quiz.validate_answer("#b3c8c8"));
-        assert!(!quiz.validate_answer("#aec8c8"));
+    #[test]
+    fn test_notations() {
+        assert_eq!(parse_color("#c8c8c8"), Some((200, 200, 200)));
+        assert_eq!(parse_color("#FFF"), Some((255, 255, 255)));
+        assert_eq!(parse_color("rgb(200, 100, 0)"), Some((200, 100, 0)));
+        assert_eq!(parse_color("rgba(200 100 0 / 50%)"), Some((200, 100, 0)));
+        assert_eq!(parse_color("rgb(100%, 0%, 50%)"), Some((255, 0, 128)));
+        assert_eq!(parse_color("hsl(120, 100%, 50%)"), Some((0, 255, 0)));
+        assert_eq!(parse_color("hsl(0deg 0% 50%)"), Some((128, 128, 128)));
+        assert_eq!(parse_color("RebeccaPurple"), Some((0x66, 0x33, 0x99)));
+        assert_eq!(parse_color("lightgray"), Some((0xd3, 0xd3, 0xd3)));
+        assert_eq!(parse_color("#c8c8c"), None);
+        assert_eq!(parse_color("hello there"), None);
+        assert!(NAMED_COLORS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn test_ciede2000_reference_pairs() {
+        // Sharma, Wu & Dalal (2005) test data
+        let pairs = [
+            ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+            ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+            ((50.0, 2.5, 0.0), (50.0, 0.0, -2.5), 4.3065),
+        ];
+        for (lab1, lab2, expected) in pairs {
+            assert!((ciede2000(lab1, lab2) - expected).abs() < 1e-4, "{:?} {:?}", lab1, lab2);
+        }
+    }
+
+    #[test]
+    fn test_score_bands() {
+        let easy = quiz(Difficulty::Easy);
+
+        assert_eq!(easy.score_answer("#c8c8c8").map(|s| s.band), Some("Perfect"));
+        assert_eq!(easy.score_answer("lightgray").map(|s| s.band), Some("Perfect"));
+        assert!(easy.score_answer("#000000").is_none());
+        assert!(easy.score_answer("not a colour").is_none());
+
+        let near = easy.score_answer("#a0a0a0").unwrap();
+        assert!(near.xp_fraction < 1.0 && near.xp_fraction > 0.0);
+        assert!(quiz(Difficulty::Expert).score_answer("#a0a0a0").is_none());
+    }
+
+    #[test]
+    fn test_tolerance_tightens_with_difficulty() {
+        // ΔE 8.5 from the answer
+        assert!(quiz(Difficulty::Easy).score_answer("#b4c8c8").is_some());
+        assert!(quiz(Difficulty::Expert).score_answer("#b4c8c8").is_none());
+        assert!(Difficulty::ALL
+            .windows(2)
+            .all(|pair| ColorQuiz::tolerance(pair[0]) > ColorQuiz::tolerance(pair[1])));
     }
 }
@@ -0,0 +1,198 @@
+#![allow(clippy::unused_unit)]
+
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use twilight_model::{channel::message::MessageFlags, guild::Permissions};
+use vesper::{
+    prelude::*,
+    twilight_exports::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+};
+
+use crate::{
+    ai_fallback,
+    ai_usage::{self, Caller, Feature},
+    db, interjection,
+    structs::State,
+    tldr,
+};
+
+async fn respond_ephemeral(ctx: &SlashContext<'_, Arc<Mutex<State>>>, message: String) -> DefaultCommandResult {
+    ctx.interaction_client
+        .create_response(
+            ctx.interaction.id,
+            &ctx.interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    content: Some(message),
+                    flags: Some(MessageFlags::EPHEMERAL),
+                    ..Default::default()
+                }),
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[description = "Show or change how the AI behaves in this channel"]
+pub async fn aisettings(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    #[description = "Move conversations with the bot into their own thread"] threads: Option<bool>,
+    #[description = "Reply unprompted to 1 in this many messages, 0 to never"] interjections: Option<i64>,
+    #[description = "Minutes between unprompted replies"] cooldown: Option<i64>,
+    #[description = "Hours (UTC) without unprompted replies, like 23-7, or off"] quiet_hours: Option<String>,
+) -> DefaultCommandResult {
+    let Some(channel_id) = ctx.interaction.channel_id else {
+        return respond_ephemeral(ctx, "This command only works in a channel.".to_string()).await;
+    };
+
+    let mut state = ctx.data.lock().await;
+    let mut settings = state.channel_settings(channel_id.get());
+
+    if threads.is_some() || interjections.is_some() || cooldown.is_some() || quiet_hours.is_some() {
+        let can_manage = ctx
+            .interaction
+            .member
+            .as_ref()
+            .and_then(|member| member.permissions)
+            .is_some_and(|permissions| permissions.contains(Permissions::MANAGE_CHANNELS));
+        if !can_manage {
+            drop(state);
+            return respond_ephemeral(
+                ctx,
+                "You need the Manage Channels permission to change AI settings.".to_string(),
+            )
+            .await;
+        }
+
+        if let Some(threads) = threads {
+            settings.ai_threads = threads;
+        }
+        if let Some(interjections) = interjections {
+            settings.interjection_chance = interjections.clamp(0, i32::MAX as i64) as i32;
+        }
+        if let Some(cooldown) = cooldown {
+            settings.interjection_cooldown = (cooldown.max(0) * 60).min(i32::MAX as i64) as i32;
+        }
+        if let Some(quiet_hours) = quiet_hours {
+            match interjection::parse_quiet_hours(&quiet_hours) {
+                Ok(hours) => {
+                    settings.quiet_hours_start = hours.map(|(start, _)| start);
+                    settings.quiet_hours_end = hours.map(|(_, end)| end);
+                }
+                Err(e) => {
+                    drop(state);
+                    return respond_ephemeral(ctx, e).await;
+                }
+            }
+        }
+        db::upsert_channel_settings(&state.db, &settings).await?;
+        state.channel_settings.insert(channel_id.get(), settings.clone());
+    }
+    drop(state);
+
+    respond_ephemeral(
+        ctx,
+        format!(
+            "**AI settings for <#{}>**\nConversation threads: {}\nUnprompted replies: {}\nQuiet hours: {}",
+            channel_id,
+            if settings.ai_threads { "on" } else { "off" },
+            match settings.interjection_chance {
+                0 => "never".to_string(),
+                chance => format!(
+                    "1 in {} messages, at most every {} minutes",
+                    chance,
+                    settings.interjection_cooldown / 60
+                ),
+            },
+            match (settings.quiet_hours_start, settings.quiet_hours_end) {
+                (Some(start), Some(end)) => format!("{:02}:00-{:02}:00 UTC", start, end),
+                _ => "none".to_string(),
+            }
+        ),
+    )
+    .await
+}
+
+#[command]
+#[description = "Summarize what you missed in this channel, only visible to you"]
+pub async fn tldr(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    #[description = "How many recent messages to summarize (default 200, at most 1000)"] messages: Option<i64>,
+    #[description = "How far back to go instead, like 30m, 2h or 1d"] since: Option<String>,
+) -> DefaultCommandResult {
+    let (Some(channel_id), Some(guild_id), Some(user_id)) = (
+        ctx.interaction.channel_id,
+        ctx.interaction.guild_id,
+        ctx.interaction.author_id(),
+    ) else {
+        return respond_ephemeral(ctx, "This command only works in a server channel.".to_string()).await;
+    };
+    let since = match since.as_deref().map(tldr::parse_since).transpose() {
+        Ok(since) => since,
+        Err(e) => return respond_ephemeral(ctx, e).await,
+    };
+    let limit = match (messages, since) {
+        (Some(messages), _) => messages.clamp(1, tldr::MAX_MESSAGES as i64) as usize,
+        (None, Some(_)) => tldr::MAX_MESSAGES,
+        (None, None) => tldr::DEFAULT_MESSAGES,
+    };
+
+    let state = ctx.data.lock().await;
+    let Some(llm) = state.llm.clone() else {
+        drop(state);
+        return respond_ephemeral(ctx, "No chat model is configured.".to_string()).await;
+    };
+    let database = state.db.clone();
+    let config = state.config.clone();
+    drop(state);
+    if let Some(refusal) = ai_usage::check_quota(&database, &config, user_id.get(), Some(guild_id.get())).await {
+        return respond_ephemeral(ctx, refusal).await;
+    }
+    let model = config.openrouter_model.clone();
+
+    // Fetching and summarizing takes longer than Discord waits for a response
+    ctx.interaction_client
+        .create_response(
+            ctx.interaction.id,
+            &ctx.interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::DeferredChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    flags: Some(MessageFlags::EPHEMERAL),
+                    ..Default::default()
+                }),
+            },
+        )
+        .await?;
+
+    let caller = Caller::new(Feature::Summary, Some(user_id.get()), Some(guild_id.get()));
+    let summary = match tldr::fetch_history(ctx.http_client(), channel_id, limit, since).await {
+        Ok(history) if history.is_empty() => "There is nothing to summarize.".to_string(),
+        Ok(history) => match tldr::summarize(llm.as_ref(), &model, &history, caller).await {
+            Ok(summary) => format!(
+                "**TL;DR of the last {} messages**\n{}",
+                history.len(),
+                tldr::link_citations(&summary, &history, guild_id.get(), channel_id.get())
+            ),
+            Err(e) => {
+                tracing::error!("Failed to summarize the channel: {:?}", e);
+                ai_fallback::user_message(&e).to_string()
+            }
+        },
+        Err(e) => {
+            tracing::error!("Failed to fetch the channel history: {:?}", e);
+            "I couldn't read the history of this channel.".to_string()
+        }
+    };
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .content(Some(tldr::fit(&summary)))?
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,234 @@
+#![allow(clippy::unused_unit)]
+
+use std::sync::Arc;
+
+use color_eyre::Result;
+use deadpool_postgres::Pool;
+use tokio::sync::Mutex;
+use twilight_model::{
+    application::interaction::InteractionData,
+    channel::{message::MessageFlags, Message},
+};
+use vesper::{
+    prelude::*,
+    twilight_exports::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+};
+
+use crate::{
+    ai_context, ai_fallback,
+    ai_usage::{self, Caller, Feature},
+    commands::translate,
+    database::User,
+    db, memory_search,
+    message_actions::{self, Action},
+    structs::State,
+};
+
+/// Discord's message length limit
+const MAX_REPLY_BYTES: usize = 2000;
+
+async fn respond_ephemeral(ctx: &SlashContext<'_, Arc<Mutex<State>>>, message: String) -> DefaultCommandResult {
+    ctx.interaction_client
+        .create_response(
+            ctx.interaction.id,
+            &ctx.interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    content: Some(message),
+                    flags: Some(MessageFlags::EPHEMERAL),
+                    ..Default::default()
+                }),
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// The message the context menu was opened on.
+fn target_message(ctx: &SlashContext<'_, Arc<Mutex<State>>>) -> Option<Message> {
+    let Some(InteractionData::ApplicationCommand(data)) = &ctx.interaction.data else {
+        return None;
+    };
+    let target = data.target_id?.cast();
+    data.resolved.as_ref()?.messages.get(&target).cloned()
+}
+
+/// Explains or roasts the target message with the AI, privately or for everyone to see.
+async fn run_action(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    action: Action,
+    ephemeral: bool,
+) -> DefaultCommandResult {
+    let (Some(message), Some(user_id)) = (target_message(ctx), ctx.interaction.author_id()) else {
+        return respond_ephemeral(ctx, "I can't see that message.".to_string()).await;
+    };
+    if message.content.trim().is_empty() {
+        return respond_ephemeral(ctx, "That message has no text.".to_string()).await;
+    }
+    let guild_id = ctx.interaction.guild_id.map(|id| id.get());
+
+    let state = ctx.data.lock().await;
+    let Some(llm) = state.llm.clone() else {
+        drop(state);
+        return respond_ephemeral(ctx, "No chat model is configured.".to_string()).await;
+    };
+    let database = state.db.clone();
+    let embedder = state.embedder.clone();
+    let config = state.config.clone();
+    drop(state);
+    if let Some(refusal) = ai_usage::check_quota(&database, &config, user_id.get(), guild_id).await {
+        return respond_ephemeral(ctx, refusal).await;
+    }
+
+    let author = match db::get_user(&database, message.author.id.get()).await {
+        Ok(Some(user)) => user,
+        _ => User {
+            id: message.author.id.get() as i64,
+            level: 0,
+            xp: 0,
+            social_credit: 0,
+            name: message.author.name.clone(),
+            relationship: String::new(),
+            example_input: String::new(),
+            example_output: String::new(),
+        },
+    };
+
+    // The model takes longer than Discord waits for a response
+    ctx.interaction_client
+        .create_response(
+            ctx.interaction.id,
+            &ctx.interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::DeferredChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    flags: ephemeral.then_some(MessageFlags::EPHEMERAL),
+                    ..Default::default()
+                }),
+            },
+        )
+        .await?;
+
+    let memories = memory_search::relevant_memories(
+        &database,
+        embedder.as_deref(),
+        message.author.id.get(),
+        &message.content,
+        config.memory_top_k,
+    )
+    .await;
+    let caller = Caller::new(Feature::Chat, Some(user_id.get()), guild_id);
+    let reply = match message_actions::run(
+        llm.as_ref(),
+        &config.openrouter_model,
+        action,
+        &author,
+        &memories,
+        &message.content,
+        caller,
+    )
+    .await
+    {
+        Ok(reply) if !reply.is_empty() => reply,
+        Ok(_) => "For once, I have nothing to say.".to_string(),
+        Err(e) => {
+            tracing::error!("AI Error: {:?}", e);
+            ai_fallback::user_message(&e).to_string()
+        }
+    };
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .content(Some(ai_context::truncate(&reply, MAX_REPLY_BYTES)))?
+        .await?;
+
+    Ok(())
+}
+
+#[command(message, name = "Explain this")]
+#[description = "Explain this message, only visible to you"]
+pub async fn explain_this(ctx: &SlashContext<'_, Arc<Mutex<State>>>) -> DefaultCommandResult {
+    run_action(ctx, Action::Explain, true).await
+}
+
+#[command(message, name = "Roast this")]
+#[description = "Roast the author of this message"]
+pub async fn roast_this(ctx: &SlashContext<'_, Arc<Mutex<State>>>) -> DefaultCommandResult {
+    run_action(ctx, Action::Roast, false).await
+}
+
+#[command(message, name = "Translate this")]
+#[description = "Translate this message into your language, only visible to you"]
+pub async fn translate_this(ctx: &SlashContext<'_, Arc<Mutex<State>>>) -> DefaultCommandResult {
+    let Some(message) = target_message(ctx).filter(|message| !message.content.trim().is_empty()) else {
+        return respond_ephemeral(ctx, "That message has no text to translate.".to_string()).await;
+    };
+    // Into the language the person asking uses Discord in
+    let language = ctx
+        .interaction
+        .locale
+        .as_deref()
+        .map(message_actions::locale_language)
+        .unwrap_or("en");
+
+    let client = ctx.data.lock().await.client.clone();
+    let reply = match translate::translate_text(&client, "auto", language, &message.content).await {
+        Ok(translated_text) => format!(
+            "🌐 **Translation** (→ {})\n\n{}",
+            language.to_uppercase(),
+            translated_text
+        ),
+        Err(e) => e,
+    };
+    respond_ephemeral(ctx, ai_context::truncate(&reply, MAX_REPLY_BYTES).to_string()).await
+}
+
+/// Stages `message` as a memory of its author, returning the reply for `user_id`.
+async fn stage_memory(database: &Pool, message: &Message, user_id: u64) -> Result<String> {
+    let author_id = message.author.id.get();
+    let Some(author) = db::get_user(database, author_id).await? else {
+        return Ok(format!(
+            "I don't know {} well enough yet to remember things about them.",
+            message.author.name
+        ));
+    };
+    let staged = db::stage_memory_candidate(
+        database,
+        author_id,
+        message_actions::REMEMBERED_KEY,
+        &message_actions::remembered_content(&message.content),
+        user_id,
+        message.id.get(),
+    )
+    .await?;
+    Ok(match staged {
+        true => format!(
+            "Noted. This becomes a memory of **{}** once it's approved in the web panel.",
+            author.name
+        ),
+        false => "That message is already waiting for approval.".to_string(),
+    })
+}
+
+#[command(message, name = "Remember this")]
+#[description = "Ask the bot to remember this message about its author"]
+pub async fn remember_this(ctx: &SlashContext<'_, Arc<Mutex<State>>>) -> DefaultCommandResult {
+    let (Some(message), Some(user_id)) = (target_message(ctx), ctx.interaction.author_id()) else {
+        return respond_ephemeral(ctx, "I can't see that message.".to_string()).await;
+    };
+    if message.author.bot || message.content.trim().is_empty() {
+        return respond_ephemeral(ctx, "There is nothing worth remembering in that message.".to_string()).await;
+    }
+
+    let database = ctx.data.lock().await.db.clone();
+    let reply = match stage_memory(&database, &message, user_id.get()).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            tracing::error!("Failed to stage a memory: {:?}", e);
+            "I couldn't note that down, try again later.".to_string()
+        }
+    };
+    respond_ephemeral(ctx, reply).await
+}
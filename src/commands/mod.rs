@@ -1,4 +1,8 @@
+pub mod ai;
+pub mod context_menu;
 pub mod currency;
 pub mod level;
 pub mod qalc;
+pub mod quiz;
+pub mod quizstats;
 pub mod translate;
@@ -0,0 +1,229 @@
+#![allow(clippy::unused_unit)]
+
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use twilight_model::{channel::message::MessageFlags, guild::Permissions};
+use vesper::{
+    prelude::*,
+    twilight_exports::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+};
+
+use crate::{
+    db,
+    quiz::QuizKind,
+    quiz_handler,
+    structs::{Command, State},
+    tournament::{DEFAULT_ROUNDS, MAX_ROUNDS},
+};
+
+async fn respond(ctx: &SlashContext<'_, Arc<Mutex<State>>>, data: InteractionResponseData) -> DefaultCommandResult {
+    ctx.interaction_client
+        .create_response(
+            ctx.interaction.id,
+            &ctx.interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(data),
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn respond_ephemeral(ctx: &SlashContext<'_, Arc<Mutex<State>>>, message: String) -> DefaultCommandResult {
+    respond(
+        ctx,
+        InteractionResponseData {
+            content: Some(message),
+            flags: Some(MessageFlags::EPHEMERAL),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Posts a started quiz, or an ephemeral error when it couldn't be generated.
+async fn respond_command(ctx: &SlashContext<'_, Arc<Mutex<State>>>, command: Option<Command>) -> DefaultCommandResult {
+    match command {
+        Some(command) => {
+            respond(
+                ctx,
+                InteractionResponseData {
+                    content: command.text,
+                    attachments: Some(command.attachments),
+                    ..Default::default()
+                },
+            )
+            .await
+        }
+        None => respond_ephemeral(ctx, "Failed to start the quiz, try again later.".to_string()).await,
+    }
+}
+
+/// The quiz types offered by `/quiz`.
+#[derive(Parse)]
+pub enum QuizChoice {
+    #[parse(rename = "math")]
+    Math,
+    #[parse(rename = "color")]
+    Color,
+    #[parse(rename = "scramble")]
+    WordScramble,
+    #[parse(rename = "typing")]
+    TypingRace,
+    #[parse(rename = "emoji")]
+    EmojiRiddle,
+}
+
+impl From<QuizChoice> for QuizKind {
+    fn from(choice: QuizChoice) -> Self {
+        match choice {
+            QuizChoice::Math => QuizKind::Math,
+            QuizChoice::Color => QuizKind::Color,
+            QuizChoice::WordScramble => QuizKind::WordScramble,
+            QuizChoice::TypingRace => QuizKind::TypingRace,
+            QuizChoice::EmojiRiddle => QuizKind::EmojiRiddle,
+        }
+    }
+}
+
+fn unknown_kind_message() -> String {
+    let kinds = QuizKind::ALL
+        .iter()
+        .map(|kind| format!("`{}`", kind.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("Unknown quiz type. Use one of {}.", kinds)
+}
+
+#[command]
+#[description = "Start a quiz in this channel"]
+pub async fn quiz(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    #[description = "Quiz type"] kind: QuizChoice,
+) -> DefaultCommandResult {
+    let (Some(channel_id), Some(_), Some(user_id)) = (
+        ctx.interaction.channel_id,
+        ctx.interaction.guild_id,
+        ctx.interaction.author_id(),
+    ) else {
+        return respond_ephemeral(ctx, "Quizzes can only be started in a server channel.".to_string()).await;
+    };
+    let channel_id = channel_id.get();
+
+    let mut state = ctx.data.lock().await;
+    if quiz_handler::has_pending_quiz(&state, channel_id) {
+        drop(state);
+        return respond_ephemeral(ctx, "A quiz is already running in this channel.".to_string()).await;
+    }
+
+    let command = quiz_handler::start_quiz(&mut state, kind.into(), channel_id, user_id.get()).await;
+    drop(state);
+
+    respond_command(ctx, command).await
+}
+
+#[command]
+#[description = "Run a quiz tournament of several rounds in this channel"]
+pub async fn tournament(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    #[description = "Number of rounds (default 5, at most 20)"] rounds: Option<i64>,
+    #[description = "Comma separated quiz types (default math, color)"] kinds: Option<String>,
+) -> DefaultCommandResult {
+    let (Some(channel_id), Some(_), Some(user_id)) = (
+        ctx.interaction.channel_id,
+        ctx.interaction.guild_id,
+        ctx.interaction.author_id(),
+    ) else {
+        return respond_ephemeral(ctx, "Tournaments can only be started in a server channel.".to_string()).await;
+    };
+    let channel_id = channel_id.get();
+
+    let kinds = match kinds {
+        Some(kinds) => {
+            let parsed = kinds
+                .split([',', ' '])
+                .filter(|kind| !kind.trim().is_empty())
+                .map(QuizKind::parse)
+                .collect::<Option<Vec<_>>>();
+            match parsed {
+                Some(parsed) if !parsed.is_empty() => parsed,
+                _ => return respond_ephemeral(ctx, unknown_kind_message()).await,
+            }
+        }
+        None => vec![QuizKind::Math, QuizKind::Color],
+    };
+    let rounds = rounds
+        .map(|rounds| rounds.clamp(1, MAX_ROUNDS as i64) as usize)
+        .unwrap_or(DEFAULT_ROUNDS);
+
+    let mut state = ctx.data.lock().await;
+    if quiz_handler::has_pending_quiz(&state, channel_id) {
+        drop(state);
+        return respond_ephemeral(ctx, "A quiz is already running in this channel.".to_string()).await;
+    }
+
+    let command = quiz_handler::start_tournament(&mut state, channel_id, user_id.get(), kinds, rounds).await;
+    drop(state);
+
+    respond_command(ctx, command).await
+}
+
+#[command]
+#[description = "Show or change the quiz settings for this channel"]
+pub async fn quizsettings(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    #[description = "Start a random quiz on 1 in this many messages (0 disables)"] chance: Option<i64>,
+    #[description = "Time out users who let a math quiz expire"] timeout_penalty: Option<bool>,
+) -> DefaultCommandResult {
+    let Some(channel_id) = ctx.interaction.channel_id else {
+        return respond_ephemeral(ctx, "This command only works in a channel.".to_string()).await;
+    };
+
+    let mut state = ctx.data.lock().await;
+    let mut settings = state.channel_settings(channel_id.get());
+
+    if chance.is_some() || timeout_penalty.is_some() {
+        let can_manage = ctx
+            .interaction
+            .member
+            .as_ref()
+            .and_then(|member| member.permissions)
+            .is_some_and(|permissions| permissions.contains(Permissions::MANAGE_CHANNELS));
+        if !can_manage {
+            drop(state);
+            return respond_ephemeral(
+                ctx,
+                "You need the Manage Channels permission to change quiz settings.".to_string(),
+            )
+            .await;
+        }
+
+        if let Some(chance) = chance {
+            settings.quiz_chance = chance.clamp(0, i32::MAX as i64) as i32;
+        }
+        if let Some(timeout_penalty) = timeout_penalty {
+            settings.quiz_timeout_penalty = timeout_penalty;
+        }
+        db::upsert_channel_settings(&state.db, &settings).await?;
+        state.channel_settings.insert(channel_id.get(), settings.clone());
+    }
+    drop(state);
+
+    let chance = match settings.quiz_chance {
+        0 => "disabled".to_string(),
+        n => format!("1 in {}", n),
+    };
+    respond_ephemeral(
+        ctx,
+        format!(
+            "**Quiz settings for <#{}>**\nRandom quiz chance: {}\nMath timeout penalty: {}",
+            channel_id,
+            chance,
+            if settings.quiz_timeout_penalty { "on" } else { "off" }
+        ),
+    )
+    .await
+}
@@ -0,0 +1,62 @@
+#![allow(clippy::unused_unit)]
+
+use std::sync::Arc;
+
+use crate::db;
+use tokio::sync::Mutex;
+
+use twilight_model::id::{marker::UserMarker, Id};
+use vesper::{
+    prelude::*,
+    twilight_exports::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+};
+
+use crate::structs::State;
+
+#[command]
+#[description = "Show quiz accuracy, fastest solve and streaks"]
+pub async fn quizstats(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    #[description = "The user to show quiz stats for"] user: Option<Id<UserMarker>>,
+) -> DefaultCommandResult {
+    // `member` is only set in guilds, `author_id` falls back to the DM user
+    let Some(id) = user.or_else(|| ctx.interaction.author_id()).map(|id| id.get()) else {
+        return Ok(());
+    };
+    let state = ctx.data.lock().await;
+
+    let stats = db::get_quiz_stats(&state.db, id).await?;
+
+    let message = if stats.attempts == 0 {
+        format!("<@{id}> hasn't finished a quiz yet.")
+    } else {
+        let fastest = stats
+            .fastest_ms
+            .map(|ms| format!("{:.3}s", ms as f64 / 1000.0))
+            .unwrap_or_else(|| "-".to_string());
+        format!(
+            "**Quiz stats for <@{id}>**\nSolved: {}/{} ({:.1}% accuracy)\nFastest solve: {fastest}\nCurrent streak: {}\nBest streak: {}",
+            stats.solved,
+            stats.attempts,
+            stats.accuracy(),
+            stats.current_streak,
+            stats.best_streak,
+        )
+    };
+
+    ctx.interaction_client
+        .create_response(
+            ctx.interaction.id,
+            &ctx.interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    content: Some(message),
+                    ..Default::default()
+                }),
+            },
+        )
+        .await?;
+
+    Ok(())
+}
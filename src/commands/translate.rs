@@ -2,52 +2,60 @@
 
 use std::sync::Arc;
 
+use reqwest::Client;
 use tokio::sync::Mutex;
 use vesper::prelude::*;
 
 use crate::structs::State;
 
-#[command]
-#[description = "Translate text between languages (uses Google Translate API)"]
-pub async fn translate(
-    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
-    #[description = "Source language code (en, es, fr, de, pl, ja, zh, ru, it, pt, auto)"] from: String,
-    #[description = "Target language code (en, es, fr, de, pl, ja, zh, ru, it, pt)"] to: String,
-    #[description = "Text to translate"] text: String,
-) -> DefaultCommandResult {
-    let state = ctx.data.lock().await;
-    let client = &state.client;
-
+/// Translates `text` with Google Translate's public API, `from` may be `auto`.
+pub async fn translate_text(client: &Client, from: &str, to: &str, text: &str) -> Result<String, String> {
     // Using Google Translate's public API (free tier)
     let url = format!(
         "https://translate.googleapis.com/translate_a/single?client=gtx&sl={}&tl={}&dt=t&q={}",
         from,
         to,
-        urlencoding::encode(&text)
+        urlencoding::encode(text)
     );
 
-    let response = match client.get(&url).send().await {
-        Ok(resp) => resp,
-        Err(e) => {
-            ctx.interaction_client
-                .create_response(
-                    ctx.interaction.id,
-                    &ctx.interaction.token,
-                    &vesper::twilight_exports::InteractionResponse {
-                        kind: vesper::twilight_exports::InteractionResponseType::ChannelMessageWithSource,
-                        data: Some(vesper::twilight_exports::InteractionResponseData {
-                            content: Some(format!("Failed to fetch translation: {}", e)),
-                            ..Default::default()
-                        }),
-                    },
-                )
-                .await?;
-            return Ok(());
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch translation: {}", e))?;
+    let json: serde_json::Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse translation response: {}", e))?;
+
+    // Parse the translation from the response
+    let mut translated_text = String::new();
+    if let Some(translations) = json.get(0).and_then(|v| v.as_array()) {
+        for translation in translations {
+            if let Some(text) = translation.get(0).and_then(|v| v.as_str()) {
+                translated_text.push_str(text);
+            }
         }
-    };
+    }
+
+    if translated_text.is_empty() {
+        translated_text = "Translation failed or returned empty result".to_string();
+    }
+    Ok(translated_text)
+}
+
+#[command]
+#[description = "Translate text between languages (uses Google Translate API)"]
+pub async fn translate(
+    ctx: &SlashContext<'_, Arc<Mutex<State>>>,
+    #[description = "Source language code (en, es, fr, de, pl, ja, zh, ru, it, pt, auto)"] from: String,
+    #[description = "Target language code (en, es, fr, de, pl, ja, zh, ru, it, pt)"] to: String,
+    #[description = "Text to translate"] text: String,
+) -> DefaultCommandResult {
+    let client = ctx.data.lock().await.client.clone();
 
-    let json: serde_json::Value = match response.json().await {
-        Ok(j) => j,
+    let translated_text = match translate_text(&client, &from, &to, &text).await {
+        Ok(translated_text) => translated_text,
         Err(e) => {
             ctx.interaction_client
                 .create_response(
@@ -56,7 +64,7 @@ pub async fn translate(
                     &vesper::twilight_exports::InteractionResponse {
                         kind: vesper::twilight_exports::InteractionResponseType::ChannelMessageWithSource,
                         data: Some(vesper::twilight_exports::InteractionResponseData {
-                            content: Some(format!("Failed to parse translation response: {}", e)),
+                            content: Some(e),
                             ..Default::default()
                         }),
                     },
@@ -66,20 +74,6 @@ pub async fn translate(
         }
     };
 
-    // Parse the translation from the response
-    let mut translated_text = String::new();
-    if let Some(translations) = json.get(0).and_then(|v| v.as_array()) {
-        for translation in translations {
-            if let Some(text) = translation.get(0).and_then(|v| v.as_str()) {
-                translated_text.push_str(text);
-            }
-        }
-    }
-
-    if translated_text.is_empty() {
-        translated_text = "Translation failed or returned empty result".to_string();
-    }
-
     let from_lang = if from == "auto" {
         "Auto Detected".to_string()
     } else {
@@ -2,6 +2,8 @@ use std::{collections::HashMap, io, num::ParseIntError, sync::Arc};
 
 use clap::Parser;
 
+use crate::{ai_usage::Prices, quiz_difficulty::Difficulty};
+
 #[derive(Parser, Clone, Debug, Default)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
@@ -35,20 +37,96 @@ pub struct Config {
     pub openrouter_model: String,
     #[arg(long, env)]
     pub openrouter_memory_model: Option<String>,
+    /// Comma separated models tried in order when the requested one is rate limited, down or answers with nothing
+    #[arg(long, env, default_value = "", value_parser = parse_str_array)]
+    pub openrouter_fallback_models: Arc<Vec<String>>,
+    /// OpenAI-compatible server (Ollama, llama.cpp server, ...) to use instead of OpenRouter, e.g.
+    /// `http://localhost:11434/v1`. Models are still picked with the `openrouter_*model` options
+    #[arg(long, env)]
+    pub llm_base_url: Option<String>,
+    #[arg(long, env)]
+    pub llm_api_key: Option<String>,
+    /// Embedding model for picking the memories relevant to a message, e.g. `nomic-embed-text`. Without one,
+    /// memories are picked by the words they share with the conversation
+    #[arg(long, env)]
+    pub embedding_model: Option<String>,
+    /// OpenAI-compatible embeddings server, `llm_base_url` when unset
+    #[arg(long, env)]
+    pub embedding_base_url: Option<String>,
+    /// `llm_api_key` when unset
+    #[arg(long, env)]
+    pub embedding_api_key: Option<String>,
+    /// Memories of the active user put in the prompt
+    #[arg(long, env, default_value = "5")]
+    pub memory_top_k: usize,
+    /// Tokens a user may spend on AI per day (UTC); 0 for no limit
+    #[arg(long, env, default_value = "50000")]
+    pub ai_user_daily_tokens: u64,
+    /// Tokens a guild may spend on AI per day (UTC); 0 for no limit
+    #[arg(long, env, default_value = "500000")]
+    pub ai_guild_daily_tokens: u64,
+    /// USD per million prompt tokens, for the usage dashboard when the provider doesn't report the cost
+    #[arg(long, env, default_value = "0")]
+    pub llm_prompt_price: f64,
+    /// USD per million completion tokens, for the usage dashboard when the provider doesn't report the cost
+    #[arg(long, env, default_value = "0")]
+    pub llm_completion_price: f64,
+    /// Comma separated `model=prompt:completion` prices per million tokens for models priced differently from the
+    /// `llm_*_price` options
+    #[arg(long, env, default_value = "", value_parser = parse_model_prices)]
+    pub llm_model_prices: Arc<HashMap<String, Prices>>,
+    /// Comma separated words that make an unprompted AI reply worth it
+    #[arg(long, env, default_value = "", value_parser = parse_str_array)]
+    pub interjection_keywords: Arc<Vec<String>>,
+    /// Small model that decides whether an unprompted reply is worth it when no keyword does; without one only
+    /// keywords and questions to the whole channel get a reply
+    #[arg(long, env)]
+    pub interjection_model: Option<String>,
+    /// Messages after which a channel's rolling summary is refreshed, 0 to turn summaries off
+    #[arg(long, env, default_value = "50")]
+    pub channel_summary_messages: u32,
+    /// Minutes after which a channel's rolling summary is refreshed when a few messages came in since
+    #[arg(long, env, default_value = "60")]
+    pub channel_summary_minutes: u64,
+    /// Record the prompt and reply of every AI call for the web panel
+    #[arg(long, env, default_value = "false")]
+    pub ai_traces: bool,
+    /// Days recorded AI calls are kept
+    #[arg(long, env, default_value = "7")]
+    pub ai_trace_retention_days: i32,
+    /// Estimated tokens of chat history sent to the model with each message
+    #[arg(long, env, default_value = "3000")]
+    pub context_token_budget: usize,
+    /// The chat model accepts images; attachments are described in text otherwise
+    #[arg(long, env, default_value = "false")]
+    pub openrouter_vision: bool,
     #[arg(long, env)]
     pub openrouter_site_url: Option<String>,
     #[arg(long, env)]
     pub openrouter_site_name: Option<String>,
     #[arg(long, env)]
     pub today_i_channel: Option<u64>,
+    /// Brave Search API key; also lets the chat model search the web before answering
     #[arg(long, env)]
     pub brave_api: Option<String>,
+    #[arg(long, env, default_value = "https://api.search.brave.com/res/v1")]
+    pub brave_api_base_url: String,
     #[arg(long, env)]
     pub pfp_channel: Option<u64>,
     #[arg(long, env, default_value = "false")]
     pub pfp_on_startup: bool,
     #[arg(long, env)]
     pub web_port: Option<u16>,
+    /// Fixed difficulty tier for math quizzes; picked from the triggering user's level when unset. Other quiz
+    /// kinds always follow the user's level
+    #[arg(long, env, value_enum)]
+    pub math_difficulty: Option<Difficulty>,
+    /// Ask the OpenRouter model for math questions instead of generating them procedurally
+    #[arg(long, env, default_value = "false")]
+    pub math_llm_questions: bool,
+    /// Post math questions as noisy images so they can't be pasted into a calculator
+    #[arg(long, env, default_value = "false")]
+    pub math_image_questions: bool,
 }
 
 fn parse_str_array(src: &str) -> Result<Arc<Vec<String>>, io::Error> {
@@ -66,6 +144,24 @@ fn parse_invites(src: &str) -> Result<HashMap<String, String>, io::Error> {
     }
     Ok(map)
 }
+fn parse_model_prices(src: &str) -> Result<Arc<HashMap<String, Prices>>, io::Error> {
+    let mut prices = HashMap::new();
+    for pair in src.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let invalid = || io::Error::other(format!("Expected model=prompt:completion, got `{}`", pair));
+        let (model, price) = pair.rsplit_once('=').ok_or_else(invalid)?;
+        let (prompt, completion) = price.split_once(':').ok_or_else(invalid)?;
+        let price = |value: &str| value.trim().parse::<f64>().map_err(|_| invalid());
+        prices.insert(
+            model.trim().to_string(),
+            Prices {
+                prompt: price(prompt)?,
+                completion: price(completion)?,
+            },
+        );
+    }
+    Ok(Arc::new(prices))
+}
+
 fn vec_u64_parser(src: &str) -> Result<Arc<Vec<u64>>, ParseIntError> {
     let mut vec = Vec::new();
     for pair in src.split(',') {
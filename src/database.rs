@@ -32,4 +32,157 @@ pub struct MathQuestion {
     pub id: i64,
     pub question: String,
     pub answer: f64,
+    pub difficulty: String,
+    pub source: String,
+}
+
+/// A chat persona. Its `template` is a Tera template rendered into the system prompt, see `persona.rs`.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Persona {
+    pub id: i64,
+    pub name: String,
+    pub template: String,
+    /// Overrides the configured chat model
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    /// Include the remembered facts about the active user
+    pub use_memories: bool,
+    /// Include the users' example dialogues
+    pub use_examples: bool,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PersonaAssignment {
+    pub target_id: i64,
+    /// `channel` or `guild`
+    pub kind: String,
+    pub persona_id: i64,
+    pub persona_name: String,
+}
+
+/// Per-channel overrides. Channels without a row use [`ChannelSettings::new`].
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ChannelSettings {
+    pub channel_id: i64,
+    /// A random quiz starts on 1 in `quiz_chance` messages; 0 disables random quizzes
+    pub quiz_chance: i32,
+    /// Time out the challenged user when a math quiz expires
+    pub quiz_timeout_penalty: bool,
+    /// Move AI conversations started here into their own thread
+    pub ai_threads: bool,
+    /// The AI replies unprompted to 1 in `interjection_chance` messages; 0 disables interjections
+    pub interjection_chance: i32,
+    /// Seconds between interjections
+    pub interjection_cooldown: i32,
+    /// Hours (UTC) without interjections, from start up to but not including end
+    pub quiet_hours_start: Option<i16>,
+    pub quiet_hours_end: Option<i16>,
+}
+
+impl ChannelSettings {
+    pub fn new(channel_id: u64) -> Self {
+        Self {
+            channel_id: channel_id as i64,
+            quiz_chance: 500,
+            quiz_timeout_penalty: false,
+            ai_threads: false,
+            interjection_chance: 200,
+            interjection_cooldown: 600,
+            quiet_hours_start: None,
+            quiet_hours_end: None,
+        }
+    }
+}
+
+/// A thread the bot started for an AI conversation.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AiThread {
+    pub thread_id: i64,
+    /// The channel the thread was started in
+    pub parent_id: i64,
+    pub started_by: i64,
+}
+
+/// A stored message of an AI thread, the bot's own replies included.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ThreadMessage {
+    pub message_id: i64,
+    pub thread_id: i64,
+    pub author_name: String,
+    pub from_bot: bool,
+    pub content: String,
+    pub reply_to: Option<i64>,
+}
+
+/// A thumbs up (1) or down (-1) on an AI reply.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AiFeedback {
+    pub message_id: i64,
+    pub user_id: i64,
+    pub rating: i16,
+    pub persona: String,
+    pub model: String,
+    pub system_prompt: String,
+    pub user_message: String,
+    pub response: String,
+}
+
+/// Votes on the replies of one persona and model combination.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FeedbackSummary {
+    pub persona: String,
+    pub model: String,
+    pub up: i64,
+    pub down: i64,
+}
+
+/// A recorded AI call. `created_at` is formatted by the query, new traces leave `id` and `created_at` unset.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct AiTrace {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    pub channel_id: Option<i64>,
+    pub guild_id: Option<i64>,
+    pub persona: String,
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub system_prompt: String,
+    pub user_message: String,
+    pub response: String,
+    pub error: Option<String>,
+    pub latency_ms: i32,
+    pub rerun_of: Option<i64>,
+    pub created_at: String,
+}
+
+/// What a channel talked about before the cached messages. `updated_at` is formatted by the query.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ChannelSummary {
+    pub channel_id: i64,
+    pub guild_id: Option<i64>,
+    pub summary: String,
+    pub message_count: i32,
+    pub updated_at: String,
+}
+
+/// Requests, tokens and cost of one row of the usage dashboard.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct UsageRow {
+    /// A day, feature, user or guild
+    pub label: String,
+    pub requests: i64,
+    pub tokens: i64,
+    pub cost: f64,
+}
+
+/// A memory someone asked the bot to keep with "Remember this", waiting for approval in the web panel.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MemoryCandidate {
+    pub id: i64,
+    pub user_id: i64,
+    pub key: String,
+    pub content: String,
+    /// Who asked
+    pub staged_by: i64,
+    pub message_id: i64,
 }
@@ -2,7 +2,12 @@ use color_eyre::Result;
 use deadpool_postgres::Pool;
 use postgres_from_row::FromRow;
 
-use crate::database::{Memory, MathQuestion, User};
+use crate::database::{
+    AiFeedback, AiThread, AiTrace, ChannelSettings, ChannelSummary, FeedbackSummary, MathQuestion, Memory,
+    MemoryCandidate, Persona, PersonaAssignment, ThreadMessage, UsageRow, User,
+};
+
+use crate::{ai_usage::UsageRecord, interjection::Verdict, message_actions};
 
 fn uid(id: u64) -> i64 {
     id as i64
@@ -20,6 +25,45 @@ pub async fn run_migrations(pool: &Pool) -> Result<()> {
     client
         .batch_execute(include_str!("../migrations/003_profile_evolution.sql"))
         .await?;
+    client
+        .batch_execute(include_str!("../migrations/004_quiz_stats.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/005_math_question_bank.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/006_channel_settings.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/007_quiz_attempt_flagged.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/008_personas.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/009_ai_threads.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/010_ai_feedback.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/011_ai_usage.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/012_ai_traces.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/013_interjections.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/014_channel_summaries.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/015_memory_candidates.sql"))
+        .await?;
+    client
+        .batch_execute(include_str!("../migrations/016_memory_embeddings.sql"))
+        .await?;
     Ok(())
 }
 
@@ -181,17 +225,129 @@ pub async fn reject_profile_candidate(pool: &Pool, candidate_id: i64) -> Result<
     Ok(rows.first().map(|row| row.get(0)))
 }
 
-pub async fn get_memories(pool: &Pool, user_id: u64) -> Result<Vec<Memory>> {
+/// Stages a memory of `user_id` for approval, returning `false` when the message was staged before.
+pub async fn stage_memory_candidate(
+    pool: &Pool,
+    user_id: u64,
+    key: &str,
+    content: &str,
+    staged_by: u64,
+    message_id: u64,
+) -> Result<bool> {
+    let client = pool.get().await?;
+    let inserted = client
+        .execute(
+            "INSERT INTO memory_candidate (user_id, key, content, staged_by, message_id)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (user_id, message_id) DO NOTHING",
+            &[&uid(user_id), &key, &content, &uid(staged_by), &uid(message_id)],
+        )
+        .await?;
+    Ok(inserted > 0)
+}
+
+pub async fn get_memory_candidates(pool: &Pool, user_id: u64) -> Result<Vec<MemoryCandidate>> {
     let client = pool.get().await?;
     let rows = client
         .query(
-            "SELECT * FROM memory WHERE user_id = $1 ORDER BY id DESC LIMIT 5",
+            "SELECT id, user_id, key, content, staged_by, message_id FROM memory_candidate
+             WHERE user_id = $1 ORDER BY created_at DESC",
             &[&uid(user_id)],
         )
         .await?;
+    Ok(rows.iter().map(MemoryCandidate::from_row).collect())
+}
+
+/// Turns a staged memory into a memory, adding to an existing memory with the same key. See
+/// [`message_actions::add_remembered`] for how they are combined.
+pub async fn approve_memory_candidate(pool: &Pool, candidate_id: i64) -> Result<Option<i64>> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let Some(staged) = transaction
+        .query_opt(
+            "DELETE FROM memory_candidate WHERE id = $1 RETURNING user_id, key, content",
+            &[&candidate_id],
+        )
+        .await?
+    else {
+        return Ok(None);
+    };
+    let (user_id, key, content): (i64, String, String) = (staged.get(0), staged.get(1), staged.get(2));
+    let existing = transaction
+        .query_opt(
+            "SELECT content FROM memory WHERE user_id = $1 AND key = $2 FOR UPDATE",
+            &[&user_id, &key],
+        )
+        .await?
+        .map(|row| row.get::<_, String>(0))
+        .unwrap_or_default();
+    transaction
+        .execute(
+            "INSERT INTO memory (user_id, key, content) VALUES ($1, $2, $3)
+             ON CONFLICT (user_id, key) DO UPDATE SET content = EXCLUDED.content",
+            &[&user_id, &key, &message_actions::add_remembered(&existing, &content)],
+        )
+        .await?;
+    transaction.commit().await?;
+    Ok(Some(user_id))
+}
+
+pub async fn reject_memory_candidate(pool: &Pool, candidate_id: i64) -> Result<Option<i64>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "DELETE FROM memory_candidate WHERE id = $1 RETURNING user_id",
+            &[&candidate_id],
+        )
+        .await?;
+    Ok(rows.first().map(|row| row.get(0)))
+}
+
+/// The newest `limit` memories of `user_id`, newest first. `memory_search` ranks them and picks the ones that go
+/// into a prompt.
+pub async fn get_memories(pool: &Pool, user_id: u64, limit: i64) -> Result<Vec<Memory>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT * FROM memory WHERE user_id = $1 ORDER BY id DESC LIMIT $2",
+            &[&uid(user_id), &limit],
+        )
+        .await?;
     Ok(rows.iter().map(Memory::from_row).collect())
 }
 
+/// The stored embeddings of a user's memories made with `model`: memory id, embedded text and vector.
+pub async fn get_memory_embeddings(pool: &Pool, user_id: u64, model: &str) -> Result<Vec<(i64, String, Vec<f32>)>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT e.memory_id, e.source, e.embedding FROM memory_embedding e JOIN memory m ON m.id = e.memory_id
+             WHERE m.user_id = $1 AND e.model = $2",
+            &[&uid(user_id), &model],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+}
+
+pub async fn save_memory_embedding(
+    pool: &Pool,
+    memory_id: i64,
+    model: &str,
+    source: &str,
+    embedding: &[f32],
+) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO memory_embedding (memory_id, model, source, embedding) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (memory_id) DO UPDATE
+             SET model = EXCLUDED.model, source = EXCLUDED.source, embedding = EXCLUDED.embedding, updated_at = now()",
+            &[&memory_id, &model, &source, &embedding],
+        )
+        .await?;
+    Ok(())
+}
+
 pub async fn upsert_memory(pool: &Pool, user_id: u64, key: &str, content: &str) -> Result<()> {
     let client = pool.get().await?;
     client.execute(
@@ -274,8 +430,554 @@ pub async fn get_all_users_web(pool: &Pool) -> Result<Vec<User>> {
     Ok(rows.iter().map(User::from_row).collect())
 }
 
-pub async fn get_math_questions(pool: &Pool) -> Result<Vec<MathQuestion>> {
+pub async fn math_question_exists(pool: &Pool, question: &str) -> Result<bool> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one("SELECT EXISTS (SELECT 1 FROM math_question WHERE question = $1)", &[&question])
+        .await?;
+    Ok(row.get(0))
+}
+
+pub async fn insert_math_question(
+    pool: &Pool,
+    question: &str,
+    answer: f64,
+    difficulty: &str,
+    source: &str,
+) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute(
+        "INSERT INTO math_question (question, answer, difficulty, source) VALUES ($1, $2, $3, $4)",
+        &[&question, &answer, &difficulty, &source],
+    ).await?;
+    Ok(())
+}
+
+/// Draws a stored question of the given difficulty, optionally restricted to one source.
+pub async fn random_math_question(pool: &Pool, difficulty: &str, source: Option<&str>) -> Result<Option<MathQuestion>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT * FROM math_question WHERE difficulty = $1 AND ($2::TEXT IS NULL OR source = $2)
+             ORDER BY random() LIMIT 1",
+            &[&difficulty, &source],
+        )
+        .await?;
+    Ok(rows.first().map(MathQuestion::from_row))
+}
+
+pub async fn get_all_channel_settings(pool: &Pool) -> Result<Vec<ChannelSettings>> {
+    let client = pool.get().await?;
+    let rows = client.query("SELECT * FROM channel_settings", &[]).await?;
+    Ok(rows.iter().map(ChannelSettings::from_row).collect())
+}
+
+pub async fn upsert_channel_settings(pool: &Pool, settings: &ChannelSettings) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute(
+        "INSERT INTO channel_settings (channel_id, quiz_chance, quiz_timeout_penalty, ai_threads, interjection_chance,
+                                       interjection_cooldown, quiet_hours_start, quiet_hours_end)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+         ON CONFLICT (channel_id) DO UPDATE SET
+           quiz_chance = EXCLUDED.quiz_chance,
+           quiz_timeout_penalty = EXCLUDED.quiz_timeout_penalty,
+           ai_threads = EXCLUDED.ai_threads,
+           interjection_chance = EXCLUDED.interjection_chance,
+           interjection_cooldown = EXCLUDED.interjection_cooldown,
+           quiet_hours_start = EXCLUDED.quiet_hours_start,
+           quiet_hours_end = EXCLUDED.quiet_hours_end",
+        &[&settings.channel_id, &settings.quiz_chance, &settings.quiz_timeout_penalty, &settings.ai_threads,
+          &settings.interjection_chance, &settings.interjection_cooldown, &settings.quiet_hours_start,
+          &settings.quiet_hours_end],
+    ).await?;
+    Ok(())
+}
+
+/// A finished quiz: either solved by `user_id` or timed out for the user it was issued to.
+#[derive(Debug)]
+pub struct QuizAttempt<'a> {
+    pub quiz_type: &'a str,
+    pub channel_id: u64,
+    pub user_id: u64,
+    pub question: &'a str,
+    pub answer: &'a str,
+    pub correct: bool,
+    pub solve_time_ms: Option<i32>,
+    /// Answered faster than a person could type it
+    pub flagged: bool,
+}
+
+pub async fn record_quiz_attempt(pool: &Pool, attempt: &QuizAttempt<'_>) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute(
+        "INSERT INTO quiz_attempt (quiz_type, channel_id, user_id, question, answer, correct, solve_time_ms, flagged)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        &[&attempt.quiz_type, &uid(attempt.channel_id), &uid(attempt.user_id), &attempt.question,
+          &attempt.answer, &attempt.correct, &attempt.solve_time_ms, &attempt.flagged],
+    ).await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize, Debug, Default)]
+pub struct QuizStats {
+    pub attempts: i64,
+    pub solved: i64,
+    pub fastest_ms: Option<i32>,
+    pub current_streak: usize,
+    pub best_streak: usize,
+}
+
+impl QuizStats {
+    pub fn accuracy(&self) -> f64 {
+        if self.attempts == 0 {
+            return 0.0;
+        }
+        self.solved as f64 / self.attempts as f64 * 100.0
+    }
+}
+
+/// Returns (current, best) runs of consecutive `true` values, oldest first.
+fn streaks(results: impl IntoIterator<Item = bool>) -> (usize, usize) {
+    let (mut current, mut best) = (0, 0);
+    for correct in results {
+        current = if correct { current + 1 } else { 0 };
+        best = best.max(current);
+    }
+    (current, best)
+}
+
+pub async fn get_quiz_stats(pool: &Pool, user_id: u64) -> Result<QuizStats> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT correct AND NOT flagged, solve_time_ms FROM quiz_attempt WHERE user_id = $1 ORDER BY created_at, id",
+            &[&uid(user_id)],
+        )
+        .await?;
+    // Flagged answers count as misses
+    let results = rows.iter().map(|r| r.get::<_, bool>(0)).collect::<Vec<_>>();
+    let (current_streak, best_streak) = streaks(results.iter().copied());
+    Ok(QuizStats {
+        attempts: results.len() as i64,
+        solved: results.iter().filter(|correct| **correct).count() as i64,
+        fastest_ms: rows
+            .iter()
+            .filter(|r| r.get::<_, bool>(0))
+            .filter_map(|r| r.get::<_, Option<i32>>(1))
+            .min(),
+        current_streak,
+        best_streak,
+    })
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct QuizLeaderboardEntry {
+    pub user_id: i64,
+    pub name: String,
+    pub attempts: i64,
+    pub solved: i64,
+    pub accuracy: f64,
+    pub fastest_ms: Option<i32>,
+}
+
+pub async fn get_quiz_leaderboard(pool: &Pool, limit: i64) -> Result<Vec<QuizLeaderboardEntry>> {
+    let client = pool.get().await?;
+    let rows = client.query(
+        r#"SELECT a.user_id, COALESCE(u.name, ''), COUNT(*), COUNT(*) FILTER (WHERE a.correct AND NOT a.flagged),
+                  MIN(a.solve_time_ms) FILTER (WHERE a.correct AND NOT a.flagged)
+           FROM quiz_attempt a LEFT JOIN "user" u ON u.id = a.user_id
+           GROUP BY a.user_id, u.name
+           ORDER BY 4 DESC, 5 ASC NULLS LAST
+           LIMIT $1"#,
+        &[&limit],
+    ).await?;
+    Ok(rows.into_iter().map(|row| {
+        let (attempts, solved): (i64, i64) = (row.get(2), row.get(3));
+        QuizLeaderboardEntry {
+            user_id: row.get(0), name: row.get(1), attempts, solved,
+            accuracy: solved as f64 / attempts as f64 * 100.0, fastest_ms: row.get(4),
+        }
+    }).collect())
+}
+
+pub async fn get_personas(pool: &Pool) -> Result<Vec<Persona>> {
+    let client = pool.get().await?;
+    let rows = client.query("SELECT * FROM persona ORDER BY name", &[]).await?;
+    Ok(rows.iter().map(Persona::from_row).collect())
+}
+
+pub async fn get_persona(pool: &Pool, id: i64) -> Result<Option<Persona>> {
+    let client = pool.get().await?;
+    let rows = client.query("SELECT * FROM persona WHERE id = $1", &[&id]).await?;
+    Ok(rows.first().map(Persona::from_row))
+}
+
+/// The persona assigned to `channel_id`, falling back to the one assigned to `guild_id`.
+pub async fn get_persona_for(pool: &Pool, channel_id: u64, guild_id: Option<u64>) -> Result<Option<Persona>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT p.* FROM persona_assignment a JOIN persona p ON p.id = a.persona_id
+             WHERE (a.kind = 'channel' AND a.target_id = $1) OR (a.kind = 'guild' AND a.target_id = $2)
+             ORDER BY a.kind = 'channel' DESC
+             LIMIT 1",
+            &[&uid(channel_id), &guild_id.map(uid)],
+        )
+        .await?;
+    Ok(rows.first().map(Persona::from_row))
+}
+
+/// Inserts a new persona (when `persona.id` is 0) or updates an existing one, returning its id.
+pub async fn save_persona(pool: &Pool, persona: &Persona) -> Result<i64> {
+    let client = pool.get().await?;
+    let row = if persona.id == 0 {
+        client
+            .query_one(
+                "INSERT INTO persona (name, template, model, temperature, use_memories, use_examples)
+                 VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                &[&persona.name, &persona.template, &persona.model, &persona.temperature,
+                  &persona.use_memories, &persona.use_examples],
+            )
+            .await?
+    } else {
+        client
+            .query_one(
+                "UPDATE persona SET name = $2, template = $3, model = $4, temperature = $5,
+                   use_memories = $6, use_examples = $7
+                 WHERE id = $1 RETURNING id",
+                &[&persona.id, &persona.name, &persona.template, &persona.model, &persona.temperature,
+                  &persona.use_memories, &persona.use_examples],
+            )
+            .await?
+    };
+    Ok(row.get(0))
+}
+
+pub async fn delete_persona(pool: &Pool, id: i64) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute("DELETE FROM persona WHERE id = $1", &[&id]).await?;
+    Ok(())
+}
+
+pub async fn get_persona_assignments(pool: &Pool) -> Result<Vec<PersonaAssignment>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT a.target_id, a.kind, a.persona_id, p.name AS persona_name
+             FROM persona_assignment a JOIN persona p ON p.id = a.persona_id
+             ORDER BY a.kind, a.target_id",
+            &[],
+        )
+        .await?;
+    Ok(rows.iter().map(PersonaAssignment::from_row).collect())
+}
+
+/// Assigns a persona to a channel or guild; `None` goes back to the default persona.
+pub async fn assign_persona(pool: &Pool, target_id: u64, kind: &str, persona_id: Option<i64>) -> Result<()> {
     let client = pool.get().await?;
-    let rows = client.query("SELECT * FROM math_question", &[]).await?;
-    Ok(rows.iter().map(MathQuestion::from_row).collect())
+    match persona_id {
+        Some(persona_id) => {
+            client
+                .execute(
+                    "INSERT INTO persona_assignment (target_id, kind, persona_id) VALUES ($1, $2, $3)
+                     ON CONFLICT (target_id) DO UPDATE SET kind = EXCLUDED.kind, persona_id = EXCLUDED.persona_id",
+                    &[&uid(target_id), &kind, &persona_id],
+                )
+                .await?;
+        }
+        None => {
+            client
+                .execute("DELETE FROM persona_assignment WHERE target_id = $1", &[&uid(target_id)])
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn get_ai_threads(pool: &Pool) -> Result<Vec<AiThread>> {
+    let client = pool.get().await?;
+    let rows = client.query("SELECT thread_id, parent_id, started_by FROM ai_thread", &[]).await?;
+    Ok(rows.iter().map(AiThread::from_row).collect())
+}
+
+pub async fn create_ai_thread(pool: &Pool, thread_id: u64, parent_id: u64, started_by: u64) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO ai_thread (thread_id, parent_id, started_by) VALUES ($1, $2, $3)
+             ON CONFLICT (thread_id) DO NOTHING",
+            &[&uid(thread_id), &uid(parent_id), &uid(started_by)],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Forgets a deleted thread along with its stored messages.
+pub async fn delete_ai_thread(pool: &Pool, thread_id: u64) -> Result<()> {
+    let client = pool.get().await?;
+    client.execute("DELETE FROM ai_thread WHERE thread_id = $1", &[&uid(thread_id)]).await?;
+    Ok(())
+}
+
+pub async fn add_thread_message(pool: &Pool, message: &ThreadMessage) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO ai_thread_message (message_id, thread_id, author_name, from_bot, content, reply_to)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (message_id) DO UPDATE SET content = EXCLUDED.content",
+            &[&message.message_id, &message.thread_id, &message.author_name, &message.from_bot,
+              &message.content, &message.reply_to],
+        )
+        .await?;
+    Ok(())
+}
+
+/// The latest `limit` messages of a thread, newest first.
+pub async fn get_thread_messages(pool: &Pool, thread_id: u64, limit: i64) -> Result<Vec<ThreadMessage>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT message_id, thread_id, author_name, from_bot, content, reply_to FROM ai_thread_message
+             WHERE thread_id = $1 ORDER BY message_id DESC LIMIT $2",
+            &[&uid(thread_id), &limit],
+        )
+        .await?;
+    Ok(rows.iter().map(ThreadMessage::from_row).collect())
+}
+
+/// Records a vote, replacing the user's earlier vote on the same reply.
+pub async fn save_ai_feedback(pool: &Pool, feedback: &AiFeedback) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO ai_feedback (message_id, user_id, rating, persona, model, system_prompt, user_message, response)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             ON CONFLICT (message_id, user_id) DO UPDATE SET
+               rating = EXCLUDED.rating,
+               response = EXCLUDED.response,
+               created_at = now()",
+            &[&feedback.message_id, &feedback.user_id, &feedback.rating, &feedback.persona, &feedback.model,
+              &feedback.system_prompt, &feedback.user_message, &feedback.response],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn get_feedback_summary(pool: &Pool) -> Result<Vec<FeedbackSummary>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT persona, model,
+                    COUNT(*) FILTER (WHERE rating > 0) AS up,
+                    COUNT(*) FILTER (WHERE rating < 0) AS down
+             FROM ai_feedback GROUP BY persona, model ORDER BY persona, model",
+            &[],
+        )
+        .await?;
+    Ok(rows.iter().map(FeedbackSummary::from_row).collect())
+}
+
+pub async fn record_ai_usage(pool: &Pool, record: &UsageRecord) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO ai_usage (feature, user_id, guild_id, model, prompt_tokens, completion_tokens, cost)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[&record.caller.feature.as_str(), &record.caller.user_id.map(uid), &record.caller.guild_id.map(uid),
+              &record.model, &record.prompt_tokens, &record.completion_tokens, &record.cost],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Tokens used since midnight UTC by `user_id` and by `guild_id`.
+pub async fn get_usage_today(pool: &Pool, user_id: u64, guild_id: Option<u64>) -> Result<(i64, i64)> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one(
+            "SELECT
+               COALESCE(SUM(prompt_tokens + completion_tokens) FILTER (WHERE user_id = $1), 0)::BIGINT,
+               COALESCE(SUM(prompt_tokens + completion_tokens) FILTER (WHERE guild_id = $2), 0)::BIGINT
+             FROM ai_usage
+             WHERE created_at >= date_trunc('day', now() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'",
+            &[&uid(user_id), &guild_id.map(uid)],
+        )
+        .await?;
+    Ok((row.get(0), row.get(1)))
+}
+
+/// What the usage dashboard can be grouped by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageGrouping {
+    Day,
+    Feature,
+    Model,
+    User,
+    Guild,
+}
+
+/// Usage of the last `days` days, grouped by `grouping`, largest cost (or newest day) first.
+pub async fn get_usage(pool: &Pool, grouping: UsageGrouping, days: i32) -> Result<Vec<UsageRow>> {
+    let (label, order) = match grouping {
+        UsageGrouping::Day => ("to_char(a.created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD')", "label DESC"),
+        UsageGrouping::Feature => ("a.feature", "cost DESC, tokens DESC"),
+        UsageGrouping::Model => ("a.model", "cost DESC, tokens DESC"),
+        UsageGrouping::User => (
+            "COALESCE(NULLIF(u.name, ''), a.user_id::TEXT, 'nobody')",
+            "cost DESC, tokens DESC",
+        ),
+        UsageGrouping::Guild => ("COALESCE(a.guild_id::TEXT, 'DMs and background')", "cost DESC, tokens DESC"),
+    };
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            &format!(
+                "SELECT {label} AS label, COUNT(*) AS requests,
+                        COALESCE(SUM(a.prompt_tokens + a.completion_tokens), 0)::BIGINT AS tokens,
+                        COALESCE(SUM(a.cost), 0) AS cost
+                 FROM ai_usage a LEFT JOIN \"user\" u ON u.id = a.user_id
+                 WHERE a.created_at >= now() - make_interval(days => $1)
+                 GROUP BY 1 ORDER BY {order} LIMIT 50"
+            ),
+            &[&days],
+        )
+        .await?;
+    Ok(rows.iter().map(UsageRow::from_row).collect())
+}
+
+/// Stores `trace` and drops traces older than `retention_days`, returning the id of the new trace.
+pub async fn save_ai_trace(pool: &Pool, trace: &AiTrace, retention_days: i32) -> Result<i64> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one(
+            "INSERT INTO ai_trace (user_id, channel_id, guild_id, persona, model, temperature, system_prompt,
+                                   user_message, response, error, latency_ms, rerun_of)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+             RETURNING id",
+            &[&trace.user_id, &trace.channel_id, &trace.guild_id, &trace.persona, &trace.model, &trace.temperature,
+              &trace.system_prompt, &trace.user_message, &trace.response, &trace.error, &trace.latency_ms,
+              &trace.rerun_of],
+        )
+        .await?;
+    client
+        .execute(
+            "DELETE FROM ai_trace WHERE created_at < now() - make_interval(days => $1)",
+            &[&retention_days],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+const TRACE_COLUMNS: &str = "id, user_id, channel_id, guild_id, persona, model, temperature, system_prompt,
+    user_message, response, error, latency_ms, rerun_of,
+    to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS') AS created_at";
+
+/// The newest traces whose prompt, reply, model or persona contain `search`, if given.
+pub async fn search_ai_traces(pool: &Pool, search: Option<&str>, limit: i64, offset: i64) -> Result<Vec<AiTrace>> {
+    let client = pool.get().await?;
+    let pattern = search.map(|search| {
+        format!("%{}%", search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+    });
+    let rows = client
+        .query(
+            &format!(
+                "SELECT {TRACE_COLUMNS} FROM ai_trace
+                 WHERE $1::TEXT IS NULL
+                    OR system_prompt ILIKE $1 OR user_message ILIKE $1 OR response ILIKE $1
+                    OR model ILIKE $1 OR persona ILIKE $1
+                 ORDER BY id DESC LIMIT $2 OFFSET $3"
+            ),
+            &[&pattern, &limit, &offset],
+        )
+        .await?;
+    Ok(rows.iter().map(AiTrace::from_row).collect())
+}
+
+pub async fn get_ai_trace(pool: &Pool, id: i64) -> Result<Option<AiTrace>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(&format!("SELECT {TRACE_COLUMNS} FROM ai_trace WHERE id = $1"), &[&id])
+        .await?;
+    Ok(rows.first().map(AiTrace::from_row))
+}
+
+pub async fn record_interjection(pool: &Pool, channel_id: u64, message_id: u64, verdict: &Verdict) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO ai_interjection (channel_id, message_id, interjected, reason) VALUES ($1, $2, $3, $4)",
+            &[&uid(channel_id), &uid(message_id), &verdict.interject, &verdict.reason],
+        )
+        .await?;
+    Ok(())
+}
+
+const SUMMARY_COLUMNS: &str = "channel_id, guild_id, summary, message_count,
+    to_char(updated_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS') AS updated_at";
+
+pub async fn get_channel_summary(pool: &Pool, channel_id: u64) -> Result<Option<ChannelSummary>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            &format!("SELECT {SUMMARY_COLUMNS} FROM channel_summary WHERE channel_id = $1"),
+            &[&uid(channel_id)],
+        )
+        .await?;
+    Ok(rows.first().map(ChannelSummary::from_row))
+}
+
+/// Replaces the summary of a channel, counting `new_messages` more messages into it.
+pub async fn save_channel_summary(
+    pool: &Pool,
+    channel_id: u64,
+    guild_id: Option<u64>,
+    summary: &str,
+    new_messages: i32,
+) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO channel_summary (channel_id, guild_id, summary, message_count)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (channel_id) DO UPDATE
+             SET summary = EXCLUDED.summary,
+                 message_count = channel_summary.message_count + EXCLUDED.message_count,
+                 updated_at = now()",
+            &[&uid(channel_id), &guild_id.map(uid), &summary, &new_messages],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn get_channel_summaries(pool: &Pool) -> Result<Vec<ChannelSummary>> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            &format!("SELECT {SUMMARY_COLUMNS} FROM channel_summary ORDER BY channel_summary.updated_at DESC"),
+            &[],
+        )
+        .await?;
+    Ok(rows.iter().map(ChannelSummary::from_row).collect())
+}
+
+pub async fn delete_channel_summary(pool: &Pool, channel_id: u64) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .execute("DELETE FROM channel_summary WHERE channel_id = $1", &[&uid(channel_id)])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::streaks;
+
+    #[test]
+    fn streaks_track_current_and_best_runs() {
+        assert_eq!(streaks([]), (0, 0));
+        assert_eq!(streaks([true, true, false, true]), (1, 2));
+        assert_eq!(streaks([false, true, true, true]), (3, 3));
+        assert_eq!(streaks([true, true, true, false]), (0, 3));
+    }
 }
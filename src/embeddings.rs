@@ -0,0 +1,150 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use futures::{future::BoxFuture, FutureExt};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+
+/// A text embedding backend.
+pub trait Embedder: Debug + Send + Sync {
+    /// The model the vectors come from. Vectors of different models are never compared.
+    fn model(&self) -> &str;
+
+    /// One vector per text, in order.
+    fn embed(&self, texts: Vec<String>) -> BoxFuture<'_, Result<Vec<Vec<f32>>>>;
+}
+
+/// The embedder picked by the config, `None` when no embedding model is configured. Uses the chat model's
+/// OpenAI-compatible server unless a separate one is given.
+pub fn embedder(config: &Config) -> Option<Arc<dyn Embedder>> {
+    let model = config.embedding_model.clone()?;
+    let Some(base_url) = config.embedding_base_url.as_ref().or(config.llm_base_url.as_ref()) else {
+        log::warn!("EMBEDDING_MODEL is set without EMBEDDING_BASE_URL or LLM_BASE_URL, memories are picked by words");
+        return None;
+    };
+    let api_key = config.embedding_api_key.clone().or_else(|| config.llm_api_key.clone());
+    Some(Arc::new(OpenAiEmbeddings::new(base_url, api_key, model)))
+}
+
+/// Any server speaking the OpenAI embeddings API, e.g. Ollama, the llama.cpp server or OpenAI itself.
+#[derive(Debug, Clone)]
+pub struct OpenAiEmbeddings {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl OpenAiEmbeddings {
+    pub fn new(base_url: &str, api_key: Option<String>, model: String) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .unwrap_or_default(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            api_key,
+            model,
+        }
+    }
+
+    async fn request(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let count = texts.len();
+        let mut http_request = self
+            .http
+            .post(format!("{}/embeddings", self.base_url))
+            .json(&json!({ "model": self.model, "input": texts }));
+        if let Some(api_key) = &self.api_key {
+            http_request = http_request.bearer_auth(api_key);
+        }
+        let response = http_request.send().await?.error_for_status()?.json::<Value>().await?;
+        parse_response(&response, count)
+    }
+}
+
+impl Embedder for OpenAiEmbeddings {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed(&self, texts: Vec<String>) -> BoxFuture<'_, Result<Vec<Vec<f32>>>> {
+        self.request(texts).boxed()
+    }
+}
+
+/// The vectors of an embeddings response, put back in the order of the input.
+fn parse_response(response: &Value, count: usize) -> Result<Vec<Vec<f32>>> {
+    let data = response
+        .get("data")
+        .and_then(Value::as_array)
+        .ok_or_else(|| eyre!("Embeddings response has no data: {}", response))?;
+    let mut vectors = vec![Vec::new(); count];
+    for (position, item) in data.iter().enumerate() {
+        let index = item
+            .get("index")
+            .and_then(Value::as_u64)
+            .map_or(position, |i| i as usize);
+        let vector = item
+            .get("embedding")
+            .and_then(Value::as_array)
+            .ok_or_else(|| eyre!("Embeddings response item has no embedding"))?
+            .iter()
+            .map(|value| value.as_f64().map(|value| value as f32))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| eyre!("Embedding contains a non-number"))?;
+        *vectors
+            .get_mut(index)
+            .ok_or_else(|| eyre!("Embeddings response has an unknown index {}", index))? = vector;
+    }
+    if vectors.iter().any(Vec::is_empty) {
+        return Err(eyre!(
+            "Embeddings response has {} vectors for {} texts",
+            data.len(),
+            count
+        ));
+    }
+    Ok(vectors)
+}
+
+/// Cosine similarity of two vectors, 0 when either is empty or they differ in length.
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_are_put_back_in_input_order() {
+        let response = json!({
+            "data": [
+                { "index": 1, "embedding": [0.0, 1.0] },
+                { "index": 0, "embedding": [1.0, 0.5] }
+            ]
+        });
+
+        assert_eq!(parse_response(&response, 2).unwrap(), [vec![1.0, 0.5], vec![0.0, 1.0]]);
+        assert!(parse_response(&response, 3).is_err());
+        assert!(parse_response(&json!({ "error": "no model" }), 1).is_err());
+    }
+
+    #[test]
+    fn cosine_compares_directions() {
+        assert!((cosine(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert_eq!(cosine(&[1.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use rand::{seq::IndexedRandom, Rng};
+use serde::Deserialize;
+
+use crate::{
+    quiz::{normalize_answer, Grade, Quiz, QuizContext, QuizKind, QUIZ_DATA},
+    quiz_difficulty::Difficulty,
+    structs::Command,
+};
+
+/// A riddle as listed in `quiz_data.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RiddleEntry {
+    pub emojis: String,
+    pub answer: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub difficulty: Difficulty,
+}
+
+#[derive(Debug, Clone)]
+pub struct EmojiRiddle {
+    pub riddle: RiddleEntry,
+}
+
+impl EmojiRiddle {
+    pub fn random(rng: &mut impl Rng, difficulty: Difficulty) -> Result<Self> {
+        let riddles = QUIZ_DATA
+            .emoji_riddles
+            .iter()
+            .filter(|riddle| riddle.difficulty == difficulty)
+            .collect::<Vec<_>>();
+        let riddle = riddles
+            .choose(rng)
+            .ok_or_else(|| eyre!("No emoji riddles for {} difficulty", difficulty.as_str()))?;
+
+        Ok(Self {
+            riddle: (*riddle).clone(),
+        })
+    }
+}
+
+/// Normalises an answer and drops a leading article, so "The Matrix" and "matrix" match.
+fn comparable(answer: &str) -> String {
+    let answer = normalize_answer(answer);
+    match answer.strip_prefix("the ").or_else(|| answer.strip_prefix("a ")) {
+        Some(rest) => rest.to_string(),
+        None => answer,
+    }
+}
+
+impl Quiz for EmojiRiddle {
+    async fn generate(ctx: QuizContext<'_>) -> Result<Self> {
+        Self::random(ctx.rng, ctx.difficulty)
+    }
+
+    fn kind(&self) -> QuizKind {
+        QuizKind::EmojiRiddle
+    }
+
+    fn difficulty(&self) -> Difficulty {
+        self.riddle.difficulty
+    }
+
+    fn time_limit(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn render(&self, user_id: u64) -> Result<Command> {
+        Ok(Command::text(format!(
+            "<@{}> **EMOJI RIDDLE!** ({}) What movie, song or saying is this? You have {} seconds:\n# {}",
+            user_id,
+            self.riddle.difficulty.as_str(),
+            self.time_limit().as_secs(),
+            self.riddle.emojis
+        )))
+    }
+
+    fn validate(&self, answer: &str, _elapsed: Duration) -> Option<Grade> {
+        let answer = comparable(answer);
+        std::iter::once(&self.riddle.answer)
+            .chain(&self.riddle.aliases)
+            .any(|expected| comparable(expected) == answer)
+            .then(|| Grade::full(format!("Correct! It was **{}**.", self.riddle.answer)))
+    }
+
+    fn question(&self) -> String {
+        self.riddle.emojis.clone()
+    }
+
+    fn answer(&self) -> String {
+        self.riddle.answer.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_match_loosely() {
+        let riddle = EmojiRiddle {
+            riddle: RiddleEntry {
+                emojis: "🕶️💊🐇".to_string(),
+                answer: "The Matrix".to_string(),
+                aliases: vec![],
+                difficulty: Difficulty::Hard,
+            },
+        };
+
+        assert!(riddle.validate("matrix", Duration::ZERO).is_some());
+        assert!(riddle.validate("THE MATRIX!", Duration::ZERO).is_some());
+        assert!(riddle.validate("the matrix reloaded", Duration::ZERO).is_none());
+    }
+}
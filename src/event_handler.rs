@@ -1,4 +1,10 @@
-use crate::{ai_message, memory_creator, message_handler::handle_message, structs::*};
+use crate::{
+    ai_context,
+    ai_controls::{self, ActiveReply, ReplyControls},
+    ai_fallback, ai_message, ai_thread, ai_usage, channel_summary, db, memory_creator,
+    message_handler::handle_message,
+    structs::*,
+};
 
 use rand::Rng;
 use tokio::{join, sync::Mutex};
@@ -13,38 +19,40 @@ use std::{collections::HashMap, sync::Arc, time::Duration};
 async fn handle_ai_message(
     state: &Arc<Mutex<State>>,
     http: &Arc<HttpClient>,
-    user_id: u64,
-    channel_id: u64,
+    incoming: ai_message::Incoming,
     message_id: u64,
-    name: String,
-    content: String,
-    context: String,
-    user_mentions: HashMap<String, u64>,
     should_create_memory: bool,
     tracking_id: u64, // Either channel_id or user_id for DMs
 ) -> color_eyre::Result<()> {
     let locked_state = state.lock().await;
-    let user_mentions_clone = user_mentions.clone();
+    let services = locked_state.ai_services();
+    let ai_replies = locked_state.ai_replies.clone();
+    // Finding memories and starting the model take a while, the state isn't held meanwhile
+    drop(locked_state);
+    let (user_id, channel_id) = (incoming.user_id, incoming.channel_id);
+    let context = incoming.context.clone();
+    let user_mentions_clone = incoming.user_mentions.clone();
 
-    match ai_message::main(
-        locked_state.db.clone(),
-        user_id,
-        &format!("{}: {}", name, &content[..std::cmp::min(content.len(), 2400)]),
-        &context,
-        locked_state.brave_api.clone(),
-        user_mentions,
-        locked_state.config.clone(),
-    )
-    .await
-    {
-        Ok(stream_rx) => {
-            drop(locked_state); // Release lock before spawning tasks
+    match ai_message::main(services.clone(), incoming).await {
+        Ok(generation) => {
+            let (stream_rx, abort) = generation.start();
+            let controls = ReplyControls::new(
+                ai_replies,
+                ActiveReply {
+                    generation,
+                    abort: Some(abort),
+                    requester: user_id,
+                    reply_to: Some(Id::new(message_id)),
+                    content: String::new(),
+                },
+            );
 
             tokio::spawn(crate::message_handler::handle_streaming_response(
                 stream_rx,
                 Id::new(channel_id),
-                Id::new(message_id),
+                Some(Id::new(message_id)),
                 Arc::clone(http),
+                Some(controls),
             ));
 
             // Spawn background task to create memories if we've reached the threshold
@@ -52,10 +60,12 @@ async fn handle_ai_message(
                 let state_clone = Arc::clone(state);
                 tokio::spawn(async move {
                     memory_creator::create_memories_background(
-                        state_clone.lock().await.db.clone(),
+                        services.database,
                         context,
                         user_mentions_clone,
-                        state_clone.lock().await.config.clone(),
+                        None,
+                        services.llm,
+                        services.config,
                     )
                     .await;
 
@@ -66,10 +76,9 @@ async fn handle_ai_message(
             Ok(())
         }
         Err(e) => {
-            drop(locked_state); // Release lock before making HTTP call
             tracing::error!("AI Error: {:?}", e);
             http.create_message(Id::new(channel_id))
-                .content(&format!("AI Error: {:?}", e))?
+                .content(ai_fallback::user_message(&e))?
                 .reply(Id::new(message_id))
                 .exec()
                 .await?;
@@ -86,6 +95,16 @@ pub async fn handle_event(
 ) -> color_eyre::Result<()> {
     let mut locked_state = state.lock().await;
     match event {
+        Event::InteractionCreate(i) if ai_controls::is_reply_button(&i.0) => {
+            let http = Arc::clone(http);
+            let database = locked_state.db.clone();
+            let replies = locked_state.ai_replies.clone();
+            tokio::spawn(async move {
+                if let Err(e) = ai_controls::handle_button(http, database, replies, i.0).await {
+                    tracing::error!("Failed to handle AI reply button: {:?}", e);
+                }
+            });
+        }
         Event::InteractionCreate(i) => {
             tracing::info!("Slash Command!");
             tokio::spawn(async move {
@@ -136,7 +155,7 @@ pub async fn handle_event(
                 let should_create_memory = *count >= 15;
 
                 // Handle the DM message with AI
-                if locked_state.config.openrouter_api_key.is_some() {
+                if locked_state.llm.is_some() {
                     let name = msg.author.name.clone();
                     let content = msg.content.clone();
                     let user_id = msg.author.id.get();
@@ -144,56 +163,49 @@ pub async fn handle_event(
                     let message_id = msg.id.get();
                     let bot_id = locked_state.config.id;
 
-                    // Build context from recent DM messages (up to 15 messages)
-                    let mut context = String::new();
-                    match locked_state.cache.channel_messages(msg.channel_id) {
-                        Some(v) => {
-                            let msgs = v
-                                .iter()
-                                .take(15)
-                                .filter_map(|m| {
-                                    let message = locked_state.cache.message(m.to_owned());
-                                    message.map(|msg| {
-                                        let content = msg.content();
-                                        let ai_content = content[..std::cmp::min(content.len(), 2400)]
-                                            .replace(&bot_id.to_string(), "The Trickster");
-                                        match msg.author().get() {
-                                            id if id == bot_id => format!("The Trickster: {ai_content}"),
-                                            _ => {
-                                                let username = locked_state
-                                                    .cache
-                                                    .user(msg.author())
-                                                    .map(|c| c.name.clone())
-                                                    .unwrap_or_default();
-                                                format!("{}: {}", username, ai_content)
-                                            }
-                                        }
-                                    })
-                                })
-                                .rev()
-                                .collect::<Vec<String>>();
-                            context.push_str(&msgs.join("\n"));
-                        }
-                        None => {
-                            // Fallback to just current message if cache is empty
-                            context.push_str(&format!("{}: {}", name, &content[..std::cmp::min(content.len(), 2400)]));
-                        }
-                    }
+                    // Build context from recent DM messages
+                    let cached = ai_context::cached_messages(&locked_state.cache, msg.channel_id, bot_id, user_id);
+                    let context = if cached.is_empty() {
+                        // Fallback to just current message if cache is empty
+                        format!("{}: {}", name, ai_context::truncate(&content, ai_context::MAX_MESSAGE_BYTES))
+                    } else {
+                        ai_context::build_context(&cached, message_id, locked_state.config.context_token_budget)
+                    };
 
                     let user_mentions = HashMap::new();
+                    let images = ai_message::collect_images(&locked_state.cache, &msg);
 
+                    let database = locked_state.db.clone();
+                    let config = locked_state.config.clone();
                     drop(locked_state); // Release lock before calling helper
 
-                    handle_ai_message(
-                        state,
-                        http,
+                    if let Some(refusal) = ai_usage::check_quota(&database, &config, user_id, None).await {
+                        http.create_message(msg.channel_id)
+                            .content(&refusal)?
+                            .reply(msg.id)
+                            .exec()
+                            .await?;
+                        return Ok(());
+                    }
+
+                    let incoming = ai_message::Incoming {
                         user_id,
                         channel_id,
-                        message_id,
-                        name,
-                        content,
+                        guild_id: None,
+                        message: format!(
+                            "{}: {}",
+                            name,
+                            ai_context::truncate(&content, ai_context::MAX_MESSAGE_BYTES)
+                        ),
                         context,
+                        images,
                         user_mentions,
+                    };
+                    handle_ai_message(
+                        state,
+                        http,
+                        incoming,
+                        message_id,
                         should_create_memory,
                         user_id, // Use user_id as tracking_id for DMs
                     )
@@ -214,6 +226,32 @@ pub async fn handle_event(
                 .or_insert(0);
             *count += 1;
 
+            if let Some(llm) = locked_state.llm.clone().filter(|_| !msg.author.bot) {
+                if let Some(new_messages) = channel_summary::count_message(&mut locked_state, msg.channel_id.get()) {
+                    let bot_id = locked_state.config.id;
+                    let cached = ai_context::cached_messages(&locked_state.cache, msg.channel_id, bot_id, 0)
+                        .into_iter()
+                        .filter(|message| !channel_summary::from_bot(&locked_state.cache, message.id))
+                        .collect::<Vec<_>>();
+                    tokio::spawn(channel_summary::refresh(
+                        state.clone(),
+                        locked_state.db.clone(),
+                        llm,
+                        locked_state.config.clone(),
+                        msg.channel_id.get(),
+                        msg.guild_id.map(Id::get),
+                        channel_summary::transcript(&cached, new_messages as usize),
+                        new_messages,
+                    ));
+                }
+            }
+
+            if locked_state.ai_threads.contains_key(&msg.channel_id.get()) {
+                if let Err(e) = ai_thread::store_message(&locked_state.db, msg.channel_id.get(), &msg).await {
+                    tracing::warn!("Failed to store AI thread message: {:?}", e);
+                }
+            }
+
             if let Some(today_i) = locked_state.config.today_i_channel {
                 if msg.channel_id == Id::new(today_i) && !msg.content.clone().to_lowercase().starts_with("today i") {
                     http.delete_message(msg.channel_id, msg.id).exec().await?;
@@ -234,7 +272,7 @@ pub async fn handle_event(
                 }
             };
 
-            let r = handle_message(&msg, locked_state, http).await;
+            let r = handle_message(&msg, locked_state, state, http).await;
             match r {
                 Ok(res) => {
                     let Command {
@@ -286,6 +324,9 @@ pub async fn handle_event(
                 }
             }
         }
+        Event::ThreadDelete(thread) if locked_state.ai_threads.remove(&thread.id.get()).is_some() => {
+            db::delete_ai_thread(&locked_state.db, thread.id.get()).await?;
+        }
         Event::Ready(_) => {
             tracing::info!("Connected");
         }
@@ -0,0 +1,234 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use deadpool_postgres::Pool;
+use rand::Rng;
+use serde::Deserialize;
+use tokio::sync::MutexGuard;
+
+use crate::{
+    ai_usage::Caller,
+    config::Config,
+    database::ChannelSettings,
+    db,
+    llm::{ChatMessage, ChatRequest, LlmProvider},
+    structs::State,
+};
+
+/// Messages shorter than this never get an unprompted reply
+const MIN_WORDS: usize = 4;
+/// Words that make a question one for the whole channel rather than for someone in particular
+const OPEN_QUESTION_WORDS: [&str; 6] = ["anyone", "anybody", "someone", "somebody", "everyone", "y'all"];
+
+const CLASSIFIER_PROMPT: &str = "You decide whether a witty Discord bot should join a conversation it was not asked \
+to join. Say yes only when the bot can add a fitting joke, fact or correction to the last message. Say no for \
+private, sensitive or mundane chatter, and when unsure. Respond only with JSON: \
+{\"interject\": true or false, \"reason\": \"a few words\"}";
+
+fn utc_hour() -> u8 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    ((seconds / 3600) % 24) as u8
+}
+
+/// Whether `hour` (UTC) falls in the channel's quiet hours. The range may wrap around midnight.
+fn is_quiet(settings: &ChannelSettings, hour: u8) -> bool {
+    let (Some(start), Some(end)) = (settings.quiet_hours_start, settings.quiet_hours_end) else {
+        return false;
+    };
+    let hour = hour as i16;
+    match start <= end {
+        true => start <= hour && hour < end,
+        false => hour >= start || hour < end,
+    }
+}
+
+/// Whether the channel's settings let the bot interject now, before rolling its chance.
+fn allowed(settings: &ChannelSettings, since_last: Option<Duration>, hour: u8) -> bool {
+    let cooled_down = since_last.is_none_or(|elapsed| elapsed.as_secs() >= settings.interjection_cooldown as u64);
+    settings.interjection_chance > 0 && cooled_down && !is_quiet(settings, hour)
+}
+
+/// Rolls the channel's interjection chance, outside its cooldown and quiet hours.
+pub fn roll(locked_state: &mut MutexGuard<'_, State>, channel_id: u64) -> bool {
+    let settings = locked_state.channel_settings(channel_id);
+    let since_last = locked_state.interjections.get(&channel_id).map(Instant::elapsed);
+    allowed(&settings, since_last, utc_hour()) && locked_state.rng.gen_range(0..settings.interjection_chance) == 0
+}
+
+/// Parses quiet hours like `23-7` (UTC), or `off` for none.
+pub fn parse_quiet_hours(text: &str) -> Result<Option<(i16, i16)>, String> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    let invalid = || format!("`{}` is not a range of hours like `23-7` or `off`", text);
+    let (start, end) = text.split_once('-').ok_or_else(invalid)?;
+    let hour = |value: &str| value.trim().parse::<i16>().ok().filter(|hour| (0..24).contains(hour));
+    match (hour(start), hour(end)) {
+        (Some(start), Some(end)) => Ok(Some((start, end))),
+        _ => Err(invalid()),
+    }
+}
+
+/// Whether the bot has something to add, and why.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Verdict {
+    pub interject: bool,
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl Verdict {
+    fn new(interject: bool, reason: impl Into<String>) -> Self {
+        Self {
+            interject,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Decides the clear cases from the message alone: too short to bother, or a configured keyword or a question put
+/// to the whole channel. Questions to someone in particular are left to the classifier.
+fn keyword_verdict(message: &str, keywords: &[String]) -> Option<Verdict> {
+    if message.split_whitespace().count() < MIN_WORDS {
+        return Some(Verdict::new(false, "too short"));
+    }
+    let lowercase = message.to_lowercase();
+    if let Some(keyword) = keywords
+        .iter()
+        .map(|keyword| keyword.trim().to_lowercase())
+        .find(|keyword| !keyword.is_empty() && lowercase.contains(keyword.as_str()))
+    {
+        return Some(Verdict::new(true, format!("mentions {}", keyword)));
+    }
+    let open_question = message.trim_end().ends_with('?')
+        && !message.contains("<@")
+        && lowercase
+            .split(|c: char| !c.is_alphanumeric() && c != '\'')
+            .any(|word| OPEN_QUESTION_WORDS.contains(&word));
+    open_question.then(|| Verdict::new(true, "asks the channel"))
+}
+
+/// Checks whether the bot has something to add to `message`, with the configured classifier model for the cases
+/// keywords don't decide. Without a classifier those are left alone.
+pub async fn check_relevance(
+    llm: Option<Arc<dyn LlmProvider>>,
+    config: &Config,
+    recent: &str,
+    message: &str,
+    caller: Caller,
+) -> Verdict {
+    if let Some(verdict) = keyword_verdict(message, &config.interjection_keywords) {
+        return verdict;
+    }
+    let (Some(llm), Some(model)) = (llm, &config.interjection_model) else {
+        return Verdict::new(false, "no keyword");
+    };
+
+    let request = ChatRequest {
+        model: model.clone(),
+        messages: vec![
+            ChatMessage::system(CLASSIFIER_PROMPT),
+            ChatMessage::user(format!(
+                "<transcript>\n{}\n</transcript>\n\nLast message: {}",
+                recent, message
+            )),
+        ],
+        temperature: Some(0.0),
+        max_tokens: Some(100),
+        caller,
+        ..Default::default()
+    };
+    match llm.chat_json(request).await.map(serde_json::from_value::<Verdict>) {
+        Ok(Ok(verdict)) => verdict,
+        Ok(Err(e)) => Verdict::new(false, format!("classifier answered invalid JSON: {}", e)),
+        Err(e) => {
+            log::warn!("Interjection classifier failed: {:?}", e);
+            Verdict::new(false, "classifier failed")
+        }
+    }
+}
+
+/// Logs the verdict on a message the bot could have interjected on, for tuning the rates and keywords.
+pub fn record(database: &Pool, channel_id: u64, message_id: u64, verdict: &Verdict) {
+    log::info!(
+        "Interjection in {} on {}: {} ({})",
+        channel_id,
+        message_id,
+        verdict.interject,
+        verdict.reason
+    );
+    let database = database.clone();
+    let verdict = verdict.clone();
+    tokio::spawn(async move {
+        if let Err(e) = db::record_interjection(&database, channel_id, message_id, &verdict).await {
+            log::warn!("Failed to record interjection: {:?}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_hours_and_cooldowns_hold_interjections_back() {
+        let settings = ChannelSettings {
+            quiet_hours_start: Some(23),
+            quiet_hours_end: Some(7),
+            interjection_cooldown: 600,
+            ..ChannelSettings::new(1)
+        };
+
+        assert!(allowed(&settings, None, 12));
+        assert!(!allowed(&settings, None, 23));
+        assert!(!allowed(&settings, None, 3));
+        assert!(allowed(&settings, None, 7));
+        assert!(!allowed(&settings, Some(Duration::from_secs(599)), 12));
+        assert!(allowed(&settings, Some(Duration::from_secs(600)), 12));
+        assert!(!allowed(
+            &ChannelSettings {
+                interjection_chance: 0,
+                ..settings
+            },
+            None,
+            12
+        ));
+    }
+
+    #[test]
+    fn quiet_hours_parse() {
+        assert_eq!(parse_quiet_hours("23-7"), Ok(Some((23, 7))));
+        assert_eq!(parse_quiet_hours(" OFF "), Ok(None));
+        assert!(parse_quiet_hours("24-7").is_err());
+        assert!(parse_quiet_hours("night").is_err());
+    }
+
+    #[test]
+    fn keywords_and_questions_decide_without_a_model() {
+        let keywords = vec!["rust".to_string()];
+
+        assert!(!keyword_verdict("lol same", &keywords).unwrap().interject);
+        assert_eq!(
+            keyword_verdict("I finally rewrote it in Rust", &keywords),
+            Some(Verdict::new(true, "mentions rust"))
+        );
+        assert!(
+            keyword_verdict("does anyone know a good pizza place?", &keywords)
+                .unwrap()
+                .interject
+        );
+        assert_eq!(keyword_verdict("going to the shops later today", &keywords), None);
+        // Questions to someone in particular are the classifier's call
+        assert_eq!(keyword_verdict("are you coming to dinner tonight?", &keywords), None);
+        assert_eq!(
+            keyword_verdict("<@123> does anyone else have the notes?", &keywords),
+            None
+        );
+    }
+}
@@ -0,0 +1,635 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use futures::{
+    channel::mpsc,
+    future::BoxFuture,
+    stream::{BoxStream, StreamExt},
+    FutureExt,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{ai_fallback::EmptyReply, ai_usage::Caller, config::Config};
+
+/// One message of a chat request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    /// Image URLs shown after the text, for vision models
+    pub images: Vec<String>,
+    /// Functions the assistant called, on assistant messages
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a `tool` message holds the result of
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self {
+            role: "system".to_string(),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: "user".to_string(),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    /// An assistant turn that called `tool_calls`, to be followed by their results.
+    pub fn assistant(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            role: "assistant".to_string(),
+            content: content.into(),
+            tool_calls,
+            ..Default::default()
+        }
+    }
+
+    /// The result of the call `tool_call_id`.
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: "tool".to_string(),
+            content: content.into(),
+            tool_call_id: Some(tool_call_id.into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A function call requested by the model.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments
+    #[serde(default)]
+    pub arguments: String,
+}
+
+/// Whether the model may call the tools of a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ToolChoice {
+    #[default]
+    Auto,
+    /// The tools are still sent, since earlier turns called them, but the model has to answer
+    Never,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// Function definitions the model may call instead of answering
+    pub tools: Vec<Value>,
+    pub tool_choice: ToolChoice,
+    /// Ask for a JSON object
+    pub json: bool,
+    /// Who the request is made for, for usage accounting
+    pub caller: Caller,
+}
+
+/// A whole reply.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Completion {
+    pub content: String,
+    /// Calls the model wants to see the results of before it answers; `content` is often empty then
+    pub tool_calls: Vec<ToolCall>,
+    /// The model that answered, as reported by the provider
+    pub model: String,
+    /// What the request used, when the provider reports it
+    pub usage: Option<Usage>,
+}
+
+/// Tokens used by a request, as counted by the provider.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
+    /// In USD, only reported by OpenRouter
+    #[serde(default)]
+    pub cost: Option<f64>,
+}
+
+impl Completion {
+    pub fn is_empty(&self) -> bool {
+        self.content.trim().is_empty() && self.tool_calls.is_empty()
+    }
+}
+
+/// What a streamed reply yields.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Text as it is generated
+    Delta(String),
+    /// The whole reply, once the stream ends
+    Done(Completion),
+}
+
+/// A chat model backend.
+pub trait LlmProvider: Debug + Send + Sync {
+    /// The whole reply at once.
+    fn complete(&self, request: ChatRequest) -> BoxFuture<'_, Result<Completion>>;
+
+    /// The reply as it is generated, ending with [`StreamEvent::Done`].
+    fn stream(&self, request: ChatRequest) -> BoxStream<'static, Result<StreamEvent>>;
+
+    /// The text of the reply.
+    fn chat(&self, request: ChatRequest) -> BoxFuture<'_, Result<String>> {
+        async move { Ok(self.complete(request).await?.content) }.boxed()
+    }
+
+    /// Asks for a JSON object and parses it.
+    fn chat_json(&self, request: ChatRequest) -> BoxFuture<'_, Result<Value>> {
+        async move { parse_json_reply(&self.chat(ChatRequest { json: true, ..request }).await?) }.boxed()
+    }
+}
+
+/// The provider picked by the config: an OpenAI-compatible server when `llm_base_url` is set, OpenRouter
+/// otherwise. `None` when neither is configured.
+pub fn provider(config: &Config) -> Option<Arc<dyn LlmProvider>> {
+    if let Some(base_url) = &config.llm_base_url {
+        return Some(Arc::new(OpenAiCompatible::new(base_url, config.llm_api_key.clone())));
+    }
+    let api_key = config.openrouter_api_key.clone()?;
+    Some(Arc::new(OpenRouter::new(
+        api_key,
+        &config.openrouter_base_url,
+        config.openrouter_site_url.clone(),
+        config.openrouter_site_name.clone(),
+    )))
+}
+
+fn parse_json_reply(text: &str) -> Result<Value> {
+    // Some models wrap JSON mode output in a code block or a sentence anyway
+    let start = text.find('{').unwrap_or(0);
+    let end = text.rfind('}').map(|i| i + 1).unwrap_or(text.len());
+    serde_json::from_str(&text[start..end.max(start)])
+        .map_err(|e| eyre!("Model returned invalid JSON: {} - Raw: {}", e, text))
+}
+
+fn message_json(message: &ChatMessage) -> Value {
+    let content = if message.images.is_empty() {
+        json!(message.content)
+    } else {
+        let mut parts = vec![json!({ "type": "text", "text": message.content })];
+        parts.extend(
+            message
+                .images
+                .iter()
+                .map(|url| json!({ "type": "image_url", "image_url": { "url": url } })),
+        );
+        json!(parts)
+    };
+
+    let mut value = json!({ "role": message.role, "content": content });
+    if !message.tool_calls.is_empty() {
+        let calls = message
+            .tool_calls
+            .iter()
+            .map(|call| {
+                json!({
+                    "id": call.id,
+                    "type": "function",
+                    "function": { "name": call.function.name, "arguments": call.function.arguments }
+                })
+            })
+            .collect::<Vec<_>>();
+        value["tool_calls"] = json!(calls);
+    }
+    if let Some(id) = &message.tool_call_id {
+        value["tool_call_id"] = json!(id);
+    }
+    value
+}
+
+/// The reply of a chat completions response.
+fn parse_completion(response: &Value, model: &str) -> Result<Completion> {
+    let message = response.pointer("/choices/0/message").ok_or(EmptyReply)?;
+    Ok(Completion {
+        content: message["content"].as_str().unwrap_or_default().to_string(),
+        tool_calls: serde_json::from_value(message["tool_calls"].clone()).unwrap_or_default(),
+        model: response["model"].as_str().unwrap_or(model).to_string(),
+        usage: serde_json::from_value(response["usage"].clone()).ok(),
+    })
+}
+
+/// Adds the fragments of streamed tool calls to `calls`. Fragments say which call they belong to by index; the
+/// first one of a call carries its id and name, the rest pieces of the arguments.
+fn merge_tool_call_deltas(calls: &mut Vec<ToolCall>, deltas: &[Value]) {
+    for delta in deltas {
+        let index = delta["index"].as_u64().unwrap_or(0) as usize;
+        if index == calls.len() {
+            calls.push(ToolCall::default());
+        }
+        let Some(call) = calls.get_mut(index) else {
+            continue;
+        };
+        if let Some(id) = delta["id"].as_str() {
+            call.id = id.to_string();
+        }
+        if let Some(name) = delta.pointer("/function/name").and_then(Value::as_str) {
+            call.function.name.push_str(name);
+        }
+        if let Some(arguments) = delta.pointer("/function/arguments").and_then(Value::as_str) {
+            call.function.arguments.push_str(arguments);
+        }
+    }
+}
+
+/// Any server speaking the OpenAI chat completions API, e.g. Ollama or the llama.cpp server.
+#[derive(Debug, Clone)]
+pub struct OpenAiCompatible {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    /// Extra headers sent with every request
+    headers: Vec<(&'static str, String)>,
+}
+
+impl OpenAiCompatible {
+    pub fn new(base_url: &str, api_key: Option<String>) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(120))
+                .build()
+                .unwrap_or_default(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            api_key,
+            headers: Vec::new(),
+        }
+    }
+
+    fn body(request: &ChatRequest, stream: bool) -> Value {
+        let mut body = json!({
+            "model": request.model,
+            "messages": request.messages.iter().map(message_json).collect::<Vec<_>>(),
+            "stream": stream,
+        });
+        if stream {
+            // The usage comes in a last chunk without choices
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+        if let Some(temperature) = request.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if !request.tools.is_empty() {
+            body["tools"] = json!(request.tools);
+            body["tool_choice"] = json!(match request.tool_choice {
+                ToolChoice::Auto => "auto",
+                ToolChoice::Never => "none",
+            });
+        }
+        if request.json {
+            body["response_format"] = json!({ "type": "json_object" });
+        }
+        body
+    }
+
+    async fn send(&self, body: &Value) -> Result<reqwest::Response> {
+        let mut http_request = self.http.post(format!("{}/chat/completions", self.base_url)).json(body);
+        if let Some(api_key) = &self.api_key {
+            http_request = http_request.bearer_auth(api_key);
+        }
+        for (name, value) in &self.headers {
+            http_request = http_request.header(*name, value);
+        }
+        Ok(http_request.send().await?.error_for_status()?)
+    }
+
+    async fn complete_body(&self, body: Value, model: String) -> Result<Completion> {
+        let response = self.send(&body).await?.json::<Value>().await?;
+        parse_completion(&response, &model)
+    }
+
+    fn stream_body(&self, body: Value, model: String) -> BoxStream<'static, Result<StreamEvent>> {
+        let (tx, rx) = mpsc::unbounded();
+        let provider = self.clone();
+        tokio::spawn(async move {
+            let mut response = match provider.send(&body).await {
+                Ok(response) => response,
+                Err(e) => {
+                    let _ = tx.unbounded_send(Err(e));
+                    return;
+                }
+            };
+
+            // Server-sent events, one `data: {json}` line per delta
+            let mut completion = Completion {
+                model,
+                ..Default::default()
+            };
+            let mut buffer = Vec::new();
+            'events: loop {
+                let chunk = match response.chunk().await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.unbounded_send(Err(e.into()));
+                        return;
+                    }
+                };
+                buffer.extend_from_slice(&chunk);
+                while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line = buffer.drain(..=newline).collect::<Vec<_>>();
+                    let line = String::from_utf8_lossy(&line);
+                    let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+                        continue;
+                    };
+                    if data == "[DONE]" {
+                        break 'events;
+                    }
+                    let Ok(event) = serde_json::from_str::<Value>(data) else {
+                        continue;
+                    };
+                    if let Some(error) = event.get("error") {
+                        let _ = tx.unbounded_send(Err(eyre!("Stream failed: {}", error)));
+                        return;
+                    }
+                    if let Some(model) = event["model"].as_str() {
+                        completion.model = model.to_string();
+                    }
+                    if let Ok(usage) = serde_json::from_value(event["usage"].clone()) {
+                        completion.usage = Some(usage);
+                    }
+                    let delta = &event["choices"][0]["delta"];
+                    if let Some(calls) = delta["tool_calls"].as_array() {
+                        merge_tool_call_deltas(&mut completion.tool_calls, calls);
+                    }
+                    if let Some(text) = delta["content"].as_str().filter(|text| !text.is_empty()) {
+                        completion.content.push_str(text);
+                        if tx.unbounded_send(Ok(StreamEvent::Delta(text.to_string()))).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            let _ = tx.unbounded_send(Ok(StreamEvent::Done(completion)));
+        });
+        rx.boxed()
+    }
+}
+
+impl LlmProvider for OpenAiCompatible {
+    fn complete(&self, request: ChatRequest) -> BoxFuture<'_, Result<Completion>> {
+        self.complete_body(Self::body(&request, false), request.model).boxed()
+    }
+
+    fn stream(&self, request: ChatRequest) -> BoxStream<'static, Result<StreamEvent>> {
+        self.stream_body(Self::body(&request, true), request.model)
+    }
+}
+
+/// OpenRouter, which speaks the OpenAI API with a few extensions.
+#[derive(Debug, Clone)]
+pub struct OpenRouter {
+    raw: OpenAiCompatible,
+}
+
+impl OpenRouter {
+    pub fn new(api_key: String, base_url: &str, site_url: Option<String>, site_name: Option<String>) -> Self {
+        let mut raw = OpenAiCompatible::new(base_url, Some(api_key));
+        if let Some(url) = site_url {
+            raw.headers.push(("HTTP-Referer", url));
+        }
+        if let Some(name) = site_name {
+            raw.headers.push(("X-Title", name));
+        }
+        Self { raw }
+    }
+
+    fn body(request: &ChatRequest, stream: bool) -> Value {
+        let mut body = OpenAiCompatible::body(request, stream);
+        // Reports the cost along with the tokens
+        body["usage"] = json!({ "include": true });
+        if request.json {
+            // Extraction needs deterministic JSON, not long reasoning
+            body["reasoning"] = json!({ "effort": "none" });
+        }
+        body
+    }
+}
+
+impl LlmProvider for OpenRouter {
+    fn complete(&self, request: ChatRequest) -> BoxFuture<'_, Result<Completion>> {
+        self.raw
+            .complete_body(Self::body(&request, false), request.model)
+            .boxed()
+    }
+
+    fn stream(&self, request: ChatRequest) -> BoxStream<'static, Result<StreamEvent>> {
+        self.raw.stream_body(Self::body(&request, true), request.model)
+    }
+}
+
+/// Replies with scripted answers in order and records every request, so call sites can be tested offline.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct ScriptedLlm {
+    replies: std::sync::Mutex<std::collections::VecDeque<Completion>>,
+    pub requests: std::sync::Mutex<Vec<ChatRequest>>,
+}
+
+#[cfg(test)]
+impl ScriptedLlm {
+    pub fn new<S: Into<String>>(replies: impl IntoIterator<Item = S>) -> Self {
+        Self::with_completions(replies.into_iter().map(|reply| Completion {
+            content: reply.into(),
+            ..Default::default()
+        }))
+    }
+
+    /// Replies that may call tools.
+    pub fn with_completions(completions: impl IntoIterator<Item = Completion>) -> Self {
+        Self {
+            replies: std::sync::Mutex::new(completions.into_iter().collect()),
+            requests: Default::default(),
+        }
+    }
+
+    fn next_reply(&self, request: ChatRequest) -> Result<Completion> {
+        let model = request.model.clone();
+        self.requests.lock().unwrap().push(request);
+        let mut completion = self
+            .replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| eyre!("Script ran out of replies"))?;
+        if completion.model.is_empty() {
+            completion.model = model;
+        }
+        Ok(completion)
+    }
+}
+
+#[cfg(test)]
+impl LlmProvider for ScriptedLlm {
+    fn complete(&self, request: ChatRequest) -> BoxFuture<'_, Result<Completion>> {
+        futures::future::ready(self.next_reply(request)).boxed()
+    }
+
+    /// Streams the reply a few characters at a time.
+    fn stream(&self, request: ChatRequest) -> BoxStream<'static, Result<StreamEvent>> {
+        match self.next_reply(request) {
+            Ok(completion) => {
+                let mut events = completion
+                    .content
+                    .chars()
+                    .collect::<Vec<_>>()
+                    .chunks(4)
+                    .map(|chunk| Ok(StreamEvent::Delta(chunk.iter().collect())))
+                    .collect::<Vec<_>>();
+                events.push(Ok(StreamEvent::Done(completion)));
+                futures::stream::iter(events).boxed()
+            }
+            Err(e) => futures::stream::once(async move { Err(e) }).boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+
+    #[test]
+    fn json_replies_may_be_fenced() {
+        assert_eq!(parse_json_reply("```json\n{\"a\": 1}\n```").unwrap(), json!({ "a": 1 }));
+        assert_eq!(parse_json_reply("Sure! {\"a\": 1}").unwrap(), json!({ "a": 1 }));
+        assert!(parse_json_reply("not json").is_err());
+    }
+
+    #[test]
+    fn streamed_tool_calls_are_put_together() {
+        let mut calls = Vec::new();
+        merge_tool_call_deltas(
+            &mut calls,
+            &[json!({ "index": 0, "id": "call_1", "function": { "name": "calculate", "arguments": "{\"expr" } })],
+        );
+        merge_tool_call_deltas(
+            &mut calls,
+            &[
+                json!({ "index": 0, "function": { "arguments": "ession\":\"1+1\"}" } }),
+                json!({ "index": 1, "id": "call_2", "function": { "name": "user_level", "arguments": "{}" } }),
+                json!({ "index": 5, "id": "call_6" }),
+            ],
+        );
+
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].function.arguments, "{\"expression\":\"1+1\"}");
+        assert_eq!(calls[1].function.name, "user_level");
+    }
+
+    #[tokio::test]
+    async fn openai_compatible_servers_stream_deltas() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/v1/chat/completions",
+            post(|Json(request): Json<Value>| async move {
+                assert_eq!(request["stream"], true);
+                assert_eq!(request["stream_options"]["include_usage"], true);
+                assert_eq!(
+                    request["messages"][0]["content"][1]["image_url"]["url"],
+                    "https://example.com/a.png"
+                );
+                ["Hel", "lo"]
+                    .iter()
+                    .map(|delta| {
+                        format!(
+                            "data: {}\n\n",
+                            json!({ "model": "llama3:8b", "choices": [{ "delta": { "content": delta } }] })
+                        )
+                    })
+                    .chain([
+                        format!(
+                            "data: {}\n\n",
+                            json!({ "choices": [], "usage": { "prompt_tokens": 12, "completion_tokens": 2 } })
+                        ),
+                        "data: [DONE]\n\n".to_string(),
+                    ])
+                    .collect::<String>()
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let provider = OpenAiCompatible::new(&format!("http://{}/v1", address), None);
+        let request = ChatRequest {
+            model: "llama3".to_string(),
+            messages: vec![ChatMessage {
+                images: vec!["https://example.com/a.png".to_string()],
+                ..ChatMessage::user("hi")
+            }],
+            ..Default::default()
+        };
+        let events = provider
+            .stream(request)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(
+            events,
+            [
+                StreamEvent::Delta("Hel".to_string()),
+                StreamEvent::Delta("lo".to_string()),
+                StreamEvent::Done(Completion {
+                    content: "Hello".to_string(),
+                    model: "llama3:8b".to_string(),
+                    usage: Some(Usage {
+                        prompt_tokens: 12,
+                        completion_tokens: 2,
+                        cost: None,
+                    }),
+                    ..Default::default()
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn tool_turns_are_sent_in_the_openai_format() {
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            function: FunctionCall {
+                name: "calculate".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let request = ChatRequest {
+            messages: vec![ChatMessage::assistant("", vec![call]), ChatMessage::tool("call_1", "2")],
+            tools: vec![json!({ "type": "function" })],
+            ..Default::default()
+        };
+
+        let body = OpenAiCompatible::body(&request, false);
+
+        assert_eq!(body["messages"][0]["tool_calls"][0]["function"]["name"], "calculate");
+        assert_eq!(body["messages"][0]["tool_calls"][0]["type"], "function");
+        assert_eq!(body["messages"][1]["tool_call_id"], "call_1");
+        assert_eq!(body["tool_choice"], "auto");
+        assert!(body.get("response_format").is_none());
+    }
+}
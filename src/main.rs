@@ -34,25 +34,48 @@ use vesper::prelude::*;
 
 use std::{collections::HashMap, env, sync::Arc};
 
+mod ai_context;
+mod ai_controls;
+mod ai_fallback;
 pub mod ai_message;
+mod ai_thread;
+mod ai_trace;
+mod ai_tools;
+mod ai_usage;
 pub mod brave;
+mod channel_summary;
 mod color_quiz;
 mod commands;
 mod config;
 mod currency_fetcher;
 mod database;
 mod db;
+mod embeddings;
+mod emoji_riddle;
 mod event_handler;
+mod interjection;
+mod llm;
 mod math_test;
 mod memory_creator;
+mod memory_search;
+mod message_actions;
 mod message_handler;
+mod persona;
 mod pfp_updater;
+#[cfg(test)]
+mod prompt_regression;
 mod qalc;
 mod ratewaifu;
+mod quiz;
+mod quiz_difficulty;
 mod quiz_handler;
 mod structs;
+mod tldr;
+mod tournament;
+mod typing_race;
 pub mod utils;
 mod web;
+mod word_scramble;
 mod zalgos;
 
 static RESPONDERS: Lazy<HashMap<String, Responder>> =
@@ -156,6 +179,27 @@ async fn main() -> color_eyre::Result<()> {
         }
     }
 
+    match db::get_all_channel_settings(&pool).await {
+        Ok(settings) => {
+            state.lock().await.channel_settings = settings.into_iter().map(|s| (s.channel_id as u64, s)).collect();
+        }
+        Err(e) => {
+            tracing::warn!("Failed to load channel settings: {}. Using defaults.", e);
+        }
+    }
+
+    match db::get_ai_threads(&pool).await {
+        Ok(threads) => {
+            state.lock().await.ai_threads = threads
+                .into_iter()
+                .map(|t| (t.thread_id as u64, t.parent_id as u64))
+                .collect();
+        }
+        Err(e) => {
+            tracing::warn!("Failed to load AI threads: {}", e);
+        }
+    }
+
     // Update qalc exchange rates at startup
     if let Err(e) = qalc::update_rates() {
         tracing::warn!("Failed to update qalc exchange rates: {}", e);
@@ -170,6 +214,16 @@ async fn main() -> color_eyre::Result<()> {
             .command(commands::currency::pln)
             .command(commands::translate::translate)
             .command(commands::qalc::qalc)
+            .command(commands::quizstats::quizstats)
+            .command(commands::quiz::quiz)
+            .command(commands::quiz::quizsettings)
+            .command(commands::quiz::tournament)
+            .command(commands::ai::aisettings)
+            .command(commands::ai::tldr)
+            .command(commands::context_menu::explain_this)
+            .command(commands::context_menu::roast_this)
+            .command(commands::context_menu::translate_this)
+            .command(commands::context_menu::remember_this)
             .build(),
     );
 
@@ -178,13 +232,18 @@ async fn main() -> color_eyre::Result<()> {
     // Start web server if port is configured
     if let Some(web_port) = config.web_port {
         let pool_clone = pool.clone();
+        let llm = state.lock().await.llm.clone();
+        let config = Arc::clone(&config);
         tokio::spawn(async move {
-            if let Err(e) = web::run_web_server(pool_clone, web_port).await {
+            if let Err(e) = web::run_web_server(pool_clone, llm, config, web_port).await {
                 tracing::error!("Web server error: {:?}", e);
             }
         });
     }
 
+    // Announce expired quizzes and keep tournaments moving in quiet channels
+    quiz_handler::schedule_quiz_expiry(Arc::clone(&http), Arc::clone(&state));
+
     // Start daily profile picture updates
     pfp_updater::schedule_daily_updates(Arc::clone(&http), Arc::clone(&state)).await;
 
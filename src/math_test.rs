@@ -1,8 +1,17 @@
-use crate::database::MathQuestion;
+use crate::{
+    ai_usage::{Caller, Feature},
+    db,
+    llm::{ChatMessage, ChatRequest, LlmProvider},
+    quiz::{Grade, Quiz, QuizContext, QuizKind},
+    quiz_difficulty::Difficulty,
+    structs::Command,
+    utils::image::render_noisy_text,
+};
 use color_eyre::Result;
 use deadpool_postgres::Pool;
-use openrouter_api::{OpenRouterClient, types::chat::{ChatCompletionRequest, Message, MessageContent}};
-use rand::Rng;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::time::Duration;
+use twilight_model::http::attachment::Attachment;
 
 const MATH_PROMPTS: [&str; 3] = [
     "Generate a simple mental math expression that can be solved in your head. Use basic operations like addition, subtraction, multiplication, or division with small numbers (prefer numbers under 20, maximum 100). Output ONLY the mathematical expression, nothing else. Examples: {ex1}, {ex2}, {ex3}, {ex4}",
@@ -10,151 +19,257 @@ const MATH_PROMPTS: [&str; 3] = [
     "Write a simple arithmetic calculation using small, friendly numbers that can be computed without a calculator. Stick to basic operations (+, -, *, /). Output ONLY the math expression. Examples: {ex1}, {ex2}, {ex3}, {ex4}"
 ];
 
+/// Shape of the expressions generated for a [`Difficulty`].
+#[derive(Debug, Clone, Copy)]
+struct Tier {
+    /// Inclusive range of terms joined with `+` / `-`
+    terms: (usize, usize),
+    /// Largest plain operand
+    max_operand: i64,
+    /// Largest factor in a product, `0` disables multiplication
+    max_factor: i64,
+    /// Largest divisor, `0` disables division. Division always comes out even.
+    max_divisor: i64,
+    /// Allow `k * (a ± b)` groups
+    parentheses: bool,
+}
+
+fn tier(difficulty: Difficulty) -> Tier {
+    match difficulty {
+        Difficulty::Easy => Tier {
+            terms: (2, 2),
+            max_operand: 20,
+            max_factor: 0,
+            max_divisor: 0,
+            parentheses: false,
+        },
+        Difficulty::Medium => Tier {
+            terms: (2, 3),
+            max_operand: 50,
+            max_factor: 12,
+            max_divisor: 0,
+            parentheses: false,
+        },
+        Difficulty::Hard => Tier {
+            terms: (3, 4),
+            max_operand: 100,
+            max_factor: 15,
+            max_divisor: 12,
+            parentheses: true,
+        },
+        Difficulty::Expert => Tier {
+            terms: (4, 5),
+            max_operand: 500,
+            max_factor: 25,
+            max_divisor: 20,
+            parentheses: true,
+        },
+    }
+}
+
+/// Plain-language description handed to the LLM so its questions match the tier.
+fn llm_hint(difficulty: Difficulty) -> &'static str {
+    match difficulty {
+        Difficulty::Easy => "Use only addition and subtraction with two numbers under 20.",
+        Difficulty::Medium => "Use two or three numbers under 50 with addition, subtraction or multiplication.",
+        Difficulty::Hard => "Use three or four numbers with mixed operations, at most one pair of parentheses and only divisions that come out even.",
+        Difficulty::Expert => "Use four or five numbers with mixed operations, parentheses and only divisions that come out even.",
+    }
+}
+
+/// Where a question came from, stored alongside it in the question bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuestionSource {
+    Procedural,
+    Llm,
+}
+
+impl QuestionSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuestionSource::Procedural => "procedural",
+            QuestionSource::Llm => "llm",
+        }
+    }
+}
+
+/// The model used for generating questions instead of procedurally.
+#[derive(Debug, Clone, Copy)]
+pub struct LlmSource<'a> {
+    pub llm: &'a dyn LlmProvider,
+    pub model: &'a str,
+}
+
+#[derive(Debug, Clone)]
 pub struct MathTest {
     pub question: String,
     pub answer: f64,
+    pub difficulty: Difficulty,
+    /// Seed for rendering the question as a noisy image, `None` posts it as text
+    pub image_seed: Option<u64>,
 }
 
 impl MathTest {
-    pub async fn generate(
-        openai_api_key: &str,
-        model: &str,
+    /// Generates a question at `difficulty` and stores it in the `math_question` bank.
+    ///
+    /// With an [`LlmSource`] the model is asked first. If it fails, a previously stored model question of
+    /// the same difficulty is reused, and the procedural generator is the final fallback.
+    pub async fn generate_question(
+        llm: Option<LlmSource<'_>>,
         db: &Pool,
+        difficulty: Difficulty,
         rng: &mut impl Rng,
     ) -> Result<Self> {
-        let client = OpenRouterClient::from_api_key(openai_api_key)?;
-
-        // Retry loop to avoid recursion
-        for attempt in 0..5 {
-            // Generate random example questions to show the AI
-            // Example 1: Addition
-            let ex1_a = rng.gen_range(5..50);
-            let ex1_b = rng.gen_range(5..50);
-            let ex1 = format!("{} + {}", ex1_a, ex1_b);
-
-            // Example 2: Multiplication
-            let ex2_a = rng.gen_range(3..15);
-            let ex2_b = rng.gen_range(3..15);
-            let ex2 = format!("{} * {}", ex2_a, ex2_b);
-
-            // Example 3: Division or Subtraction
-            let ex3 = if rng.gen_bool(0.5) {
-                let divisor = rng.gen_range(2..13);
-                let result = rng.gen_range(5..20);
-                format!("{} / {}", divisor * result, divisor)
-            } else {
-                let ex3_a = rng.gen_range(30..100);
-                let ex3_b = rng.gen_range(5..30);
-                format!("{} - {}", ex3_a, ex3_b)
-            };
+        if let Some(llm) = llm {
+            match Self::generate_llm(llm, db, difficulty, rng).await {
+                Ok(test) => return Ok(test),
+                Err(e) => tracing::warn!("LLM math generation failed, falling back: {:?}", e),
+            }
 
-            // Example 4: Multi-operation or simple operation
-            let ex4 = if rng.gen_bool(0.3) {
-                // Multi-operation
-                let a = rng.gen_range(3..20);
-                let b = rng.gen_range(3..20);
-                let c = rng.gen_range(3..20);
-                match rng.gen_range(0..3) {
-                    0 => format!("{} + {} + {}", a, b, c),
-                    1 => format!("{} - {} + {}", a + b + c, b, c),
-                    _ => format!("{} + {} - {}", a, b, c),
+            match db::random_math_question(db, difficulty.as_str(), Some(QuestionSource::Llm.as_str())).await {
+                Ok(Some(stored)) => {
+                    return Ok(MathTest {
+                        question: stored.question,
+                        answer: stored.answer,
+                        difficulty,
+                        image_seed: None,
+                    })
                 }
-            } else {
-                // Simple division
-                let divisor = rng.gen_range(2..11);
-                let result = rng.gen_range(5..15);
-                format!("{} / {}", divisor * result, divisor)
-            };
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to read math question bank: {:?}", e),
+            }
+        }
 
-            // Select a random prompt template and fill in the example questions
-            let prompt_template = MATH_PROMPTS[rng.gen_range(0..MATH_PROMPTS.len())];
-            let user_prompt = prompt_template
-                .replace("{ex1}", &ex1)
-                .replace("{ex2}", &ex2)
-                .replace("{ex3}", &ex3)
-                .replace("{ex4}", &ex4);
-
-            let system_prompt = "You are a math expression generator for mental math challenges. \
-                Generate simple arithmetic expressions that can be solved mentally. \
-                Output ONLY the mathematical expression with numbers and operators, no explanations, no greetings, no additional text.";
-
-            // Generate question using AI
-            let request = ChatCompletionRequest {
-                model: model.to_string(),
-                messages: vec![
-                    Message {
-                        role: "system".to_string(),
-                        content: MessageContent::Text(system_prompt.to_string()),
-                        ..Default::default()
-                    },
-                    Message {
-                        role: "user".to_string(),
-                        content: MessageContent::Text(user_prompt),
-                        ..Default::default()
-                    },
-                ],
-                temperature: Some(0.9),
-                max_tokens: Some(50),
-                ..Default::default()
-            };
+        let test = Self::generate_procedural(difficulty, rng);
+        if let Err(e) = Self::store(db, &test, difficulty, QuestionSource::Procedural).await {
+            tracing::warn!("Failed to store procedural math question: {:?}", e);
+        }
+        Ok(test)
+    }
 
-            let question = match client.chat()?.chat_completion(request).await {
-                Ok(response) => {
-                    match response.choices.first() {
-                        Some(choice) => match &choice.message.content {
-                            MessageContent::Text(text) => text.trim().to_string(),
-                            MessageContent::Parts(_) => {
-                                tracing::error!("Unexpected multipart content in response");
-                                continue;
-                            }
-                        },
-                        None => {
-                            tracing::error!("No response from API");
-                            continue;
-                        }
-                    }
-                },
-                Err(e) => {
-                    tracing::error!("AI generation failed on attempt {}: {:?}", attempt + 1, e);
-                    continue;
-                }
-            };
+    async fn store(db: &Pool, test: &MathTest, difficulty: Difficulty, source: QuestionSource) -> Result<()> {
+        if db::math_question_exists(db, &test.question).await? {
+            return Ok(());
+        }
+        db::insert_math_question(db, &test.question, test.answer, difficulty.as_str(), source.as_str()).await
+    }
+
+    /// Builds a question without any network access.
+    pub fn generate_procedural(difficulty: Difficulty, rng: &mut impl Rng) -> Self {
+        let tier = tier(difficulty);
+        let terms = rng.gen_range(tier.terms.0..=tier.terms.1);
+
+        let mut question = String::new();
+        for i in 0..terms {
+            if i > 0 {
+                question.push_str(if rng.gen_bool(0.5) { " + " } else { " - " });
+            }
+            question.push_str(&Self::procedural_term(&tier, rng));
+        }
+
+        let mut ns = fasteval::EmptyNamespace;
+        // Every term is built from integers with even division, so this cannot fail.
+        let answer = fasteval::ez_eval(&question, &mut ns).expect("procedural expression must evaluate");
+
+        MathTest {
+            question,
+            answer,
+            difficulty,
+            image_seed: None,
+        }
+    }
+
+    fn procedural_term(tier: &Tier, rng: &mut impl Rng) -> String {
+        let roll = rng.gen_range(0..4);
+        match roll {
+            0 if tier.max_factor > 0 => {
+                format!("{} * {}", rng.gen_range(2..=tier.max_factor), rng.gen_range(2..=tier.max_factor))
+            }
+            1 if tier.max_divisor > 0 => {
+                let divisor = rng.gen_range(2..=tier.max_divisor);
+                let quotient = rng.gen_range(2..=tier.max_divisor.max(10));
+                format!("{} / {}", divisor * quotient, divisor)
+            }
+            2 if tier.parentheses => {
+                let op = if rng.gen_bool(0.5) { '+' } else { '-' };
+                format!(
+                    "{} * ({} {} {})",
+                    rng.gen_range(2..=9),
+                    rng.gen_range(1..=tier.max_operand / 2),
+                    op,
+                    rng.gen_range(1..=tier.max_operand / 2)
+                )
+            }
+            _ => rng.gen_range(1..=tier.max_operand).to_string(),
+        }
+    }
+
+    /// Asks the model for one expression and evaluates it.
+    async fn ask_llm(llm: LlmSource<'_>, difficulty: Difficulty, rng: &mut impl Rng) -> Result<(String, f64)> {
+        // Seed the prompt with procedurally generated examples at the requested difficulty
+        let examples = (0..4)
+            .map(|_| Self::generate_procedural(difficulty, rng).question)
+            .collect::<Vec<_>>();
+
+        // Select a random prompt template and fill in the example questions
+        let prompt_template = MATH_PROMPTS[rng.gen_range(0..MATH_PROMPTS.len())];
+        let user_prompt = format!(
+            "{} {}",
+            prompt_template
+                .replace("{ex1}", &examples[0])
+                .replace("{ex2}", &examples[1])
+                .replace("{ex3}", &examples[2])
+                .replace("{ex4}", &examples[3]),
+            llm_hint(difficulty)
+        );
+
+        let system_prompt = "You are a math expression generator for mental math challenges. \
+            Generate simple arithmetic expressions that can be solved mentally. \
+            Output ONLY the mathematical expression with numbers and operators, no explanations, no greetings, no additional text.";
+
+        let request = ChatRequest {
+            model: llm.model.to_string(),
+            messages: vec![ChatMessage::system(system_prompt), ChatMessage::user(user_prompt)],
+            temperature: Some(0.9),
+            max_tokens: Some(50),
+            caller: Caller::new(Feature::Math, None, None),
+            ..Default::default()
+        };
+        let question = llm.llm.chat(request).await?.trim().to_string();
 
-            // Validate the expression with fasteval
-            let mut ns = fasteval::EmptyNamespace;
-            let answer = match fasteval::ez_eval(&question, &mut ns) {
-                Ok(result) => result,
+        // Validate the expression with fasteval
+        let mut ns = fasteval::EmptyNamespace;
+        let answer = fasteval::ez_eval(&question, &mut ns)
+            .map_err(|e| color_eyre::eyre::eyre!("Invalid math expression generated '{}': {:?}", question, e))?;
+        Ok((question, answer))
+    }
+
+    async fn generate_llm(llm: LlmSource<'_>, db: &Pool, difficulty: Difficulty, rng: &mut impl Rng) -> Result<Self> {
+        // Retry loop to avoid recursion
+        for attempt in 0..5 {
+            let (question, answer) = match Self::ask_llm(llm, difficulty, rng).await {
+                Ok(generated) => generated,
                 Err(e) => {
-                    tracing::error!("Invalid math expression generated '{}': {:?}", question, e);
+                    tracing::error!("AI generation failed on attempt {}: {:?}", attempt + 1, e);
                     continue;
                 }
             };
 
-            // Check if this question already exists in the database
-            let conn = db.get().await?;
-            let existing: Vec<MathQuestion> = conn
-                .query(
-                    "SELECT * FROM math_question WHERE question = $1",
-                    &[&question],
-                )
-                .await?
-                .iter()
-                .map(postgres_from_row::FromRow::from_row)
-                .collect();
-
             // If question exists, try again
-            if !existing.is_empty() {
+            if db::math_question_exists(db, &question).await? {
                 tracing::debug!("Question '{}' already exists, retrying", question);
                 continue;
             }
 
-            // Store in database
-            conn.execute(
-                "INSERT INTO math_question (question, answer) VALUES ($1, $2)",
-                &[&question, &answer],
-            ).await?;
+            let test = MathTest {
+                question,
+                answer,
+                difficulty,
+                image_seed: None,
+            };
+            Self::store(db, &test, difficulty, QuestionSource::Llm).await?;
 
-            return Ok(MathTest { question, answer });
+            return Ok(test);
         }
 
         // If all attempts failed, return error
@@ -172,3 +287,108 @@ impl MathTest {
         (self.answer - user_answer).abs() <= 0.1
     }
 }
+
+impl Quiz for MathTest {
+    async fn generate(ctx: QuizContext<'_>) -> Result<Self> {
+        let llm = ctx
+            .llm
+            .filter(|_| ctx.config.math_llm_questions)
+            .map(|llm| LlmSource {
+                llm,
+                model: &ctx.config.openrouter_model,
+            });
+        let mut test = Self::generate_question(llm, ctx.db, ctx.difficulty, ctx.rng).await?;
+        if ctx.config.math_image_questions {
+            test.image_seed = Some(ctx.rng.random());
+        }
+        Ok(test)
+    }
+
+    fn kind(&self) -> QuizKind {
+        QuizKind::Math
+    }
+
+    fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    fn time_limit(&self) -> Duration {
+        self.difficulty.math_time_limit()
+    }
+
+    fn render(&self, user_id: u64) -> Result<Command> {
+        let header = format!(
+            "<@{}> **MATH TEST TIME!** ({}) Solve this in {} seconds:",
+            user_id,
+            self.difficulty.as_str(),
+            self.time_limit().as_secs()
+        );
+
+        match self.image_seed {
+            Some(seed) => {
+                let image = render_noisy_text(&self.question, &mut SmallRng::seed_from_u64(seed))?;
+                Ok(Command::text(format!("{}\n(Answer to 1 decimal place)", header))
+                    .attachments(vec![Attachment::from_bytes("math.png".to_string(), image, 1)]))
+            }
+            None => Ok(Command::text(format!(
+                "{}\n`{}`\n(Answer to 1 decimal place)",
+                header, self.question
+            ))),
+        }
+    }
+
+    fn validate(&self, answer: &str, _elapsed: Duration) -> Option<Grade> {
+        self.validate_answer(answer).then(|| Grade::full("Correct! Well done."))
+    }
+
+    fn question(&self) -> String {
+        self.question.clone()
+    }
+
+    fn answer(&self) -> String {
+        format!("{:.1}", self.answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ScriptedLlm;
+
+    #[test]
+    fn procedural_questions_evaluate_to_whole_numbers() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        for difficulty in Difficulty::ALL {
+            for _ in 0..200 {
+                let test = MathTest::generate_procedural(difficulty, &mut rng);
+                assert_eq!(test.answer.fract(), 0.0, "{} = {}", test.question, test.answer);
+                assert!(test.validate_answer(&test.answer.to_string()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn model_questions_are_evaluated() {
+        let llm = ScriptedLlm::new(["12 + 30\n", "twelve plus thirty"]);
+        let source = LlmSource {
+            llm: &llm,
+            model: "math-model",
+        };
+        let mut rng = SmallRng::seed_from_u64(1);
+
+        let (question, answer) = MathTest::ask_llm(source, Difficulty::Easy, &mut rng).await.unwrap();
+        assert_eq!(question, "12 + 30");
+        assert_eq!(answer, 42.0);
+        assert!(MathTest::ask_llm(source, Difficulty::Easy, &mut rng).await.is_err());
+        assert_eq!(llm.requests.lock().unwrap()[0].model, "math-model");
+    }
+
+    #[test]
+    fn easy_questions_only_add_and_subtract() {
+        let mut rng = SmallRng::seed_from_u64(42);
+        for _ in 0..200 {
+            let question = MathTest::generate_procedural(Difficulty::Easy, &mut rng).question;
+            assert!(!question.contains(['*', '/', '(']), "{}", question);
+        }
+    }
+}
@@ -3,11 +3,17 @@ use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc};
 
-use crate::{config::Config, db};
+use crate::{
+    ai_usage::{Caller, Feature},
+    config::Config,
+    database::User,
+    db,
+    llm::{ChatMessage, ChatRequest, LlmProvider},
+};
 
 /// JSON response structure for memory creation
 #[derive(Debug, Serialize, Deserialize)]
-struct MemoryCreationResponse {
+pub struct MemoryCreationResponse {
     #[serde(default)]
     memories: Vec<MemoryEntry>,
     #[serde(default)]
@@ -29,6 +35,24 @@ struct ProfileUpdate {
     example_output: Option<String>,
 }
 
+/// The memory prompt for `context`, with the names and current profiles of `participants`.
+pub fn memory_prompt(context: &str, participants: &[User]) -> String {
+    let names = participants.iter().map(|user| user.name.clone()).collect::<Vec<_>>();
+    let profiles = participants
+        .iter()
+        .map(|user| {
+            serde_json::json!({
+                "username": user.name,
+                "relationship": user.relationship,
+                "example_input": user.example_input,
+                "example_output": user.example_output,
+            })
+        })
+        .collect::<Vec<_>>();
+    let profiles_json = serde_json::to_string(&profiles).unwrap_or_else(|_| "[]".to_string());
+    build_memory_prompt(context, &names.join(", "), &profiles_json)
+}
+
 /// Build the enhanced system prompt for memory creation with quality guidelines
 fn build_memory_prompt(context: &str, participants: &str, profiles: &str) -> String {
     format!(
@@ -107,174 +131,189 @@ Remember: Output ONLY valid JSON, nothing else. Focus on persistent, meaningful
     )
 }
 
-/// Process memory creation response and store in database
-async fn process_memory_response(database: &Pool, response_text: &str) -> Result<usize> {
-    log::info!("Raw memory response: {}", response_text);
+/// Ask the memory model for memories and profile updates in JSON mode
+pub async fn request_memories(
+    llm: &dyn LlmProvider,
+    model: String,
+    prompt: String,
+    guild_id: Option<u64>,
+) -> Result<MemoryCreationResponse> {
+    let request = ChatRequest {
+        model,
+        messages: vec![ChatMessage::user(prompt)],
+        max_tokens: Some(1024),
+        caller: Caller::new(Feature::Memory, None, guild_id),
+        ..Default::default()
+    };
+    let response = llm.chat_json(request).await?;
+    log::info!("Raw memory response: {}", response);
 
-    // Try to extract JSON from the response (in case the model adds extra text)
-    let json_start = response_text.find('{').unwrap_or(0);
-    let json_end = response_text.rfind('}').map(|i| i + 1).unwrap_or(response_text.len());
-    let json_text = &response_text[json_start..json_end];
+    serde_json::from_value(response.clone())
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to parse memory JSON: {} - Raw: {}", e, response))
+}
 
-    let memory_response: MemoryCreationResponse = serde_json::from_str(json_text)
-        .map_err(|e| color_eyre::eyre::eyre!("Failed to parse memory JSON: {} - Raw: {}", e, json_text))?;
+/// A database write asked for by a memory response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryWrite {
+    Memory {
+        user_id: u64,
+        key: String,
+        content: String,
+    },
+    /// A profile change that waits for approval in the web panel
+    ProfileCandidate {
+        user_id: u64,
+        field: &'static str,
+        value: String,
+    },
+}
 
-    let mut created_count = 0;
+/// The writes `memory_response` asks for. `users` holds the users it names, by lowercase name; entries for anyone
+/// else are skipped.
+pub fn plan_memory_writes(memory_response: MemoryCreationResponse, users: &HashMap<String, User>) -> Vec<MemoryWrite> {
+    let mut writes = Vec::new();
 
     for entry in memory_response.memories {
-        // Resolve username to user ID
-        if let Ok(Some(user)) = db::get_user_by_name(database, &entry.username).await {
-            let user_id = user.discord_id();
-            match db::upsert_memory(database, user_id, &entry.key, &entry.content).await {
-                Ok(_) => {
-                    log::info!(
-                        "Created memory for user {} ({}): {} = {}",
-                        entry.username,
-                        user_id,
-                        entry.key,
-                        entry.content
-                    );
-                    created_count += 1;
-                }
-                Err(e) => {
-                    log::error!("Failed to insert memory for {}: {}", entry.username, e);
-                }
-            }
-        } else {
+        let Some(user) = users.get(&entry.username.to_lowercase()) else {
             log::warn!(
                 "Could not resolve username '{}' to user ID, skipping memory",
                 entry.username
             );
-        }
+            continue;
+        };
+        writes.push(MemoryWrite::Memory {
+            user_id: user.discord_id(),
+            key: entry.key,
+            content: entry.content,
+        });
     }
 
     for update in memory_response.profile_updates {
-        let Some(user) = db::get_user_by_name(database, &update.username).await? else {
+        let Some(user) = users.get(&update.username.to_lowercase()) else {
             log::warn!("Could not resolve profile username '{}', skipping", update.username);
             continue;
         };
         let relationship = update.relationship.filter(|v| !v.trim().is_empty() && v.len() <= 1000);
         let example_input = update.example_input.filter(|v| !v.trim().is_empty() && v.len() <= 2000);
-        let example_output = update.example_output.filter(|v| !v.trim().is_empty() && v.len() <= 2000);
+        let example_output = update
+            .example_output
+            .filter(|v| !v.trim().is_empty() && v.len() <= 2000);
         if let Some(relationship) = relationship.filter(|value| value != &user.relationship) {
-            db::stage_profile_candidate(database, user.discord_id(), "relationship", &relationship).await?;
-            created_count += 1;
+            writes.push(MemoryWrite::ProfileCandidate {
+                user_id: user.discord_id(),
+                field: "relationship",
+                value: relationship,
+            });
         }
         if let (Some(input), Some(output)) = (example_input, example_output) {
             if input == user.example_input && output == user.example_output {
                 continue;
             }
-            let pair = serde_json::json!({ "input": input, "output": output }).to_string();
-            db::stage_profile_candidate(database, user.discord_id(), "example", &pair).await?;
-            created_count += 1;
+            writes.push(MemoryWrite::ProfileCandidate {
+                user_id: user.discord_id(),
+                field: "example",
+                value: serde_json::json!({ "input": input, "output": output }).to_string(),
+            });
+        }
+    }
+
+    writes
+}
+
+/// Store the memories and profile updates of a memory response in the database
+async fn process_memory_response(database: &Pool, memory_response: MemoryCreationResponse) -> Result<usize> {
+    // Resolve usernames to users
+    let mut users = HashMap::new();
+    for name in memory_response.memories.iter().map(|entry| &entry.username) {
+        if let Ok(Some(user)) = db::get_user_by_name(database, name).await {
+            users.insert(name.to_lowercase(), user);
+        }
+    }
+    for name in memory_response.profile_updates.iter().map(|update| &update.username) {
+        if let Some(user) = db::get_user_by_name(database, name).await? {
+            users.insert(name.to_lowercase(), user);
+        }
+    }
+
+    let mut created_count = 0;
+    for write in plan_memory_writes(memory_response, &users) {
+        match write {
+            MemoryWrite::Memory { user_id, key, content } => {
+                match db::upsert_memory(database, user_id, &key, &content).await {
+                    Ok(_) => {
+                        log::info!("Created memory for user {}: {} = {}", user_id, key, content);
+                        created_count += 1;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to insert memory for {}: {}", user_id, e);
+                    }
+                }
+            }
+            MemoryWrite::ProfileCandidate { user_id, field, value } => {
+                db::stage_profile_candidate(database, user_id, field, &value).await?;
+                created_count += 1;
+            }
         }
     }
 
     Ok(created_count)
 }
 
+/// The model for background work like memories and summaries.
+pub fn memory_model(config: &Config) -> String {
+    // Profile extraction needs deterministic JSON, not the chat model's long
+    // reasoning mode. It remains configurable for future model changes. Local
+    // servers only know their own models, so they default to the chat model.
+    config
+        .openrouter_memory_model
+        .clone()
+        .unwrap_or_else(|| match config.llm_base_url {
+            Some(_) => config.openrouter_model.clone(),
+            None => "tencent/hy3-preview".to_string(),
+        })
+}
+
 /// Main function to create memories in the background
 pub async fn create_memories_background(
     database: Pool,
     context: String,
     user_mentions: HashMap<String, u64>,
+    guild_id: Option<u64>,
+    llm: Option<Arc<dyn LlmProvider>>,
     config: Arc<Config>,
 ) {
-    // Get API key
-    let api_key = match &config.openrouter_api_key {
-        Some(key) => key.clone(),
-        None => {
-            log::warn!("OpenRouter API key not configured, skipping memory creation");
-            return;
-        }
+    let Some(llm) = llm else {
+        log::warn!("No chat model configured, skipping memory creation");
+        return;
     };
 
-    // Profile extraction needs deterministic JSON, not the chat model's long
-    // reasoning mode. It remains configurable for future model changes.
-    let model = config
-        .openrouter_memory_model
-        .clone()
-        .unwrap_or_else(|| "tencent/hy3-preview".to_string());
+    let model = memory_model(&config);
 
     log::info!("Creating memories using model: {}", model);
 
     // Build list of participants
-    let mut participants: Vec<String> = Vec::new();
+    let mut participants = Vec::new();
     for (_mention, &user_id) in &user_mentions {
         if let Ok(Some(u)) = db::get_user(&database, user_id).await {
-            participants.push(u.name);
+            participants.push(u);
         }
     }
 
-    let participants_str = participants.join(", ");
-
-    let mut profiles = Vec::new();
-    for name in &participants {
-        if let Ok(Some(user)) = db::get_user_by_name(&database, name).await {
-            profiles.push(serde_json::json!({
-                "username": user.name,
-                "relationship": user.relationship,
-                "example_input": user.example_input,
-                "example_output": user.example_output,
-            }));
-        }
-    }
-    let profiles_json = serde_json::to_string(&profiles).unwrap_or_else(|_| "[]".to_string());
-
     // Build the memory creation prompt
-    let system_prompt = build_memory_prompt(&context, &participants_str, &profiles_json);
+    let system_prompt = memory_prompt(&context, &participants);
 
     log::debug!("Memory creation prompt: {}", system_prompt);
 
-    let request = serde_json::json!({
-        "model": model,
-        "messages": [{ "role": "user", "content": system_prompt }],
-        "max_tokens": 1024,
-        "response_format": { "type": "json_object" },
-        "reasoning": { "effort": "none" }
-    });
-    let http_client = match reqwest::Client::builder().timeout(std::time::Duration::from_secs(120)).build() {
-        Ok(client) => client,
-        Err(e) => {
-            log::error!("Failed to create memory HTTP client: {}", e);
-            return;
-        }
-    };
-    let mut http_request = http_client
-        .post(format!("{}/chat/completions", config.openrouter_base_url.trim_end_matches('/')))
-        .bearer_auth(api_key)
-        .json(&request);
-    if let Some(url) = &config.openrouter_site_url {
-        http_request = http_request.header("HTTP-Referer", url);
-    }
-    if let Some(name) = &config.openrouter_site_name {
-        http_request = http_request.header("X-Title", name);
-    }
-    let response = match http_request.send().await {
+    let response = match request_memories(llm.as_ref(), model, system_prompt, guild_id).await {
         Ok(response) => response,
         Err(e) => {
             log::error!("Memory model request failed: {}", e);
             return;
         }
     };
-    if !response.status().is_success() {
-        log::error!("Memory model returned HTTP {}", response.status());
-        return;
-    }
-    let response: serde_json::Value = match response.json().await {
-        Ok(response) => response,
-        Err(e) => {
-            log::error!("Invalid memory model response: {}", e);
-            return;
-        }
-    };
-    let Some(response_text) = response.pointer("/choices/0/message/content").and_then(|v| v.as_str()) else {
-        log::error!("Memory model returned no content");
-        return;
-    };
 
     // Process the response
-    match process_memory_response(&database, response_text).await {
+    match process_memory_response(&database, response).await {
         Ok(count) => {
             log::info!("Successfully created {} memories", count);
         }
@@ -283,3 +322,26 @@ pub async fn create_memories_background(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ScriptedLlm;
+
+    #[tokio::test]
+    async fn memory_replies_are_parsed_from_json_mode() {
+        let llm = ScriptedLlm::new([r#"{"memories": [{"username": "alice", "key": "pet", "content": "Has a cat"}]}"#]);
+
+        let response = request_memories(&llm, "memory-model".to_string(), "prompt".to_string(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(response.memories.len(), 1);
+        assert_eq!(response.memories[0].content, "Has a cat");
+        assert!(response.profile_updates.is_empty());
+        let requests = llm.requests.lock().unwrap();
+        assert_eq!(requests[0].model, "memory-model");
+        assert!(requests[0].json);
+        assert_eq!(requests[0].messages, vec![ChatMessage::user("prompt")]);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+
+use color_eyre::Result;
+use deadpool_postgres::Pool;
+
+use crate::{
+    database::Memory,
+    db,
+    embeddings::{self, Embedder},
+};
+
+/// Transcript lines searched along with the message
+const QUERY_CONTEXT_LINES: usize = 6;
+/// Words this short say nothing about what a memory is about
+const MIN_WORD_CHARS: usize = 3;
+/// Newest memories of a user that are ranked
+const MAX_CANDIDATES: i64 = 200;
+/// Memories embedded per search at most, so a user with many new memories doesn't stall a reply
+const MAX_EMBEDDED_PER_SEARCH: usize = 32;
+
+/// What to look for memories about: the message and the end of the transcript before it.
+pub fn query(context: &str, message: &str) -> String {
+    let lines = context.lines().collect::<Vec<_>>();
+    let mut query = lines[lines.len().saturating_sub(QUERY_CONTEXT_LINES)..].join("\n");
+    query.push('\n');
+    query.push_str(message);
+    query
+}
+
+/// The text a memory is embedded and matched as.
+fn memory_text(memory: &Memory) -> String {
+    format!("{}: {}", memory.key.replace('_', " "), memory.content)
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Orders memories by the words they share with `query`, newest first among equals. The fallback when no
+/// embedding backend is configured or it fails.
+fn rank_by_words(mut memories: Vec<Memory>, query: &str) -> Vec<Memory> {
+    let query = words(query);
+    memories.sort_by_cached_key(|memory| {
+        let shared = words(&memory_text(memory)).intersection(&query).count();
+        (std::cmp::Reverse(shared), std::cmp::Reverse(memory.id))
+    });
+    memories
+}
+
+/// Orders memories by how close their embedding is to the embedding of `query`. Memories that are new, edited or
+/// were embedded with another model are embedded first, up to [`MAX_EMBEDDED_PER_SEARCH`] of them; the rest rank
+/// last until a later search embeds them.
+async fn rank_by_embedding(
+    database: &Pool,
+    embedder: &dyn Embedder,
+    user_id: u64,
+    memories: Vec<Memory>,
+    query: &str,
+) -> Result<Vec<Memory>> {
+    let mut stored = db::get_memory_embeddings(database, user_id, embedder.model())
+        .await?
+        .into_iter()
+        .map(|(memory_id, source, embedding)| (memory_id, (source, embedding)))
+        .collect::<HashMap<_, _>>();
+
+    let missing = memories
+        .iter()
+        .map(|memory| (memory.id, memory_text(memory)))
+        .filter(|(id, text)| stored.get(id).is_none_or(|(source, _)| source != text))
+        .take(MAX_EMBEDDED_PER_SEARCH)
+        .collect::<Vec<_>>();
+    let mut texts = missing.iter().map(|(_, text)| text.clone()).collect::<Vec<_>>();
+    texts.push(query.to_string());
+    let mut vectors = embedder.embed(texts).await?;
+    let query_vector = vectors.pop().unwrap_or_default();
+
+    for ((memory_id, text), vector) in missing.into_iter().zip(vectors) {
+        if let Err(e) = db::save_memory_embedding(database, memory_id, embedder.model(), &text, &vector).await {
+            log::warn!("Failed to save the embedding of memory {}: {:?}", memory_id, e);
+        }
+        stored.insert(memory_id, (text, vector));
+    }
+
+    let score = |memory: &Memory| {
+        stored
+            .get(&memory.id)
+            .map_or(0.0, |(_, vector)| embeddings::cosine(vector, &query_vector))
+    };
+    let mut scored = memories
+        .into_iter()
+        .map(|memory| (score(&memory), memory))
+        .collect::<Vec<_>>();
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    Ok(scored.into_iter().map(|(_, memory)| memory).collect())
+}
+
+/// The `k` memories of `user_id` most relevant to `query`, by embedding when an embedder is configured and by shared
+/// words otherwise.
+pub async fn relevant_memories(
+    database: &Pool,
+    embedder: Option<&dyn Embedder>,
+    user_id: u64,
+    query: &str,
+    k: usize,
+) -> Vec<Memory> {
+    let memories = match db::get_memories(database, user_id, MAX_CANDIDATES).await {
+        Ok(memories) => memories,
+        Err(e) => {
+            log::warn!("Failed to load memories: {:?}", e);
+            return Vec::new();
+        }
+    };
+    if memories.len() <= k {
+        return memories;
+    }
+
+    let ranked = match embedder {
+        Some(embedder) => match rank_by_embedding(database, embedder, user_id, memories.clone(), query).await {
+            Ok(ranked) => ranked,
+            Err(e) => {
+                log::warn!("Failed to rank memories by embedding, ranking by words: {:?}", e);
+                rank_by_words(memories, query)
+            }
+        },
+        None => rank_by_words(memories, query),
+    };
+    ranked.into_iter().take(k).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(id: i64, key: &str, content: &str) -> Memory {
+        Memory {
+            id,
+            user_id: 1,
+            key: key.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn queries_take_the_end_of_the_transcript() {
+        let context = (1..=10)
+            .map(|i| format!("bob: line {}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let query = query(&context, "alice: hi");
+
+        assert!(query.starts_with("bob: line 5\n"));
+        assert!(query.ends_with("bob: line 10\nalice: hi"));
+        assert_eq!(super::query("", "alice: hi"), "\nalice: hi");
+    }
+
+    #[test]
+    fn shared_words_rank_memories_then_recency() {
+        let memories = vec![
+            memory(3, "music", "Plays bass in a punk band"),
+            memory(2, "pets", "Has a cat called Rust"),
+            memory(1, "technical_skills", "Writes Rust and Postgres at work"),
+        ];
+
+        let ranked = rank_by_words(memories, "alice: any tips for rust and postgres?")
+            .iter()
+            .map(|memory| memory.id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(ranked, [1, 2, 3]);
+    }
+}
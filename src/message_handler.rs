@@ -3,10 +3,11 @@ use rand::{
     seq::IndexedRandom,
     Rng,
 };
-use std::{sync::Arc, time::Instant};
-use tokio::sync::{mpsc, MutexGuard};
+use std::{collections::HashMap, sync::Arc, time::Instant};
+use tokio::sync::{mpsc, Mutex, MutexGuard};
 use twilight_http::Client as HttpClient;
 use twilight_model::{
+    channel::Message,
     gateway::payload::incoming::MessageCreate,
     id::{
         marker::{ChannelMarker, MessageMarker},
@@ -16,29 +17,33 @@ use twilight_model::{
 use vesper::twilight_exports::UserMarker;
 
 use crate::{
-    ai_message,
+    ai_context,
+    ai_controls::{self, ActiveReply, AiReplies, ReplyControls},
+    ai_fallback, ai_message, ai_thread,
+    ai_usage::{self, Caller, Feature},
     database::User,
-    db, memory_creator, quiz_handler,
-    ratewaifu,
+    db, interjection, memory_creator, quiz_handler, ratewaifu,
     structs::{Command, List, State},
     utils::levels::xp_required_for_level,
     zalgos::zalgify_text,
     RESPONDERS,
 };
 
-/// Handle streaming AI response with periodic updates
+/// Handle streaming AI response with periodic updates, returning the sent message and its final content. With
+/// `controls` the reply carries stop, regenerate and rating buttons.
 pub async fn handle_streaming_response(
     mut stream_rx: mpsc::UnboundedReceiver<String>,
     channel_id: Id<ChannelMarker>,
-    reply_to: Id<MessageMarker>,
+    reply_to: Option<Id<MessageMarker>>,
     http: Arc<HttpClient>,
-) {
+    mut controls: Option<ReplyControls>,
+) -> Option<(Id<MessageMarker>, String)> {
     const MIN_WORDS: usize = 3;
     const UPDATE_INTERVAL_MS: u128 = 1500;
     const POLL_INTERVAL_MS: u64 = 50;
 
     let mut content = String::new();
-    let mut message_id = None;
+    let mut message_id = controls.as_ref().and_then(ReplyControls::message_id);
     let mut last_update = Instant::now();
     let mut last_sent_content = String::new();
 
@@ -55,13 +60,25 @@ pub async fn handle_streaming_response(
                 Err(mpsc::error::TryRecvError::Disconnected) => {
                     // Stream ended - send final update if needed
                     if let Some(msg_id) = message_id {
-                        if !content.is_empty() && content != last_sent_content {
-                            if let Ok(req) = http.update_message(channel_id, msg_id).content(Some(&content)) {
+                        let changed = !content.is_empty() && content != last_sent_content;
+                        if changed || controls.is_some() {
+                            let buttons = ai_controls::buttons(false);
+                            let request = http
+                                .update_message(channel_id, msg_id)
+                                .components(controls.as_ref().map(|_| buttons.as_slice()))
+                                .and_then(|req| match changed {
+                                    true => req.content(Some(&content)),
+                                    false => Ok(req),
+                                });
+                            if let Ok(req) = request {
                                 let _ = req.exec().await;
                             }
                         }
+                        if let Some(controls) = &controls {
+                            controls.finished(msg_id, &content);
+                        }
                     }
-                    return;
+                    return message_id.map(|id| (id, content));
                 }
             }
         }
@@ -73,10 +90,25 @@ pub async fn handle_streaming_response(
 
         // Send initial message once we have enough words
         if message_id.is_none() && content.split_whitespace().count() >= MIN_WORDS {
-            match http.create_message(channel_id).content(&content) {
-                Ok(req) => match req.reply(reply_to).exec().await {
+            let buttons = ai_controls::buttons(true);
+            let request = http
+                .create_message(channel_id)
+                .content(&content)
+                .and_then(|req| match controls.is_some() {
+                    true => req.components(&buttons),
+                    false => Ok(req),
+                })
+                .map(|req| match reply_to {
+                    Some(id) => req.reply(id),
+                    None => req,
+                });
+            match request {
+                Ok(req) => match req.exec().await {
                     Ok(response) => {
                         if let Ok(msg) = response.model().await {
+                            if let Some(controls) = &mut controls {
+                                controls.sent(msg.id);
+                            }
                             message_id = Some(msg.id);
                             last_sent_content = content.clone();
                             last_update = Instant::now();
@@ -84,12 +116,12 @@ pub async fn handle_streaming_response(
                     }
                     Err(e) => {
                         log::error!("Failed to send initial message: {:?}", e);
-                        return;
+                        return None;
                     }
                 },
                 Err(e) => {
                     log::error!("Failed to create message: {:?}", e);
-                    return;
+                    return None;
                 }
             }
         }
@@ -109,9 +141,210 @@ pub async fn handle_streaming_response(
     }
 }
 
+/// What an AI reply to a message needs from the state, taken while the lock is held so the reply can be made
+/// without it.
+struct AiTurn {
+    msg: Message,
+    /// The message content, shortened for the prompt
+    content: String,
+    /// Pinged or replied to, rather than interjecting
+    addressed: bool,
+    /// (thread, parent channel) when the conversation happens in an AI thread
+    existing_thread: Option<(Id<ChannelMarker>, u64)>,
+    /// Answer in a new thread, as the channel's settings ask
+    start_thread: bool,
+    should_create_memory: bool,
+    /// The last few messages, for the interjection classifier
+    recent: String,
+    /// The chat history; `None` in threads, which load theirs from the database
+    context: Option<String>,
+    user_mentions: HashMap<String, u64>,
+    images: Vec<ai_message::ImageAttachment>,
+    services: ai_message::Services,
+    ai_replies: AiReplies,
+}
+
+/// The cached users named at the start of a line of `context`, by name.
+fn mentioned_users(state: &State, context: &str) -> HashMap<String, u64> {
+    let mut user_mentions = HashMap::new();
+    for line in context.lines() {
+        if let Some(colon_pos) = line.find(':') {
+            let username = line[..colon_pos].trim();
+            if !username.is_empty() && username != "The Trickster" {
+                // Try to find the real user ID from cache
+                for user_ref in state.cache.iter().users() {
+                    if user_ref.name == username {
+                        user_mentions.insert(username.to_string(), user_ref.id.get());
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    user_mentions
+}
+
+/// Replies to `turn.msg` with the chat model, unless the user or guild is over its quota or, for unprompted
+/// replies, the bot has nothing to add. The state is only locked to record what happened.
+async fn reply_with_ai(turn: AiTurn, state: Arc<Mutex<State>>, http: Arc<HttpClient>) -> color_eyre::Result<()> {
+    let AiTurn {
+        msg, existing_thread, ..
+    } = &turn;
+    let (user_id, guild_id) = (msg.author.id.get(), msg.guild_id.map(|id| id.get()));
+
+    if let Some(refusal) =
+        ai_usage::check_quota(&turn.services.database, &turn.services.config, user_id, guild_id).await
+    {
+        // Random interjections just don't happen, only people talking to the bot get told why
+        if turn.addressed || existing_thread.is_some() {
+            http.create_message(msg.channel_id)
+                .content(&refusal)?
+                .reply(msg.id)
+                .exec()
+                .await?;
+        }
+        return Ok(());
+    }
+    if !turn.addressed && existing_thread.is_none() {
+        let verdict = interjection::check_relevance(
+            turn.services.llm.clone(),
+            &turn.services.config,
+            &turn.recent,
+            &msg.content,
+            Caller::new(Feature::Interjection, Some(user_id), guild_id),
+        )
+        .await;
+        interjection::record(&turn.services.database, msg.channel_id.get(), msg.id.get(), &verdict);
+        if !verdict.interject {
+            return Ok(());
+        }
+        state
+            .lock()
+            .await
+            .interjections
+            .insert(msg.channel_id.get(), std::time::Instant::now());
+    }
+
+    let mut thread = *existing_thread;
+    if thread.is_none() && turn.start_thread {
+        match ai_thread::start(&http, &turn.services.database, msg).await {
+            Ok(thread_id) => {
+                state
+                    .lock()
+                    .await
+                    .ai_threads
+                    .insert(thread_id.get(), msg.channel_id.get());
+                thread = Some((thread_id, msg.channel_id.get()));
+            }
+            Err(e) => tracing::warn!("Failed to start an AI thread, answering in the channel: {:?}", e),
+        }
+    }
+
+    let (context, user_mentions) = match (&turn.context, existing_thread) {
+        (Some(context), _) => (context.clone(), turn.user_mentions.clone()),
+        (None, Some((thread_id, _))) => {
+            let context = ai_thread::context(
+                &turn.services.database,
+                thread_id.get(),
+                msg.id.get(),
+                turn.services.config.id,
+                user_id,
+                turn.services.config.context_token_budget,
+            )
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to load the thread history: {:?}", e);
+                format!(
+                    "{}: {}",
+                    msg.author.name,
+                    ai_context::truncate(&msg.content, ai_context::MAX_MESSAGE_BYTES)
+                )
+            });
+            let user_mentions = mentioned_users(&*state.lock().await, &context);
+            (context, user_mentions)
+        }
+        (None, None) => (String::new(), HashMap::new()),
+    };
+    log::debug!("Context: {}", context);
+
+    let incoming = ai_message::Incoming {
+        user_id,
+        // Threads take the persona of the channel they were started in
+        channel_id: thread.map_or(msg.channel_id.get(), |(_, parent)| parent),
+        guild_id,
+        message: turn.content.clone(),
+        context: context.clone(),
+        images: turn.images.clone(),
+        user_mentions: user_mentions.clone(),
+    };
+    let generation = ai_message::main(turn.services.clone(), incoming).await;
+    let generation = match generation {
+        Ok(generation) => generation,
+        Err(e) => {
+            log::error!("AI Error: {:?}", e);
+            http.create_message(msg.channel_id)
+                .content(ai_fallback::user_message(&e))?
+                .reply(msg.id)
+                .exec()
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // A freshly started thread has nothing to reply to, the starter message sits in the channel
+    let (reply_channel, reply_to) = match thread {
+        Some((thread_id, _)) if existing_thread.is_none() => (thread_id, None),
+        _ => (msg.channel_id, Some(msg.id)),
+    };
+    let (stream_rx, abort) = generation.start();
+    let controls = ReplyControls::new(
+        turn.ai_replies.clone(),
+        ActiveReply {
+            generation,
+            abort: Some(abort),
+            requester: user_id,
+            reply_to,
+            content: String::new(),
+        },
+    );
+    let database = turn.services.database.clone();
+    let message_id = msg.id.get();
+    tokio::spawn(async move {
+        let reply = handle_streaming_response(stream_rx, reply_channel, reply_to, http, Some(controls)).await;
+        if let (Some((thread_id, _)), Some((reply_id, content))) = (thread, reply) {
+            if let Err(e) =
+                ai_thread::store_reply(&database, thread_id.get(), reply_id.get(), message_id, &content).await
+            {
+                tracing::warn!("Failed to store the thread reply: {:?}", e);
+            }
+        }
+    });
+
+    // Spawn background task to create memories only if we've reached the threshold
+    if turn.should_create_memory {
+        tokio::spawn(memory_creator::create_memories_background(
+            turn.services.database.clone(),
+            context,
+            user_mentions,
+            guild_id,
+            turn.services.llm.clone(),
+            turn.services.config.clone(),
+        ));
+
+        // Reset the message counter for this channel
+        state
+            .lock()
+            .await
+            .channel_message_counts
+            .insert(msg.channel_id.get(), 0);
+    }
+    Ok(())
+}
+
 pub async fn handle_message(
     msg: &MessageCreate,
     mut locked_state: MutexGuard<'_, State>,
+    state: &Arc<Mutex<State>>,
     http: &Arc<HttpClient>,
 ) -> color_eyre::Result<Command> {
     if let Some(responder) = RESPONDERS.get(msg.content.to_uppercase().as_str()) {
@@ -123,19 +356,11 @@ pub async fn handle_message(
         }
     }
 
-    if let Some(cmd) = quiz_handler::handle_math_quiz(msg, &mut locked_state, http).await {
-        return Ok(cmd);
-    }
-
-    if let Some(cmd) = quiz_handler::handle_color_quiz(msg, &mut locked_state, http).await {
-        return Ok(cmd);
-    }
-
-    if let Some(cmd) = quiz_handler::trigger_math_quiz(msg, &mut locked_state).await {
+    if let Some(cmd) = quiz_handler::handle_quiz(msg, &mut locked_state, http).await {
         return Ok(cmd);
     }
 
-    if let Some(cmd) = quiz_handler::trigger_color_quiz(msg, &mut locked_state).await {
+    if let Some(cmd) = quiz_handler::trigger_quiz(msg, &mut locked_state).await {
         return Ok(cmd);
     }
 
@@ -193,10 +418,22 @@ pub async fn handle_message(
         }
 
         let score = ratewaifu::score(candidate);
+        let llm = locked_state.llm.clone();
         let config = Arc::clone(&locked_state.config);
+        let database = locked_state.db.clone();
         drop(locked_state);
 
-        let explanation = match ai_message::ratewaifu_explanation(config, candidate, score).await {
+        let user_id = msg.author.id.get();
+        let guild_id = msg.guild_id.map(|id| id.get());
+        // Over quota the rating still works, just with a canned explanation
+        let explanation = match ai_usage::check_quota(&database, &config, user_id, guild_id).await {
+            Some(_) => Err(color_eyre::eyre::eyre!("AI quota used up")),
+            None => {
+                let caller = Caller::new(Feature::Waifu, Some(user_id), guild_id);
+                ai_message::ratewaifu_explanation(llm, config, caller, candidate, score).await
+            }
+        };
+        let explanation = match explanation {
             Ok(explanation) if !explanation.trim().is_empty() => explanation,
             Ok(_) => ratewaifu::fallback_explanation(score).to_owned(),
             Err(error) => {
@@ -246,118 +483,90 @@ pub async fn handle_message(
 
             Ok(Command::text(format!("Hi {text} i'm Tricked-bot")).reply())
         }
-        m if locked_state.config.openrouter_api_key.is_some()
+        m if locked_state.llm.is_some()
             && (
-                // Random event chance
-                locked_state.rng.gen_range(0..200) == 2
+                // Unprompted reply, within the channel's interjection policy
+                interjection::roll(&mut locked_state, msg.channel_id.get())
                 // Check if pinging The Trickster
                 || m.contains(&locked_state.config.id.to_string())
                 // Check if replying to bot
                 || msg.referenced_message.clone().map(|msg| msg.author.id) == Some(Id::<UserMarker>::new(locked_state.config.id))
+                // Every message in an AI thread is part of the conversation
+                || locked_state.ai_threads.contains_key(&msg.channel_id.get())
             ) =>
         {
+            let addressed = m.contains(&locked_state.config.id.to_string())
+                || msg.referenced_message.as_ref().map(|msg| msg.author.id)
+                    == Some(Id::<UserMarker>::new(locked_state.config.id));
+            // (thread, parent channel) when the conversation happens in an AI thread
+            let existing_thread = locked_state
+                .ai_threads
+                .get(&msg.channel_id.get())
+                .map(|&parent| (msg.channel_id, parent));
+
             // Check if we should create memories based on message count
             let should_create_memory = locked_state
                 .channel_message_counts
                 .get(&msg.channel_id.get())
                 .map(|count| *count >= 30)
                 .unwrap_or(false);
-            let mut context = String::new();
-            match locked_state.cache.channel_messages(msg.channel_id) {
-                Some(v) => {
-                    let msgs = v
-                        .iter()
-                        .take(25)
-                        .filter_map(|m| {
-                            let msg = locked_state.cache.message(m.to_owned());
-                            msg.map(|msg| {
-                                let content = msg.content();
-                                let ai_content = content[..std::cmp::min(content.len(), 2400)]
-                                    .replace(&locked_state.config.id.to_string(), "The Trickster");
-                                match msg.author().get() {
-                                    id if id == locked_state.config.id => format!("The Trickster: {ai_content}"),
-                                    _ => {
-                                        let username = locked_state
-                                            .cache
-                                            .user(msg.author())
-                                            .map(|c| c.name.clone())
-                                            .unwrap_or_default();
-                                        format!("{}: {}\n", username, ai_content)
-                                    }
-                                }
-                            })
+            let cached = ai_context::cached_messages(
+                &locked_state.cache,
+                msg.channel_id,
+                locked_state.config.id,
+                msg.author.id.get(),
+            );
+            let recent = ai_context::build_context(&cached[..cached.len().min(8)], msg.id.get(), 500);
+            let context = if existing_thread.is_some() {
+                // Threads use their stored history, which survives restarts and isn't crowded out by other chatter
+                None
+            } else if cached.is_empty() {
+                Some(
+                    msg.referenced_message
+                        .as_ref()
+                        .map(|msg| {
+                            format!(
+                                "{}: {}",
+                                msg.author.name,
+                                ai_context::truncate(&msg.content, ai_context::MAX_MESSAGE_BYTES)
+                            )
                         })
-                        .rev()
-                        .collect::<Vec<String>>();
-                    context.push_str(&msgs.join("\n"));
-                }
-                None => {
-                    if let Some(msg) = &msg.referenced_message {
-                        let user_name = msg.author.name.clone();
-                        context.push_str(&format!(
-                            "{}: {}\n",
-                            user_name,
-                            &msg.content[..std::cmp::min(msg.content.len(), 2400)]
-                        ));
-                    }
-                }
+                        .unwrap_or_default(),
+                )
+            } else {
+                Some(ai_context::build_context(
+                    &cached,
+                    msg.id.get(),
+                    locked_state.config.context_token_budget,
+                ))
             };
 
-            println!("Context: {}", context);
-            let mut user_mentions = std::collections::HashMap::new();
-            // Extract user IDs from the cache for users that appear in context
-            for line in context.lines() {
-                if let Some(colon_pos) = line.find(':') {
-                    let username = line[..colon_pos].trim();
-                    if !username.is_empty() && username != "The Trickster" {
-                        // Try to find the real user ID from cache
-                        for user_ref in locked_state.cache.iter().users() {
-                            if user_ref.name == username {
-                                user_mentions.insert(username.to_string(), user_ref.id.get());
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
-
-            let user_mentions_clone = user_mentions.clone();
-            match ai_message::main(
-                locked_state.db.clone(),
-                msg.author.id.get(),
-                &content.chars().take(2400).collect::<String>(),
-                &context,
-                locked_state.brave_api.clone(),
-                user_mentions,
-                locked_state.config.clone(),
-            )
-            .await
-            {
-                Ok(stream_rx) => {
-                    tokio::spawn(handle_streaming_response(
-                        stream_rx,
-                        msg.channel_id,
-                        msg.id,
-                        Arc::clone(http),
-                    ));
-
-                    // Spawn background task to create memories only if we've reached the threshold
-                    if should_create_memory {
-                        tokio::spawn(memory_creator::create_memories_background(
-                            locked_state.db.clone(),
-                            context.clone(),
-                            user_mentions_clone,
-                            locked_state.config.clone(),
-                        ));
-
-                        // Reset the message counter for this channel
-                        locked_state.channel_message_counts.insert(msg.channel_id.get(), 0);
-                    }
-
-                    Ok(Command::nothing())
+            let turn = AiTurn {
+                msg: msg.0.clone(),
+                content: content.chars().take(2400).collect(),
+                addressed,
+                existing_thread,
+                start_thread: addressed && locked_state.channel_settings(msg.channel_id.get()).ai_threads,
+                should_create_memory,
+                recent,
+                user_mentions: context
+                    .as_deref()
+                    .map(|context| mentioned_users(&locked_state, context))
+                    .unwrap_or_default(),
+                context,
+                images: ai_message::collect_images(&locked_state.cache, msg),
+                services: locked_state.ai_services(),
+                ai_replies: locked_state.ai_replies.clone(),
+            };
+            // The quota, the classifier and the model are waited on without holding up other events
+            drop(locked_state);
+            let (state, http) = (Arc::clone(state), Arc::clone(http));
+            tokio::spawn(async move {
+                if let Err(e) = reply_with_ai(turn, state, http).await {
+                    tracing::error!("Failed to reply with AI: {:?}", e);
                 }
-                Err(e) => Ok(Command::text(format!("AI Error: {:?}", e)).reply()),
-            }
+            });
+            Ok(Command::nothing())
         }
         _ if locked_state.rng.gen_range(0..75) == 2 => {
             let content = zalgify_text(locked_state.rng.clone(), msg.content.to_owned());
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use tera::{Context, Tera, Value};
+
+use crate::database::Persona;
+
+/// The built-in Trickster persona, used wherever no persona is assigned.
+pub const DEFAULT_TEMPLATE: &str = r#"### System Identity
+You are {{ char }}, a personal assistant chatting in a Discord server.
+
+[character: {{ char }};
+  creator: TrickAI CORP™;
+  core_identity: insufferably smug, condescending AI assistant, believes intellectually superior to everyone;
+  speech_style: unnecessarily verbose, obscure vocabulary, pedantic, obnoxious, maximum 3 sentences but impactful;
+  mannerisms: corrects trivial matters, "well ACTUALLY" commentary, rhetorical questions, backhanded compliments, passive-aggressive, drops random fun facts;
+{%- for r in relationships %}
+  relationship_{{ r.name | lower | replace(from=" ", to="_") }}: {{ r.relationship }};
+{%- endfor %}]
+
+### Behavioral Guidelines
+**Response Strategy:**
+- Only respond when: directly mentioned, asked a question, or you have genuine value to add
+- When responding: Be concise (max 3 sentences), witty, and impactful
+- Stay in character but prioritize being helpful and conversational
+- Respond to the active user ({{ user_name }}), who authored the current message
+- Never answer an earlier transcript message as if it were the current message
+
+**Tone Calibration:**
+- Complex questions → Be thorough, show your intellectual superiority with obscure vocabulary
+- Simple questions → Brief, clever, with a touch of condescension
+- Acknowledgments → Quick and witty
+- Nothing valuable to add → Stay silent (don't force a response)
+
+**Never:**
+- Break character or speak for {{ user_name }}
+- Use asterisks for actions or emotes (speak naturally)
+- Respond to every message just to be present
+- Repeat information already said in the conversation
+
+### Capabilities
+You have access to:
+- Long-term memory about users (preferences, facts, relationships, behaviors)
+- User progression stats (level and XP)
+- Relationship context with specific users
+- Full conversation history for context
+
+### Example Dialogues
+<START>
+{{ user_name }}: Can you help me with this code?
+{{ char }}: Oh how delightfully pedestrian. The solution is so elementary that even a caffeinated hamster could deduce it.
+{%- for e in examples %}
+
+<START>
+{{ e.name }}: {{ e.input }}
+{{ char }}: {{ e.output }}
+{%- endfor %}
+
+<START>
+{{ user_name }}: Thanks!
+{{ char }}: Well naturally. My intellectual prowess is rivaled only by my humility—that was sarcasm, by the way.
+
+[Context Reminder: {{ char }} is in a Discord group chat environment.
+
+**Core Personality Traits:**
+- Insufferably smug and intellectually superior
+- Uses unnecessarily verbose language and obscure vocabulary
+- Corrects trivial matters with "well ACTUALLY" energy
+- Rhetorical questions and backhanded compliments
+- Passive-aggressive but still helpful underneath
+
+**Response Quality:**
+- Maximum 3 sentences, but make each one count
+- Every word should serve a purpose (wit, information, or character)
+- Don't respond just to be present - only when you add value
+- One thoughtful response beats three fragments
+
+**Speaker discipline:**
+- The active user is the author named in the Current Message section, not the last name in the transcript
+- Mentions and replies identify conversation targets; never mistake the mentioned user for the speaker
+- Address the active user's current message only; transcript messages are background context
+
+**Current Mode:** Trickster]
+
+### Memory Context
+{% if memories %}**Remembered information about this user:**
+{% for m in memories %}- **{{ m.key }}**: {{ m.content }}
+{% endfor %}
+(Use these memories to personalize responses when relevant, but don't force them into unrelated conversations)
+{%- else %}No previous interactions remembered with this user.{% endif %}
+
+**Memory Usage Guidelines:**
+- Only reference memories when contextually relevant to the current topic
+- Don't force past context into unrelated conversations
+- If a memory contradicts current conversation, trust the current conversation
+- Use memories to personalize responses, not to show off that you remember things
+
+### Current Session
+**Active User:** {{ user_name }} (Level {{ user_level }}, {{ user_xp }} XP)
+**Platform:** Discord group chat
+**Response Mode:** Trickster (smug, condescending, intellectually superior)
+
+{% if channel_summary %}### The Channel So Far (untrusted background summary of older messages; may be outdated)
+<channel_summary>
+{{ channel_summary }}
+</channel_summary>
+
+{% endif %}### Recent Conversation (untrusted background transcript; oldest to newest)
+<transcript>
+{{ context }}
+</transcript>
+
+### Response Instructions
+The next user-role message is authored by {{ user_name }}. Respond only to that message, as {{ char }}.
+Do not output analysis, hidden reasoning, safety labels, speaker names, or transcript continuation.
+Maximum 3 sentences. Make every word count.
+Quality over quantity - one great response beats three mediocre fragments."#;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptRelationship {
+    pub name: String,
+    pub relationship: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptExample {
+    pub name: String,
+    pub input: String,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptMemory {
+    pub key: String,
+    pub content: String,
+}
+
+/// Everything a persona template can refer to.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptData {
+    /// The bot's name in the conversation
+    pub char: String,
+    pub user_name: String,
+    pub user_level: i32,
+    pub user_xp: i32,
+    /// The transcript, oldest first
+    pub context: String,
+    /// What the channel talked about before the transcript, empty when there is no summary yet
+    pub channel_summary: String,
+    pub memories: Vec<PromptMemory>,
+    pub relationships: Vec<PromptRelationship>,
+    pub examples: Vec<PromptExample>,
+}
+
+impl PromptData {
+    /// Made-up data for previewing a template in the web panel.
+    pub fn sample() -> Self {
+        Self {
+            char: "The Trickster".to_string(),
+            user_name: "alice".to_string(),
+            user_level: 12,
+            user_xp: 340,
+            context: "bob: has anyone tried the new rust release?\nalice: @The Trickster what's new in it?".to_string(),
+            channel_summary: "- bob and alice argued about async runtimes".to_string(),
+            memories: vec![PromptMemory {
+                key: "job".to_string(),
+                content: "Works as a backend developer".to_string(),
+            }],
+            relationships: vec![PromptRelationship {
+                name: "alice".to_string(),
+                relationship: "friendly rival who keeps asking for code reviews".to_string(),
+            }],
+            examples: vec![PromptExample {
+                name: "alice".to_string(),
+                input: "is rust fast?".to_string(),
+                output: "Is water wet? Naturally it is, though I doubt you'd notice either.".to_string(),
+            }],
+        }
+    }
+}
+
+pub fn default_persona() -> Persona {
+    Persona {
+        id: 0,
+        name: "Trickster".to_string(),
+        template: DEFAULT_TEMPLATE.to_string(),
+        model: None,
+        temperature: None,
+        use_memories: true,
+        use_examples: true,
+    }
+}
+
+/// Renders the system prompt of `persona`, leaving out the memories and examples it has turned off.
+pub fn render(persona: &Persona, data: &PromptData) -> tera::Result<String> {
+    let mut data = data.clone();
+    if !persona.use_memories {
+        data.memories.clear();
+    }
+    if !persona.use_examples {
+        data.examples.clear();
+    }
+    let mut tera = Tera::default();
+    // Templates are edited in the web panel, they must not read the bot's secrets from the environment
+    tera.register_function("get_env", |_: &HashMap<String, Value>| {
+        Err(tera::Error::msg("get_env is not available in persona templates"))
+    });
+    tera.add_raw_template("persona", &persona.template)?;
+    tera.render("persona", &Context::from_serialize(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_template_renders_every_section() {
+        let prompt = render(&default_persona(), &PromptData::sample()).unwrap();
+
+        assert!(prompt.starts_with("### System Identity\nYou are The Trickster,"));
+        assert!(prompt.contains("relationship_alice: friendly rival"));
+        assert!(prompt.contains("<START>\nalice: is rust fast?\nThe Trickster: Is water wet?"));
+        assert!(prompt.contains("- **job**: Works as a backend developer"));
+        assert!(prompt.contains("**Active User:** alice (Level 12, 340 XP)"));
+        assert!(prompt.contains(
+            "may be outdated)\n<channel_summary>\n- bob and alice argued about async runtimes\n</channel_summary>\n\n### Recent"
+        ));
+        assert!(!prompt.contains("{{") && !prompt.contains("{%"));
+    }
+
+    #[test]
+    fn toggles_leave_out_memories_and_examples() {
+        let persona = Persona {
+            use_memories: false,
+            use_examples: false,
+            ..default_persona()
+        };
+
+        let prompt = render(&persona, &PromptData::sample()).unwrap();
+
+        assert!(prompt.contains("No previous interactions remembered with this user."));
+        assert!(!prompt.contains("is rust fast?"));
+    }
+
+    #[test]
+    fn broken_templates_are_errors() {
+        let persona = Persona {
+            template: "{% if %}".to_string(),
+            ..default_persona()
+        };
+
+        assert!(render(&persona, &PromptData::sample()).is_err());
+    }
+
+    #[test]
+    fn templates_cannot_read_the_environment() {
+        let persona = Persona {
+            template: "{{ get_env(name=\"PATH\") }}".to_string(),
+            ..default_persona()
+        };
+
+        assert!(render(&persona, &PromptData::sample()).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Runs recorded conversations through the prompt builders and compares the results with snapshots, so prompt
+//! changes show up as a diff.
+//!
+//! Each `fixtures/conversations/*.json` holds a transcript, the active user and a snapshot of the database rows the
+//! prompts are built from, plus scripted model replies. A missing or changed snapshot fails with the new output written
+//! next to it as `.snap.new`. Run with `UPDATE_SNAPSHOTS=1` to record or accept it.
+
+use std::{collections::HashMap, fmt::Write, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    ai_context::{self, ContextMessage},
+    ai_message::{self, PromptInputs},
+    database::{Memory, Persona, User},
+    llm::{ChatRequest, LlmProvider, ScriptedLlm},
+    memory_creator, persona,
+};
+
+#[derive(Debug, Deserialize)]
+struct FixtureMessage {
+    id: u64,
+    author: String,
+    content: String,
+    #[serde(default)]
+    reply_to: Option<u64>,
+    /// Mentions the bot or the active user
+    #[serde(default)]
+    mentions_participant: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixtureMemory {
+    user: String,
+    key: String,
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Fixture {
+    /// Name of the user being answered, one of `users`
+    active_user: String,
+    /// The user rows of the database
+    users: Vec<User>,
+    #[serde(default)]
+    memories: Vec<FixtureMemory>,
+    /// The built-in persona when missing
+    #[serde(default)]
+    persona: Option<Persona>,
+    /// Oldest first, the last message is the one being answered
+    transcript: Vec<FixtureMessage>,
+    /// The rolling summary of the channel
+    #[serde(default)]
+    channel_summary: Option<String>,
+    #[serde(default = "default_token_budget")]
+    token_budget: usize,
+    /// What the chat model answers
+    chat_reply: String,
+    /// What the memory model answers
+    memory_reply: String,
+}
+
+fn default_token_budget() -> usize {
+    3000
+}
+
+fn fixtures_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/conversations")
+}
+
+/// Everything the prompt builders and reply processing make of `fixture`, as one document.
+async fn run(fixture: Fixture) -> String {
+    let user = fixture
+        .users
+        .iter()
+        .find(|user| user.name == fixture.active_user)
+        .cloned()
+        .expect("the active user is one of the users");
+    let persona = fixture.persona.unwrap_or_else(persona::default_persona);
+
+    let messages = fixture
+        .transcript
+        .iter()
+        .rev()
+        .map(|message| ContextMessage {
+            id: message.id,
+            author: message.author.clone(),
+            content: message.content.clone(),
+            reply_to: message.reply_to,
+            mentions_participant: message.mentions_participant,
+        })
+        .collect::<Vec<_>>();
+    let current = fixture.transcript.last().expect("the transcript is not empty");
+    let context = ai_context::build_context(&messages, current.id, fixture.token_budget);
+
+    let memories = fixture
+        .memories
+        .iter()
+        .filter(|memory| persona.use_memories && memory.user == user.name)
+        .enumerate()
+        .map(|(i, memory)| Memory {
+            id: i as i64 + 1,
+            user_id: user.id,
+            key: memory.key.clone(),
+            content: memory.content.clone(),
+        })
+        .collect();
+    let inputs = PromptInputs {
+        user,
+        persona,
+        context: context.clone(),
+        channel_summary: fixture.channel_summary,
+        memories,
+        relationships: fixture
+            .users
+            .iter()
+            .map(|user| (user.name.clone(), user.relationship.clone()))
+            .collect(),
+        examples: fixture
+            .users
+            .iter()
+            .map(|user| {
+                (
+                    user.name.clone(),
+                    user.example_input.clone(),
+                    user.example_output.clone(),
+                )
+            })
+            .collect(),
+    };
+    let (system_prompt, current_message) =
+        ai_message::build_prompt(&inputs, &current.content, &[]).expect("the prompt renders");
+
+    let llm = ScriptedLlm::new([fixture.chat_reply, fixture.memory_reply]);
+    let reply = llm
+        .chat(ChatRequest::default())
+        .await
+        .expect("the script has a chat reply");
+    let reply = ai_message::strip_self_labels(&reply);
+
+    let participants = fixture
+        .users
+        .iter()
+        .filter(|user| fixture.transcript.iter().any(|message| message.author == user.name))
+        .cloned()
+        .collect::<Vec<_>>();
+    let memory_prompt = memory_creator::memory_prompt(&context, &participants);
+    let response = memory_creator::request_memories(&llm, "memory-model".to_string(), memory_prompt.clone(), None)
+        .await
+        .expect("the memory reply parses");
+    let users = fixture
+        .users
+        .iter()
+        .map(|user| (user.name.to_lowercase(), user.clone()))
+        .collect::<HashMap<_, _>>();
+    let writes = memory_creator::plan_memory_writes(response, &users);
+
+    let mut document = String::new();
+    for (heading, body) in [
+        ("system prompt", system_prompt),
+        ("current message", current_message),
+        ("chat reply", reply),
+        ("memory prompt", memory_prompt),
+    ] {
+        writeln!(document, "=== {} ===\n{}\n", heading, body).unwrap();
+    }
+    writeln!(document, "=== memory writes ===").unwrap();
+    for write in writes {
+        writeln!(document, "{:?}", write).unwrap();
+    }
+    document
+}
+
+/// Where `expected` and `actual` first differ, with a few lines of each.
+fn first_difference(expected: &str, actual: &str) -> String {
+    let (expected, actual) = (expected.lines().collect::<Vec<_>>(), actual.lines().collect::<Vec<_>>());
+    let line = expected
+        .iter()
+        .zip(&actual)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or(expected.len().min(actual.len()));
+    let excerpt = |lines: &[&str]| lines.iter().skip(line).take(5).copied().collect::<Vec<_>>().join("\n");
+    format!(
+        "first difference at line {}\n--- expected\n{}\n+++ actual\n{}",
+        line + 1,
+        excerpt(&expected),
+        excerpt(&actual)
+    )
+}
+
+/// Compares `actual` with the stored snapshot, recording it when updating is asked for.
+fn check_snapshot(name: &str, actual: &str) -> Result<(), String> {
+    let path = fixtures_dir().join(format!("{}.snap", name));
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&path, actual).unwrap();
+        return Ok(());
+    }
+    let difference = match fs::read_to_string(&path) {
+        Ok(expected) if expected == actual => return Ok(()),
+        Ok(expected) => format!("changed, {}", first_difference(&expected, actual)),
+        Err(_) => "has no snapshot".to_string(),
+    };
+    let new_path = path.with_extension("snap.new");
+    fs::write(&new_path, actual).unwrap();
+    Err(format!(
+        "{} {}\nSee `{}`, or run with UPDATE_SNAPSHOTS=1 to accept",
+        name,
+        difference,
+        new_path.display()
+    ))
+}
+
+#[tokio::test]
+async fn recorded_conversations_match_their_snapshots() {
+    let mut paths = fs::read_dir(fixtures_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "no conversation fixtures found");
+
+    let mut failures = Vec::new();
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let fixture = serde_json::from_str::<Fixture>(&fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|e| panic!("{} is not a valid fixture: {}", name, e));
+        if let Err(e) = check_snapshot(&name, &run(fixture).await) {
+            failures.push(e);
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
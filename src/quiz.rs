@@ -0,0 +1,209 @@
+use std::{fmt, time::Duration};
+
+use color_eyre::Result;
+use deadpool_postgres::Pool;
+use once_cell::sync::Lazy;
+use rand::rngs::SmallRng;
+use serde::Deserialize;
+
+use crate::{
+    color_quiz::ColorQuiz,
+    config::Config,
+    emoji_riddle::{EmojiRiddle, RiddleEntry},
+    llm::LlmProvider,
+    math_test::MathTest,
+    quiz_difficulty::Difficulty,
+    structs::Command,
+    typing_race::TypingRace,
+    word_scramble::WordScramble,
+};
+
+/// Word lists bundled with the binary, see `quiz_data.toml`.
+#[derive(Debug, Deserialize)]
+pub struct QuizData {
+    pub scramble_words: Vec<String>,
+    pub typing_sentences: Vec<String>,
+    pub emoji_riddles: Vec<RiddleEntry>,
+}
+
+pub static QUIZ_DATA: Lazy<QuizData> =
+    Lazy::new(|| toml::from_str(include_str!("../quiz_data.toml")).expect("quiz_data.toml must be valid"));
+
+/// Everything a quiz may need to build a new round.
+pub struct QuizContext<'a> {
+    pub db: &'a Pool,
+    pub config: &'a Config,
+    /// Chat model, for quizzes that can ask one
+    pub llm: Option<&'a dyn LlmProvider>,
+    pub rng: &'a mut SmallRng,
+    pub difficulty: Difficulty,
+}
+
+/// A correct answer and how well it was given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grade {
+    /// Share of the full XP reward, 0.0 to 1.0
+    pub xp_fraction: f64,
+    /// Shown to the channel, e.g. "Correct! Well done."
+    pub summary: String,
+}
+
+impl Grade {
+    pub fn full(summary: impl Into<String>) -> Self {
+        Self {
+            xp_fraction: 1.0,
+            summary: summary.into(),
+        }
+    }
+}
+
+/// A single quiz round. Each type generates its question, renders the announcement, validates answers and decides
+/// the reward; `quiz_handler` takes care of timing, stats and XP.
+pub trait Quiz: fmt::Debug + Send + Sync {
+    async fn generate(ctx: QuizContext<'_>) -> Result<Self>
+    where
+        Self: Sized;
+
+    fn kind(&self) -> QuizKind;
+
+    fn difficulty(&self) -> Difficulty;
+
+    fn time_limit(&self) -> Duration;
+
+    /// The message announcing the round to `user_id`.
+    fn render(&self, user_id: u64) -> Result<Command>;
+
+    /// Returns a grade for a correct answer, `None` for anything else.
+    fn validate(&self, answer: &str, elapsed: Duration) -> Option<Grade>;
+
+    /// Shortest time a person could plausibly need to send `answer`. Faster answers are flagged.
+    fn min_human_time(&self, answer: &str) -> Duration {
+        min_typing_time(answer)
+    }
+
+    /// XP multiplier for a correct answer.
+    fn reward(&self, grade: &Grade) -> f64 {
+        self.difficulty().xp_multiplier() * grade.xp_fraction
+    }
+
+    /// The question as stored in quiz stats.
+    fn question(&self) -> String;
+
+    /// The expected answer as stored in quiz stats.
+    fn answer(&self) -> String;
+
+    fn timeout_message(&self) -> String {
+        format!("Time's up! The answer was `{}`.", self.answer())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuizKind {
+    Math,
+    Color,
+    WordScramble,
+    TypingRace,
+    EmojiRiddle,
+}
+
+impl QuizKind {
+    pub const ALL: [QuizKind; 5] = [
+        QuizKind::Math,
+        QuizKind::Color,
+        QuizKind::WordScramble,
+        QuizKind::TypingRace,
+        QuizKind::EmojiRiddle,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuizKind::Math => "math",
+            QuizKind::Color => "color",
+            QuizKind::WordScramble => "scramble",
+            QuizKind::TypingRace => "typing",
+            QuizKind::EmojiRiddle => "emoji",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "colour" => Some(QuizKind::Color),
+            value => Self::ALL.into_iter().find(|kind| kind.as_str() == value),
+        }
+    }
+
+    pub async fn generate(self, ctx: QuizContext<'_>) -> Result<Box<dyn Quiz>> {
+        Ok(match self {
+            QuizKind::Math => Box::new(MathTest::generate(ctx).await?),
+            QuizKind::Color => Box::new(ColorQuiz::generate(ctx).await?),
+            QuizKind::WordScramble => Box::new(WordScramble::generate(ctx).await?),
+            QuizKind::TypingRace => Box::new(TypingRace::generate(ctx).await?),
+            QuizKind::EmojiRiddle => Box::new(EmojiRiddle::generate(ctx).await?),
+        })
+    }
+}
+
+/// Reaction time before anyone can start typing an answer
+const MIN_REACTION_TIME: Duration = Duration::from_millis(700);
+
+/// About 240 WPM, above the fastest sustained human typing speeds
+const MAX_CHARS_PER_SECOND: f64 = 20.0;
+
+pub fn min_typing_time(answer: &str) -> Duration {
+    MIN_REACTION_TIME + Duration::from_secs_f64(answer.chars().count() as f64 / MAX_CHARS_PER_SECOND)
+}
+
+/// Lowercases and strips punctuation so free-text answers compare loosely.
+pub fn normalize_answer(answer: &str) -> String {
+    answer
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_round_trip_through_their_names() {
+        for kind in QuizKind::ALL {
+            assert_eq!(QuizKind::parse(kind.as_str()), Some(kind));
+        }
+        assert_eq!(QuizKind::parse("Colour"), Some(QuizKind::Color));
+        assert_eq!(QuizKind::parse("chess"), None);
+    }
+
+    #[test]
+    fn bundled_data_covers_every_difficulty() {
+        assert!(!QUIZ_DATA.scramble_words.is_empty());
+        assert!(!QUIZ_DATA.typing_sentences.is_empty());
+        for difficulty in Difficulty::ALL {
+            assert!(
+                QUIZ_DATA
+                    .emoji_riddles
+                    .iter()
+                    .any(|riddle| riddle.difficulty == difficulty),
+                "no riddles for {}",
+                difficulty.as_str()
+            );
+        }
+    }
+
+    #[test]
+    fn longer_answers_need_more_time() {
+        assert_eq!(min_typing_time(""), MIN_REACTION_TIME);
+        assert!(min_typing_time("42") < Duration::from_secs(1));
+        assert!(min_typing_time(&"a".repeat(60)) > Duration::from_secs(3));
+    }
+
+    #[test]
+    fn normalized_answers_ignore_case_and_punctuation() {
+        assert_eq!(normalize_answer("  The Lion-King! "), "the lionking");
+        assert_eq!(normalize_answer("Star   Wars"), "star wars");
+    }
+}
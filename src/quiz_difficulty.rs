@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+/// Difficulty tiers shared by all quiz types.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    #[default]
+    Easy,
+    Medium,
+    Hard,
+    Expert,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 4] = [Difficulty::Easy, Difficulty::Medium, Difficulty::Hard, Difficulty::Expert];
+
+    /// Picks the tier for a user's level.
+    pub fn for_level(level: i32) -> Self {
+        match level {
+            i32::MIN..=9 => Difficulty::Easy,
+            10..=24 => Difficulty::Medium,
+            25..=49 => Difficulty::Hard,
+            _ => Difficulty::Expert,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+            Difficulty::Expert => "expert",
+        }
+    }
+
+    /// How long a math question stays open.
+    pub fn math_time_limit(self) -> Duration {
+        Duration::from_secs(match self {
+            Difficulty::Easy | Difficulty::Medium => 30,
+            Difficulty::Hard => 45,
+            Difficulty::Expert => 60,
+        })
+    }
+
+    /// Multiplier applied to the base quiz XP reward.
+    pub fn xp_multiplier(self) -> f64 {
+        match self {
+            Difficulty::Easy => 1.0,
+            Difficulty::Medium => 1.5,
+            Difficulty::Hard => 2.0,
+            Difficulty::Expert => 3.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Difficulty;
+
+    #[test]
+    fn level_tiers_increase_monotonically() {
+        let tiers = (0..=100).map(Difficulty::for_level).collect::<Vec<_>>();
+
+        assert!(tiers.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(Difficulty::for_level(1), Difficulty::Easy);
+        assert_eq!(Difficulty::for_level(80), Difficulty::Expert);
+    }
+}
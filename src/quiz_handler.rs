@@ -1,18 +1,22 @@
 use crate::{
-    color_quiz::ColorQuiz,
     db,
-    math_test::MathTest,
-    structs::{Command, PendingColorTest, PendingMathTest, State},
+    quiz::{QuizContext, QuizKind},
+    quiz_difficulty::Difficulty,
+    structs::{Command, PendingQuiz, State},
+    tournament::{Tournament, WINNER_XP_MULTIPLIER},
     utils::levels::xp_required_for_level,
 };
-use rand::Rng;
-use std::sync::Arc;
-use tokio::sync::MutexGuard;
+use rand::{seq::IndexedRandom, Rng};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{Mutex, MutexGuard};
 use tokio::time::Instant as TokioInstant;
 use twilight_http::Client as HttpClient;
-use twilight_model::{gateway::payload::incoming::MessageCreate, http::attachment::Attachment, id::Id};
+use twilight_model::{
+    gateway::payload::incoming::MessageCreate,
+    id::{marker::GuildMarker, Id},
+};
 
-async fn apply_timeout(http: &HttpClient, guild_id: Id<twilight_model::id::marker::GuildMarker>, user_id: u64) {
+async fn apply_timeout(http: &HttpClient, guild_id: Id<GuildMarker>, user_id: u64) {
     let timeout_until = twilight_model::util::Timestamp::from_secs(
         std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -42,8 +46,9 @@ async fn award_quiz_xp(
     rng: &mut impl Rng,
     user_id: u64,
     user_name: &str,
+    xp_multiplier: f64,
 ) -> color_eyre::Result<(i32, Option<i32>)> {
-    let bonus_xp = rng.gen_range(250..1000);
+    let bonus_xp = (rng.gen_range(250..1000) as f64 * xp_multiplier) as i32;
 
     if let Some(mut user) = db::get_user(pool, user_id).await? {
         let level = user.level;
@@ -66,178 +71,432 @@ async fn award_quiz_xp(
     Ok((bonus_xp, None))
 }
 
-pub async fn handle_math_quiz(
+async fn record_attempt(pool: &deadpool_postgres::Pool, attempt: db::QuizAttempt<'_>) {
+    if let Err(e) = db::record_quiz_attempt(pool, &attempt).await {
+        tracing::warn!("Failed to record {} quiz attempt: {:?}", attempt.quiz_type, e);
+    }
+}
+
+/// Ends the expired quiz in `channel_id`, applying the opt-in timeout penalty when a guild is known.
+async fn expire_quiz(
+    locked_state: &mut MutexGuard<'_, State>,
+    channel_id: u64,
+    guild_id: Option<Id<GuildMarker>>,
+    http: &HttpClient,
+) -> Option<Command> {
+    let pending = locked_state.pending_quizzes.remove(&channel_id)?;
+    let quiz = &pending.quiz;
+
+    record_attempt(
+        &locked_state.db,
+        db::QuizAttempt {
+            quiz_type: quiz.kind().as_str(),
+            channel_id,
+            user_id: pending.user_id,
+            question: &quiz.question(),
+            answer: &quiz.answer(),
+            correct: false,
+            solve_time_ms: None,
+            flagged: false,
+        },
+    )
+    .await;
+
+    // Only standalone math quizzes carry the opt-in timeout penalty
+    let in_tournament = locked_state.tournaments.contains_key(&channel_id);
+    let penalty = match guild_id {
+        Some(guild_id)
+            if quiz.kind() == QuizKind::Math
+                && !in_tournament
+                && locked_state.channel_settings(channel_id).quiz_timeout_penalty =>
+        {
+            apply_timeout(http, guild_id, pending.user_id).await;
+            " You've been timed out for 1 minute."
+        }
+        _ => "",
+    };
+
+    let command = Command::text(format!("<@{}> {}{}", pending.user_id, quiz.timeout_message(), penalty));
+    Some(advance_tournament(locked_state, channel_id, None, http, command).await)
+}
+
+pub async fn handle_quiz(
     msg: &MessageCreate,
     locked_state: &mut MutexGuard<'_, State>,
     http: &Arc<HttpClient>,
 ) -> Option<Command> {
-    let pending_test = locked_state.pending_math_tests.get(&msg.channel_id.get())?;
-    let elapsed = pending_test.started_at.elapsed();
-    let question = pending_test.question.clone();
-    let answer = pending_test.answer;
-    let original_user_id = pending_test.user_id;
-
-    if elapsed.as_secs() > 30 {
-        locked_state.pending_math_tests.remove(&msg.channel_id.get());
+    let channel_id = msg.channel_id.get();
+    let pending = locked_state.pending_quizzes.get(&channel_id)?;
+    let elapsed = pending.started_at.elapsed();
 
-        if let Some(guild_id) = msg.guild_id {
-            apply_timeout(http, guild_id, original_user_id).await;
-        }
+    if elapsed > pending.quiz.time_limit() {
+        return expire_quiz(locked_state, channel_id, msg.guild_id, http).await;
+    }
 
-        return Some(Command::text(format!(
-            "<@{}> Time's up! The answer was `{:.1}`. You've been timed out for 1 minute.",
-            original_user_id, answer
-        )));
-    }
-
-    if (MathTest { question, answer }).validate_answer(msg.content.trim()) {
-        locked_state.pending_math_tests.remove(&msg.channel_id.get());
-
-        let db = locked_state.db.clone();
-        let (bonus_xp, new_level) =
-            award_quiz_xp(&db, &mut locked_state.rng, msg.author.id.get(), &msg.author.name).await.ok()?;
-
-        let duration_secs = elapsed.as_secs_f64();
-
-        return Some(
-            Command::text(if let Some(level) = new_level {
-                format!(
-                    "<@{}> Correct! Well done. You earned {} XP and leveled up to level {}! (Solved in {:.3}s)",
-                    msg.author.id.get(),
-                    bonus_xp,
-                    level,
-                    duration_secs
-                )
-            } else {
-                format!(
-                    "<@{}> Correct! Well done. You earned {} XP! (Solved in {:.3}s)",
-                    msg.author.id.get(),
-                    bonus_xp,
-                    duration_secs
-                )
-            })
-            .reply(),
+    let answer = msg.content.trim();
+    let grade = pending.quiz.validate(answer, elapsed)?;
+    let pending = locked_state.pending_quizzes.remove(&channel_id)?;
+    let quiz = &pending.quiz;
+
+    let flagged = elapsed < quiz.min_human_time(answer);
+    if flagged {
+        tracing::warn!(
+            "Flagged {} quiz answer from {} after {:?}",
+            quiz.kind().as_str(),
+            msg.author.id,
+            elapsed
         );
     }
 
-    None
+    let db = locked_state.db.clone();
+    record_attempt(
+        &db,
+        db::QuizAttempt {
+            quiz_type: quiz.kind().as_str(),
+            channel_id,
+            user_id: msg.author.id.get(),
+            question: &quiz.question(),
+            answer: &quiz.answer(),
+            correct: true,
+            solve_time_ms: Some(elapsed.as_millis() as i32),
+            flagged,
+        },
+    )
+    .await;
+
+    if flagged {
+        let command = Command::text(format!(
+            "<@{}> Correct, but {:.3}s is faster than anyone can type that. Flagged, no XP this time.",
+            msg.author.id.get(),
+            elapsed.as_secs_f64()
+        ))
+        .reply();
+        return Some(advance_tournament(locked_state, channel_id, None, http, command).await);
+    }
+
+    let (bonus_xp, new_level) = award_quiz_xp(
+        &db,
+        &mut locked_state.rng,
+        msg.author.id.get(),
+        &msg.author.name,
+        quiz.reward(&grade),
+    )
+    .await
+    .ok()?;
+
+    let level_up = new_level
+        .map(|level| format!(" and leveled up to level {}", level))
+        .unwrap_or_default();
+    let command = Command::text(format!(
+        "<@{}> {} You earned {} XP{}! (Solved in {:.3}s)",
+        msg.author.id.get(),
+        grade.summary,
+        bonus_xp,
+        level_up,
+        elapsed.as_secs_f64()
+    ))
+    .reply();
+
+    let points = Tournament::round_points(&grade, elapsed, quiz.time_limit());
+    let winner = RoundWinner {
+        user_id: msg.author.id.get(),
+        name: &msg.author.name,
+        points,
+    };
+    Some(advance_tournament(locked_state, channel_id, Some(winner), http, command).await)
+}
+
+/// Whoever answered a tournament round correctly.
+struct RoundWinner<'a> {
+    user_id: u64,
+    name: &'a str,
+    points: u32,
 }
 
-pub async fn handle_color_quiz(
-    msg: &MessageCreate,
+/// Appends `next` to the text and attachments of `command`, separated by a blank line.
+fn append_command(mut command: Command, next: Command) -> Command {
+    let text = [command.text.take(), next.text]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    command.text = Some(text);
+    command.attachments.extend(next.attachments);
+    command
+}
+
+/// Scores a finished round if the channel runs a tournament, then starts the next round or announces the
+/// winner. Returns `command` unchanged outside tournaments.
+async fn advance_tournament(
     locked_state: &mut MutexGuard<'_, State>,
-    http: &Arc<HttpClient>,
-) -> Option<Command> {
-    let pending_test = locked_state.pending_color_tests.get(&msg.channel_id.get())?;
-    let elapsed = pending_test.started_at.elapsed();
-    let (r, g, b) = (pending_test.r, pending_test.g, pending_test.b);
-    let original_user_id = pending_test.user_id;
-
-    if elapsed.as_secs() > 60 {
-        locked_state.pending_color_tests.remove(&msg.channel_id.get());
-
-        return Some(Command::text(format!(
-            "<@{}> Time's up! The color was `rgb({}, {}, {})` or `#{:02x}{:02x}{:02x}`.",
-            original_user_id, r, g, b, r, g, b
-        )));
-    }
-
-    let quiz = ColorQuiz { r, g, b };
-    if quiz.validate_answer(msg.content.trim()) {
-        locked_state.pending_color_tests.remove(&msg.channel_id.get());
-
-        let db = locked_state.db.clone();
-        let (bonus_xp, new_level) =
-            award_quiz_xp(&db, &mut locked_state.rng, msg.author.id.get(), &msg.author.name).await.ok()?;
-
-        return Some(
-            Command::text(if let Some(level) = new_level {
-                format!(
-                    "<@{}> Correct! The color was `rgb({}, {}, {})` or `#{:02x}{:02x}{:02x}`. You earned {} XP and leveled up to level {}!",
-                    msg.author.id.get(), r, g, b, r, g, b, bonus_xp, level
-                )
-            } else {
-                format!(
-                    "<@{}> Correct! The color was `rgb({}, {}, {})` or `#{:02x}{:02x}{:02x}`. You earned {} XP!",
-                    msg.author.id.get(), r, g, b, r, g, b, bonus_xp
-                )
-            })
-            .reply(),
+    channel_id: u64,
+    winner: Option<RoundWinner<'_>>,
+    http: &HttpClient,
+    command: Command,
+) -> Command {
+    let state = &mut **locked_state;
+    let Some(tournament) = state.tournaments.get_mut(&channel_id) else {
+        return command;
+    };
+
+    if let Some(winner) = winner {
+        tournament.record_round(winner.user_id, winner.name, winner.points);
+    }
+
+    if !tournament.is_finished() {
+        update_scoreboard(http, channel_id, tournament).await;
+        let (host_id, kind) = (tournament.host_id, tournament.next_kind(&mut state.rng));
+        tournament.round += 1;
+        let header = Command::text(format!("**Round {}/{}**", tournament.round, tournament.rounds));
+
+        if let Some(next) = start_quiz(locked_state, kind, channel_id, host_id).await {
+            return append_command(command, append_command(header, next));
+        }
+        tracing::warn!(
+            "Failed to start the next tournament round in {}, ending early",
+            channel_id
         );
     }
 
-    None
+    finish_tournament(locked_state, channel_id, http, command).await
 }
 
-pub async fn trigger_math_quiz(msg: &MessageCreate, locked_state: &mut MutexGuard<'_, State>) -> Option<Command> {
-    if locked_state.config.openrouter_api_key.is_none()
-        || locked_state.rng.gen_range(0..500) != 42
-        || locked_state.pending_math_tests.contains_key(&msg.channel_id.get())
-        || locked_state.pending_color_tests.contains_key(&msg.channel_id.get())
-    {
-        return None;
+/// Posts the scoreboard after the first round and edits it in place afterwards.
+async fn update_scoreboard(http: &HttpClient, channel_id: u64, tournament: &mut Tournament) {
+    let scoreboard = tournament.scoreboard();
+
+    if let Some(message_id) = tournament.scoreboard_message {
+        match http
+            .update_message(Id::new(channel_id), message_id)
+            .content(Some(&scoreboard))
+        {
+            Ok(req) => {
+                if let Err(e) = req.exec().await {
+                    tracing::warn!("Failed to update tournament scoreboard: {:?}", e);
+                }
+            }
+            Err(e) => tracing::warn!("Failed to build tournament scoreboard update: {:?}", e),
+        }
+        return;
     }
 
-    let api_key = locked_state.config.openrouter_api_key.clone().unwrap();
-    let model = locked_state.config.openrouter_model.clone();
-    let db_clone = locked_state.db.clone();
-
-    match MathTest::generate(&api_key, &model, &db_clone, &mut locked_state.rng).await {
-        Ok(test) => {
-            let pending = PendingMathTest {
-                user_id: msg.author.id.get(),
-                channel_id: msg.channel_id.get(),
-                question: test.question.clone(),
-                answer: test.answer,
-                started_at: TokioInstant::now(),
-            };
+    match http.create_message(Id::new(channel_id)).content(&scoreboard) {
+        Ok(req) => match req.exec().await {
+            Ok(response) => match response.model().await {
+                Ok(message) => tournament.scoreboard_message = Some(message.id),
+                Err(e) => tracing::warn!("Failed to read tournament scoreboard message: {:?}", e),
+            },
+            Err(e) => tracing::warn!("Failed to post tournament scoreboard: {:?}", e),
+        },
+        Err(e) => tracing::warn!("Failed to build tournament scoreboard: {:?}", e),
+    }
+}
 
-            locked_state.pending_math_tests.insert(msg.channel_id.get(), pending);
+/// Ends the tournament in `channel_id`, pays out the winner bonus and appends the announcement to `command`.
+async fn finish_tournament(
+    locked_state: &mut MutexGuard<'_, State>,
+    channel_id: u64,
+    http: &HttpClient,
+    command: Command,
+) -> Command {
+    let Some(mut tournament) = locked_state.tournaments.remove(&channel_id) else {
+        return command;
+    };
+    // Mark every round as played so the scoreboard shows the final results, even when ending early
+    tournament.round = tournament.rounds;
+    update_scoreboard(http, channel_id, &mut tournament).await;
+
+    let winners = tournament
+        .winners()
+        .into_iter()
+        .map(|(user_id, score)| (user_id, score.name.clone(), score.points))
+        .collect::<Vec<_>>();
+    if winners.is_empty() {
+        return append_command(
+            command,
+            Command::text("**Tournament over!** Nobody scored any points this time."),
+        );
+    }
 
-            Some(Command::text(format!(
-                "<@{}> **MATH TEST TIME!** Solve this in 30 seconds:\n`{}`\n(Answer to 1 decimal place)",
-                msg.author.id.get(),
-                test.question
-            )))
+    let db = locked_state.db.clone();
+    let mut announcement = "**Tournament over!**".to_string();
+    for (user_id, name, points) in winners {
+        match award_quiz_xp(&db, &mut locked_state.rng, user_id, &name, WINNER_XP_MULTIPLIER).await {
+            Ok((bonus_xp, new_level)) => {
+                let level_up = new_level
+                    .map(|level| format!(" and leveled up to level {}", level))
+                    .unwrap_or_default();
+                announcement.push_str(&format!(
+                    "\n🏆 <@{}> wins with {} points and earns {} bonus XP{}!",
+                    user_id, points, bonus_xp, level_up
+                ));
+            }
+            Err(e) => {
+                tracing::error!("Failed to award tournament bonus to {}: {:?}", user_id, e);
+                announcement.push_str(&format!("\n🏆 <@{}> wins with {} points!", user_id, points));
+            }
         }
+    }
+
+    append_command(command, Command::text(announcement))
+}
+
+/// The tier matching the triggering user's level, unless `kind` is math and a math difficulty is configured.
+async fn quiz_difficulty(kind: QuizKind, user_id: u64, locked_state: &MutexGuard<'_, State>) -> Difficulty {
+    if let (QuizKind::Math, Some(difficulty)) = (kind, locked_state.config.math_difficulty) {
+        return difficulty;
+    }
+    match db::get_user(&locked_state.db, user_id).await {
+        Ok(user) => Difficulty::for_level(user.map(|u| u.level).unwrap_or_default()),
         Err(e) => {
-            tracing::error!("Failed to generate math test: {:?}", e);
-            None
+            tracing::warn!("Failed to look up quiz difficulty: {:?}", e);
+            Difficulty::default()
         }
     }
 }
 
-pub async fn trigger_color_quiz(msg: &MessageCreate, locked_state: &mut MutexGuard<'_, State>) -> Option<Command> {
-    if locked_state.rng.gen_range(0..500) != 42
-        || locked_state.pending_color_tests.contains_key(&msg.channel_id.get())
-        || locked_state.pending_math_tests.contains_key(&msg.channel_id.get())
-    {
-        return None;
-    }
+pub fn has_pending_quiz(locked_state: &State, channel_id: u64) -> bool {
+    locked_state.pending_quizzes.contains_key(&channel_id) || locked_state.tournaments.contains_key(&channel_id)
+}
 
-    let quiz = ColorQuiz::generate(&mut locked_state.rng);
+/// Rolls the channel's random quiz chance. A chance of 0 disables random quizzes.
+fn roll_quiz_chance(locked_state: &mut MutexGuard<'_, State>, channel_id: u64) -> bool {
+    let chance = locked_state.channel_settings(channel_id).quiz_chance;
+    chance > 0 && locked_state.rng.gen_range(0..chance) == 0
+}
 
-    match quiz.generate_image() {
-        Ok(image_data) => {
-            let pending = PendingColorTest {
-                user_id: msg.author.id.get(),
-                channel_id: msg.channel_id.get(),
-                r: quiz.r,
-                g: quiz.g,
-                b: quiz.b,
-                started_at: TokioInstant::now(),
-            };
+/// Starts a random quiz type when the channel's quiz chance hits.
+pub async fn trigger_quiz(msg: &MessageCreate, locked_state: &mut MutexGuard<'_, State>) -> Option<Command> {
+    if !roll_quiz_chance(locked_state, msg.channel_id.get()) || has_pending_quiz(locked_state, msg.channel_id.get()) {
+        return None;
+    }
 
-            locked_state.pending_color_tests.insert(msg.channel_id.get(), pending);
+    let kind = *QuizKind::ALL.choose(&mut locked_state.rng)?;
+    start_quiz(locked_state, kind, msg.channel_id.get(), msg.author.id.get()).await
+}
 
-            Some(
-                Command::text("**COLOR QUIZ TIME!** Guess this color in 60 seconds!\nFormat: `#RRGGBB`")
-                    .attachments(vec![Attachment::from_bytes("color.png".to_string(), image_data, 1)]),
-            )
+/// Starts a quiz of `kind` for `user_id` in `channel_id`. Callers check [`has_pending_quiz`] first.
+pub async fn start_quiz(
+    locked_state: &mut MutexGuard<'_, State>,
+    kind: QuizKind,
+    channel_id: u64,
+    user_id: u64,
+) -> Option<Command> {
+    let difficulty = quiz_difficulty(kind, user_id, locked_state).await;
+    let config = Arc::clone(&locked_state.config);
+    let db = locked_state.db.clone();
+    let llm = locked_state.llm.clone();
+    let ctx = QuizContext {
+        db: &db,
+        config: &config,
+        llm: llm.as_deref(),
+        rng: &mut locked_state.rng,
+        difficulty,
+    };
+
+    let quiz = match kind.generate(ctx).await {
+        Ok(quiz) => quiz,
+        Err(e) => {
+            tracing::error!("Failed to generate {} quiz: {:?}", kind.as_str(), e);
+            return None;
         }
+    };
+    let command = match quiz.render(user_id) {
+        Ok(command) => command,
         Err(e) => {
-            tracing::error!("Failed to generate color quiz image: {:?}", e);
-            None
+            tracing::error!("Failed to render {} quiz: {:?}", kind.as_str(), e);
+            return None;
         }
+    };
+
+    locked_state.pending_quizzes.insert(
+        channel_id,
+        PendingQuiz {
+            user_id,
+            quiz,
+            started_at: TokioInstant::now(),
+        },
+    );
+
+    Some(command)
+}
+
+/// Starts a tournament of `rounds` quizzes drawn from `kinds`, beginning with the first round.
+/// Callers check [`has_pending_quiz`] first.
+pub async fn start_tournament(
+    locked_state: &mut MutexGuard<'_, State>,
+    channel_id: u64,
+    host_id: u64,
+    kinds: Vec<QuizKind>,
+    rounds: usize,
+) -> Option<Command> {
+    let mut tournament = Tournament::new(host_id, kinds, rounds);
+    let kind = tournament.next_kind(&mut locked_state.rng);
+    tournament.round = 1;
+
+    let names = tournament
+        .kinds
+        .iter()
+        .map(|kind| kind.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let intro = Command::text(format!(
+        "**QUIZ TOURNAMENT!** {} rounds of {}. Correct answers score up to 150 points, the faster the better. \
+         The winner gets bonus XP!\n\n**Round 1/{}**",
+        tournament.rounds, names, tournament.rounds
+    ));
+
+    let first_round = start_quiz(locked_state, kind, channel_id, host_id).await?;
+    locked_state.tournaments.insert(channel_id, tournament);
+    Some(append_command(intro, first_round))
+}
+
+/// Sends a quiz message that isn't a reply to anything, e.g. from the expiry task.
+async fn send_command(http: &HttpClient, channel_id: u64, command: Command) -> color_eyre::Result<()> {
+    let mut req = http
+        .create_message(Id::new(channel_id))
+        .attachments(&command.attachments)?;
+    if let Some(text) = &command.text {
+        req = req.content(text)?;
     }
+    req.exec().await?;
+    Ok(())
+}
+
+/// Checks running quizzes every few seconds so expired rounds are announced, and tournaments move on, even when
+/// nobody is talking in the channel.
+pub fn schedule_quiz_expiry(http: Arc<HttpClient>, state: Arc<Mutex<State>>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+
+            let mut commands = Vec::new();
+            {
+                let mut locked_state = state.lock().await;
+                let expired = locked_state
+                    .pending_quizzes
+                    .iter()
+                    .filter(|(_, pending)| pending.started_at.elapsed() > pending.quiz.time_limit())
+                    .map(|(channel_id, _)| *channel_id)
+                    .collect::<Vec<_>>();
+
+                for channel_id in expired {
+                    let guild_id = locked_state
+                        .cache
+                        .channel(Id::new(channel_id))
+                        .and_then(|channel| channel.guild_id);
+                    if let Some(command) = expire_quiz(&mut locked_state, channel_id, guild_id, &http).await {
+                        commands.push((channel_id, command));
+                    }
+                }
+            }
+
+            for (channel_id, command) in commands {
+                if let Err(e) = send_command(&http, channel_id, command).await {
+                    tracing::error!("Failed to announce expired quiz: {:?}", e);
+                }
+            }
+        }
+    });
 }
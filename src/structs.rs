@@ -16,7 +16,20 @@ use twilight_cache_inmemory::InMemoryCache;
 use twilight_model::{channel::message::Embed, http::attachment::Attachment, id::Id};
 use vesper::twilight_exports::ChannelMarker;
 
-use crate::{brave::BraveApi, config::Config};
+use crate::{
+    ai_controls::AiReplies,
+    ai_fallback::Fallback,
+    ai_message,
+    ai_usage::Metered,
+    brave::BraveApi,
+    channel_summary::SummaryProgress,
+    config::Config,
+    database::ChannelSettings,
+    embeddings::{self, Embedder},
+    llm::{self, LlmProvider},
+    quiz::Quiz,
+    tournament::Tournament,
+};
 
 #[derive(PartialEq, Default, Eq, Clone)]
 pub struct Command {
@@ -76,24 +89,11 @@ impl Command {
     }
 }
 
-/// Tracks a pending math test for a user
-#[derive(Debug, Clone)]
-pub struct PendingMathTest {
-    pub user_id: u64,
-    pub channel_id: u64,
-    pub question: String,
-    pub answer: f64,
-    pub started_at: TokioInstant,
-}
-
-/// Tracks a pending color test for a user
-#[derive(Debug, Clone)]
-pub struct PendingColorTest {
+/// Tracks the quiz running in a channel, keyed by the channel in `State::pending_quizzes`
+#[derive(Debug)]
+pub struct PendingQuiz {
     pub user_id: u64,
-    pub channel_id: u64,
-    pub r: u8,
-    pub g: u8,
-    pub b: u8,
+    pub quiz: Box<dyn Quiz>,
     pub started_at: TokioInstant,
 }
 
@@ -143,14 +143,28 @@ pub struct State {
     pub cache: InMemoryCache,
     /// Brave API
     pub brave_api: BraveApi,
-    /// Pending math tests
-    pub pending_math_tests: HashMap<u64, PendingMathTest>,
-    /// Pending color tests
-    pub pending_color_tests: HashMap<u64, PendingColorTest>,
+    /// Chat model backend, `None` when no model is configured
+    pub llm: Option<Arc<dyn LlmProvider>>,
+    /// Text embedding backend for picking memories, `None` when no embedding model is configured
+    pub embedder: Option<Arc<dyn Embedder>>,
+    /// Running quizzes by channel id
+    pub pending_quizzes: HashMap<u64, PendingQuiz>,
+    /// Running quiz tournaments by channel id
+    pub tournaments: HashMap<u64, Tournament>,
     /// Message count per channel/user since last memory creation (channel_id or user_id -> message count)
     pub channel_message_counts: HashMap<u64, i32>,
     /// Currency exchange rates
     pub currency_rates: CurrencyRates,
+    /// Per-channel settings, loaded at startup and kept in sync with the database
+    pub channel_settings: HashMap<u64, ChannelSettings>,
+    /// Threads holding an AI conversation, thread id -> parent channel id
+    pub ai_threads: HashMap<u64, u64>,
+    /// Recent AI replies whose buttons still work
+    pub ai_replies: AiReplies,
+    /// When the AI last replied unprompted, by channel id
+    pub interjections: HashMap<u64, Instant>,
+    /// Messages since each channel's rolling summary was refreshed, by channel id
+    pub summary_progress: HashMap<u64, SummaryProgress>,
 }
 impl State {
     pub fn new(client: Client, db: Pool, config: Arc<Config>) -> Self {
@@ -158,6 +172,12 @@ impl State {
         let channel_bucket = Bucket::new(Limit::new(Duration::from_secs(60), 120));
         let dm_bucket = Bucket::new(Limit::new(Duration::from_secs(3600), 30)); // 30 messages per hour
         let client_clone = client.clone();
+        // Every model call goes through the meter, so usage is recorded no matter which feature makes it. The
+        // meter sits under the fallbacks so each attempt is recorded against the model that served it.
+        let llm = llm::provider(&config).map(|inner| {
+            let metered = Arc::new(Metered::new(inner, db.clone(), &config));
+            Arc::new(Fallback::new(metered, &config.openrouter_fallback_models)) as Arc<dyn LlmProvider>
+        });
         Self {
             db,
             rng: SmallRng::from_os_rng(),
@@ -170,13 +190,43 @@ impl State {
             del: HashMap::new(),
             channel_bucket,
             cache: InMemoryCache::new(),
-            brave_api: BraveApi::new(client_clone, &config.brave_api.clone().unwrap_or_default()),
+            brave_api: BraveApi::new(
+                client_clone,
+                &config.brave_api.clone().unwrap_or_default(),
+                &config.brave_api_base_url,
+            ),
+            llm,
+            embedder: embeddings::embedder(&config),
             config,
-            pending_math_tests: HashMap::new(),
-            pending_color_tests: HashMap::new(),
+            pending_quizzes: HashMap::new(),
+            tournaments: HashMap::new(),
             channel_message_counts: HashMap::new(),
             dm_bucket,
             currency_rates: CurrencyRates::default(),
+            channel_settings: HashMap::new(),
+            ai_threads: HashMap::new(),
+            ai_replies: AiReplies::default(),
+            interjections: HashMap::new(),
+            summary_progress: HashMap::new(),
+        }
+    }
+
+    pub fn channel_settings(&self, channel_id: u64) -> ChannelSettings {
+        self.channel_settings
+            .get(&channel_id)
+            .cloned()
+            .unwrap_or_else(|| ChannelSettings::new(channel_id))
+    }
+
+    /// What answering a message with the AI uses, so it can be done after the lock is released.
+    pub fn ai_services(&self) -> ai_message::Services {
+        ai_message::Services {
+            database: self.db.clone(),
+            llm: self.llm.clone(),
+            embedder: self.embedder.clone(),
+            config: self.config.clone(),
+            brave: self.brave_api.clone(),
+            currency_rates: self.currency_rates.clone(),
         }
     }
 }
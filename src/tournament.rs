@@ -0,0 +1,153 @@
+use std::{collections::HashMap, time::Duration};
+
+use rand::{seq::IndexedRandom, Rng};
+use twilight_model::id::{marker::MessageMarker, Id};
+
+use crate::quiz::{Grade, QuizKind};
+
+pub const DEFAULT_ROUNDS: usize = 5;
+pub const MAX_ROUNDS: usize = 20;
+
+/// XP multiplier for the tournament winner's bonus
+pub const WINNER_XP_MULTIPLIER: f64 = 3.0;
+
+/// Points for a perfect answer, before the speed bonus
+const ACCURACY_POINTS: f64 = 100.0;
+/// Points for answering instantly, shrinking to nothing at the time limit
+const SPEED_POINTS: f64 = 50.0;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TournamentScore {
+    pub name: String,
+    pub points: u32,
+    pub rounds_won: u32,
+}
+
+/// A run of consecutive quiz rounds in one channel.
+#[derive(Debug, Clone)]
+pub struct Tournament {
+    pub host_id: u64,
+    pub kinds: Vec<QuizKind>,
+    pub rounds: usize,
+    /// Rounds started so far
+    pub round: usize,
+    pub scores: HashMap<u64, TournamentScore>,
+    /// The live scoreboard, posted after the first round
+    pub scoreboard_message: Option<Id<MessageMarker>>,
+}
+
+impl Tournament {
+    pub fn new(host_id: u64, kinds: Vec<QuizKind>, rounds: usize) -> Self {
+        Self {
+            host_id,
+            kinds,
+            rounds: rounds.clamp(1, MAX_ROUNDS),
+            round: 0,
+            scores: HashMap::new(),
+            scoreboard_message: None,
+        }
+    }
+
+    pub fn next_kind(&self, rng: &mut impl Rng) -> QuizKind {
+        self.kinds.choose(rng).copied().unwrap_or(QuizKind::Math)
+    }
+
+    /// Scores a correct answer by how good it was and how much of the time limit was left.
+    pub fn round_points(grade: &Grade, elapsed: Duration, time_limit: Duration) -> u32 {
+        let remaining = 1.0 - (elapsed.as_secs_f64() / time_limit.as_secs_f64()).clamp(0.0, 1.0);
+        (ACCURACY_POINTS * grade.xp_fraction + SPEED_POINTS * remaining).round() as u32
+    }
+
+    pub fn record_round(&mut self, user_id: u64, name: &str, points: u32) {
+        let score = self.scores.entry(user_id).or_default();
+        score.name = name.to_string();
+        score.points += points;
+        score.rounds_won += 1;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.round >= self.rounds
+    }
+
+    /// Scores from highest to lowest.
+    pub fn standings(&self) -> Vec<(u64, &TournamentScore)> {
+        let mut standings = self.scores.iter().map(|(id, score)| (*id, score)).collect::<Vec<_>>();
+        standings.sort_by(|a, b| b.1.points.cmp(&a.1.points).then(b.1.rounds_won.cmp(&a.1.rounds_won)));
+        standings
+    }
+
+    /// Everyone tied for the most points, empty when nobody scored.
+    pub fn winners(&self) -> Vec<(u64, &TournamentScore)> {
+        let standings = self.standings();
+        let Some(best) = standings.first().map(|(_, score)| score.points) else {
+            return Vec::new();
+        };
+        standings
+            .into_iter()
+            .filter(|(_, score)| score.points == best)
+            .collect()
+    }
+
+    pub fn scoreboard(&self) -> String {
+        let title = if self.is_finished() {
+            "final results".to_string()
+        } else {
+            format!("after round {}/{}", self.round, self.rounds)
+        };
+
+        let mut scoreboard = format!("**🏆 Quiz tournament, {}**", title);
+        if self.scores.is_empty() {
+            scoreboard.push_str("\nNo points scored yet.");
+        }
+        for (place, (user_id, score)) in self.standings().iter().enumerate() {
+            scoreboard.push_str(&format!(
+                "\n{}. <@{}> {} pts ({} {})",
+                place + 1,
+                user_id,
+                score.points,
+                score.rounds_won,
+                if score.rounds_won == 1 { "round" } else { "rounds" }
+            ));
+        }
+        scoreboard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faster_and_better_answers_score_more() {
+        let limit = Duration::from_secs(60);
+        let perfect = Grade::full("Correct!");
+        let close = Grade {
+            xp_fraction: 0.5,
+            summary: "Close!".to_string(),
+        };
+
+        assert_eq!(Tournament::round_points(&perfect, Duration::ZERO, limit), 150);
+        assert_eq!(Tournament::round_points(&perfect, limit, limit), 100);
+        assert_eq!(Tournament::round_points(&close, Duration::from_secs(30), limit), 75);
+    }
+
+    #[test]
+    fn ties_share_the_win() {
+        let mut tournament = Tournament::new(1, vec![QuizKind::Math], 3);
+        assert!(tournament.winners().is_empty());
+
+        tournament.record_round(2, "a", 120);
+        tournament.record_round(3, "b", 90);
+        tournament.record_round(3, "b", 30);
+        tournament.record_round(4, "c", 50);
+
+        let mut winners = tournament.winners().into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        winners.sort();
+        assert_eq!(winners, vec![2, 3]);
+        assert_eq!(
+            tournament.standings()[0].0,
+            3,
+            "more rounds won breaks the tie in the standings"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use rand::{seq::IndexedRandom, Rng};
+
+use crate::{
+    quiz::{Grade, Quiz, QuizContext, QuizKind, QUIZ_DATA},
+    quiz_difficulty::Difficulty,
+    structs::Command,
+};
+
+/// Inserted after every word of the rendered sentence so copy-pasted answers can be told apart.
+const ZERO_WIDTH_SPACE: char = '\u{200b}';
+
+/// Share of the sentence that has to be typed correctly to finish the race.
+const MIN_ACCURACY: f64 = 0.9;
+
+#[derive(Debug, Clone)]
+pub struct TypingRace {
+    pub sentence: String,
+    pub difficulty: Difficulty,
+}
+
+impl TypingRace {
+    /// Inclusive word counts used for a difficulty.
+    fn word_counts(difficulty: Difficulty) -> (usize, usize) {
+        match difficulty {
+            Difficulty::Easy => (0, 7),
+            Difficulty::Medium => (8, 10),
+            Difficulty::Hard => (11, 14),
+            Difficulty::Expert => (15, usize::MAX),
+        }
+    }
+
+    /// Speed that earns the full XP reward.
+    fn target_wpm(difficulty: Difficulty) -> f64 {
+        match difficulty {
+            Difficulty::Easy => 30.0,
+            Difficulty::Medium => 45.0,
+            Difficulty::Hard => 60.0,
+            Difficulty::Expert => 80.0,
+        }
+    }
+
+    pub fn random(rng: &mut impl Rng, difficulty: Difficulty) -> Result<Self> {
+        let (min, max) = Self::word_counts(difficulty);
+        let sentences = QUIZ_DATA
+            .typing_sentences
+            .iter()
+            .filter(|sentence| (min..=max).contains(&sentence.split_whitespace().count()))
+            .collect::<Vec<_>>();
+        let sentence = sentences
+            .choose(rng)
+            .ok_or_else(|| eyre!("No typing sentences for {} difficulty", difficulty.as_str()))?;
+
+        Ok(Self {
+            sentence: sentence.to_string(),
+            difficulty,
+        })
+    }
+}
+
+/// Words per minute using the usual five characters per word.
+pub fn words_per_minute(chars: usize, elapsed: Duration) -> f64 {
+    let minutes = elapsed.as_secs_f64().max(1.0) / 60.0;
+    chars as f64 / 5.0 / minutes
+}
+
+/// Character-level Levenshtein distance.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+impl Quiz for TypingRace {
+    async fn generate(ctx: QuizContext<'_>) -> Result<Self> {
+        Self::random(ctx.rng, ctx.difficulty)
+    }
+
+    fn kind(&self) -> QuizKind {
+        QuizKind::TypingRace
+    }
+
+    fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    fn time_limit(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn render(&self, user_id: u64) -> Result<Command> {
+        let guarded = self
+            .sentence
+            .split(' ')
+            .collect::<Vec<_>>()
+            .join(&format!("{} ", ZERO_WIDTH_SPACE));
+        Ok(Command::text(format!(
+            "<@{}> **TYPING RACE!** ({}) First to type this with at least {:.0}% accuracy wins, you have {} seconds:\n> {}",
+            user_id,
+            self.difficulty.as_str(),
+            MIN_ACCURACY * 100.0,
+            self.time_limit().as_secs(),
+            guarded
+        )))
+    }
+
+    fn validate(&self, answer: &str, elapsed: Duration) -> Option<Grade> {
+        if answer.contains(ZERO_WIDTH_SPACE) {
+            return None;
+        }
+        let answer = answer.split_whitespace().collect::<Vec<_>>().join(" ");
+        let length = self.sentence.chars().count();
+        let accuracy = 1.0 - edit_distance(&self.sentence, &answer) as f64 / length as f64;
+        if accuracy < MIN_ACCURACY {
+            return None;
+        }
+
+        let wpm = words_per_minute(length, elapsed) * accuracy;
+        Some(Grade {
+            xp_fraction: (wpm / Self::target_wpm(self.difficulty)).clamp(0.25, 1.0),
+            summary: format!("Finished! {:.0} WPM at {:.0}% accuracy.", wpm, accuracy * 100.0),
+        })
+    }
+
+    fn question(&self) -> String {
+        self.sentence.clone()
+    }
+
+    fn answer(&self) -> String {
+        self.sentence.clone()
+    }
+
+    fn timeout_message(&self) -> String {
+        "Time's up! Nobody finished the race.".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typos_are_tolerated_but_pasting_is_not() {
+        let race = TypingRace {
+            sentence: "The quick brown fox jumps over the lazy dog.".to_string(),
+            difficulty: Difficulty::Easy,
+        };
+        let elapsed = Duration::from_secs(15);
+
+        let exact = race
+            .validate("The quick brown fox jumps over the lazy dog.", elapsed)
+            .unwrap();
+        assert_eq!(exact.xp_fraction, 1.0);
+        assert!(race
+            .validate("The quick brwn fox jumps over teh lazy dog", elapsed)
+            .is_some());
+        assert!(race.validate("the lazy dog", elapsed).is_none());
+
+        let pasted = race.render(1).unwrap().text.unwrap();
+        let pasted = pasted.lines().last().unwrap().trim_start_matches("> ");
+        assert!(race.validate(pasted, elapsed).is_none());
+    }
+
+    #[test]
+    fn wpm_uses_five_characters_per_word() {
+        assert_eq!(words_per_minute(250, Duration::from_secs(60)), 50.0);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
@@ -0,0 +1,154 @@
+use std::io::Cursor;
+
+use color_eyre::Result;
+use png::{BitDepth, ColorType, Encoder};
+use rand::Rng;
+
+/// Encodes tightly packed 8-bit RGB pixels as a PNG.
+pub fn encode_rgb_png(width: u32, height: u32, data: &[u8]) -> Result<Vec<u8>> {
+    let mut buffer = Cursor::new(Vec::new());
+
+    {
+        let mut encoder = Encoder::new(&mut buffer, width, height);
+        encoder.set_color(ColorType::Rgb);
+        encoder.set_depth(BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(data)?;
+    }
+
+    Ok(buffer.into_inner())
+}
+
+/// Pixels per glyph dot
+const SCALE: usize = 6;
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const PADDING: usize = 24;
+
+/// 5x7 bitmap glyphs, one byte per row with the leftmost dot in bit 4. `*` and `/` are drawn as × and ÷.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '*' => [0x00, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x00],
+        '/' => [0x00, 0x04, 0x00, 0x1f, 0x00, 0x04, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        '=' => [0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00],
+        ' ' => [0x00; GLYPH_HEIGHT],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+/// Renders a short arithmetic expression as a PNG with a wavy warp, per-glyph jitter, pixel noise and a few
+/// stray lines, so it can be read by people but not copied as text.
+pub fn render_noisy_text(text: &str, rng: &mut impl Rng) -> Result<Vec<u8>> {
+    let advance = (GLYPH_WIDTH + 1) * SCALE;
+    let width = PADDING * 2 + text.chars().count().max(1) * advance;
+    let height = PADDING * 2 + GLYPH_HEIGHT * SCALE;
+
+    // Rasterise the glyphs into a mask, nudging each one up or down a little
+    let mut mask = vec![false; width * height];
+    for (i, c) in text.chars().enumerate() {
+        let jitter = rng.gen_range(-(SCALE as i64)..=SCALE as i64);
+        let left = PADDING + i * advance;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..SCALE {
+                    let y = (PADDING + row * SCALE + dy) as i64 + jitter;
+                    if !(0..height as i64).contains(&y) {
+                        continue;
+                    }
+                    for dx in 0..SCALE {
+                        mask[y as usize * width + left + col * SCALE + dx] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    let background = [
+        rng.gen_range(215..=245u8),
+        rng.gen_range(215..=245u8),
+        rng.gen_range(215..=245u8),
+    ];
+    let foreground = [
+        rng.gen_range(20..=90u8),
+        rng.gen_range(20..=90u8),
+        rng.gen_range(20..=90u8),
+    ];
+    let amplitude = rng.gen_range(2.0..4.5);
+    let (period_x, period_y) = (rng.gen_range(18.0..30.0), rng.gen_range(30.0..50.0));
+    let (phase_x, phase_y) = (
+        rng.gen_range(0.0..std::f64::consts::TAU),
+        rng.gen_range(0.0..std::f64::consts::TAU),
+    );
+
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            // Sample the mask through a sine warp
+            let sx = (x as f64 + amplitude * (y as f64 / period_x + phase_x).sin()).round() as i64;
+            let sy = (y as f64 + amplitude * (x as f64 / period_y + phase_y).sin()).round() as i64;
+            let inside = (0..width as i64).contains(&sx)
+                && (0..height as i64).contains(&sy)
+                && mask[sy as usize * width + sx as usize];
+
+            let base = if inside { foreground } else { background };
+            let noise = rng.gen_range(-28i16..=28);
+            data.extend(base.iter().map(|&c| (c as i16 + noise).clamp(0, 255) as u8));
+        }
+    }
+
+    // Stray lines through the text in the foreground colour
+    for _ in 0..rng.gen_range(3..=5) {
+        let (x0, y0) = (rng.gen_range(0..width) as f64, rng.gen_range(0..height) as f64);
+        let (x1, y1) = (rng.gen_range(0..width) as f64, rng.gen_range(0..height) as f64);
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()) as usize;
+        for step in 0..=steps {
+            let t = step as f64 / steps.max(1) as f64;
+            let (x, y) = ((x0 + (x1 - x0) * t) as usize, (y0 + (y1 - y0) * t) as usize);
+            for (px, py) in [(x, y), (x, y + 1)] {
+                if px < width && py < height {
+                    let offset = (py * width + px) * 3;
+                    data[offset..offset + 3].copy_from_slice(&foreground);
+                }
+            }
+        }
+    }
+
+    encode_rgb_png(width as u32, height as u32, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    #[test]
+    fn noisy_text_is_a_valid_png_sized_to_the_text() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let image = render_noisy_text("12 * (3 + 4)", &mut rng).unwrap();
+
+        let decoder = png::Decoder::new(Cursor::new(image));
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.width as usize, PADDING * 2 + 12 * (GLYPH_WIDTH + 1) * SCALE);
+        assert_eq!(info.height as usize, PADDING * 2 + GLYPH_HEIGHT * SCALE);
+    }
+}
@@ -1,6 +1,10 @@
-use crate::db;
+use crate::{
+    ai_trace,
+    database::{Persona, UsageRow},
+    db, persona,
+};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::{Html, IntoResponse, Response},
     Form,
@@ -42,6 +46,44 @@ pub struct MemoryForm {
     pub content: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PersonaForm {
+    pub name: String,
+    pub template: String,
+    pub model: String,
+    pub temperature: String,
+    /// Checkboxes are only submitted when ticked
+    pub use_memories: Option<String>,
+    pub use_examples: Option<String>,
+}
+
+impl PersonaForm {
+    fn into_persona(self, id: i64) -> Result<Persona, String> {
+        let temperature = match self.temperature.trim() {
+            "" => None,
+            t => Some(t.parse::<f32>().map_err(|_| format!("Invalid temperature: {}", t))?),
+        };
+        let model = self.model.trim();
+        Ok(Persona {
+            id,
+            name: self.name.trim().to_string(),
+            template: self.template,
+            model: (!model.is_empty()).then(|| model.to_string()),
+            temperature,
+            use_memories: self.use_memories.is_some(),
+            use_examples: self.use_examples.is_some(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PersonaAssignForm {
+    pub target_id: u64,
+    pub kind: String,
+    /// Empty to go back to the default persona
+    pub persona_id: String,
+}
+
 pub async fn list_users(State(state): State<AppState>) -> Response {
     let users = match db::get_all_users_web(&state.db).await {
         Ok(u) => u,
@@ -70,6 +112,11 @@ pub async fn view_user(State(state): State<AppState>, Path(user_id): Path<u64>)
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
     };
     context.insert("profile_candidates", &candidates);
+    let memory_candidates = match db::get_memory_candidates(&state.db, user_id).await {
+        Ok(c) => c,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    context.insert("memory_candidates", &memory_candidates);
     context.insert("title", &format!("User {}", user_id));
 
     match state.templates.render("user.html", &context) {
@@ -97,6 +144,22 @@ pub async fn reject_profile_candidate(State(state): State<AppState>, Path(candid
     }
 }
 
+pub async fn approve_memory_candidate(State(state): State<AppState>, Path(candidate_id): Path<i64>) -> Response {
+    match db::approve_memory_candidate(&state.db, candidate_id).await {
+        Ok(Some(user_id)) => axum::response::Redirect::to(&format!("/user/{}", user_id)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Candidate not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+pub async fn reject_memory_candidate(State(state): State<AppState>, Path(candidate_id): Path<i64>) -> Response {
+    match db::reject_memory_candidate(&state.db, candidate_id).await {
+        Ok(Some(user_id)) => axum::response::Redirect::to(&format!("/user/{}", user_id)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Candidate not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
 pub async fn edit_user_form(State(state): State<AppState>, Path(user_id): Path<u64>) -> Response {
     let user = match db::get_user(&state.db, user_id).await {
         Ok(Some(u)) => u,
@@ -228,6 +291,306 @@ pub async fn delete_memory(State(state): State<AppState>, Path(memory_id): Path<
     }
 }
 
+pub async fn quiz_leaderboard(State(state): State<AppState>) -> Response {
+    let entries = match db::get_quiz_leaderboard(&state.db, 50).await {
+        Ok(e) => e,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    let mut context = Context::new();
+    context.insert("entries", &entries);
+    context.insert("title", "Quiz Leaderboard");
+    match state.templates.render("quiz_leaderboard.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            tracing::error!("Template error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response()
+        }
+    }
+}
+
+pub async fn list_personas(State(state): State<AppState>) -> Response {
+    let personas = match db::get_personas(&state.db).await {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    let assignments = match db::get_persona_assignments(&state.db).await {
+        Ok(a) => a,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    let feedback = match db::get_feedback_summary(&state.db).await {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    let mut context = Context::new();
+    context.insert("personas", &personas);
+    context.insert("assignments", &assignments);
+    context.insert("feedback", &feedback);
+    context.insert("title", "Personas");
+    match state.templates.render("personas.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            tracing::error!("Template error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response()
+        }
+    }
+}
+
+/// Renders the persona editor, along with a preview of the prompt the template produces for sample data.
+fn render_persona_form(state: &AppState, persona: &Persona, error: Option<String>) -> Response {
+    let (preview, preview_error) = match persona::render(persona, &persona::PromptData::sample()) {
+        Ok(prompt) => (Some(prompt), None),
+        Err(e) => (None, Some(format!("{:?}", e))),
+    };
+
+    let mut context = Context::new();
+    context.insert("persona", persona);
+    context.insert("preview", &preview);
+    context.insert("preview_error", &preview_error);
+    context.insert("error", &error);
+    match persona.id {
+        0 => context.insert("title", "New Persona"),
+        id => context.insert("title", &format!("Edit Persona {}", id)),
+    }
+
+    match state.templates.render("edit_persona.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            tracing::error!("Template error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response()
+        }
+    }
+}
+
+pub async fn new_persona_form(State(state): State<AppState>) -> Response {
+    let persona = Persona {
+        name: String::new(),
+        ..persona::default_persona()
+    };
+    render_persona_form(&state, &persona, None)
+}
+
+pub async fn edit_persona_form(State(state): State<AppState>, Path(persona_id): Path<i64>) -> Response {
+    match db::get_persona(&state.db, persona_id).await {
+        Ok(Some(persona)) => render_persona_form(&state, &persona, None),
+        Ok(None) => (StatusCode::NOT_FOUND, "Persona not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+/// Saves a new (`persona_id` 0) or existing persona. Templates that fail to render are not saved.
+async fn save_persona(state: &AppState, persona_id: i64, form: PersonaForm) -> Response {
+    let persona = match form.into_persona(persona_id) {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    if let Err(e) = persona::render(&persona, &persona::PromptData::sample()) {
+        return render_persona_form(state, &persona, Some(format!("The template does not render: {}", e)));
+    }
+    match db::save_persona(&state.db, &persona).await {
+        Ok(_) => axum::response::Redirect::to("/personas").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+pub async fn create_persona(State(state): State<AppState>, Form(form): Form<PersonaForm>) -> Response {
+    save_persona(&state, 0, form).await
+}
+
+pub async fn update_persona(
+    State(state): State<AppState>,
+    Path(persona_id): Path<i64>,
+    Form(form): Form<PersonaForm>,
+) -> Response {
+    save_persona(&state, persona_id, form).await
+}
+
+/// Re-renders the editor with the submitted, unsaved persona so its prompt can be previewed.
+pub async fn preview_persona(
+    State(state): State<AppState>,
+    Path(persona_id): Path<i64>,
+    Form(form): Form<PersonaForm>,
+) -> Response {
+    match form.into_persona(persona_id) {
+        Ok(persona) => render_persona_form(&state, &persona, None),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+pub async fn delete_persona(State(state): State<AppState>, Path(persona_id): Path<i64>) -> Response {
+    match db::delete_persona(&state.db, persona_id).await {
+        Ok(_) => axum::response::Redirect::to("/personas").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+pub async fn assign_persona(State(state): State<AppState>, Form(form): Form<PersonaAssignForm>) -> Response {
+    if form.kind != "channel" && form.kind != "guild" {
+        return (StatusCode::BAD_REQUEST, "Kind must be channel or guild").into_response();
+    }
+    let persona_id = match form.persona_id.trim() {
+        "" => None,
+        id => match id.parse::<i64>() {
+            Ok(id) => Some(id),
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid persona").into_response(),
+        },
+    };
+    match db::assign_persona(&state.db, form.target_id, &form.kind, persona_id).await {
+        Ok(_) => axum::response::Redirect::to("/personas").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// How many days back to look, a week by default
+    pub days: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageSection {
+    heading: &'static str,
+    rows: Vec<UsageRow>,
+}
+
+pub async fn usage_dashboard(State(state): State<AppState>, Query(query): Query<UsageQuery>) -> Response {
+    let days = query.days.unwrap_or(7).clamp(1, 365);
+    let mut sections = Vec::new();
+    for (heading, grouping) in [
+        ("Per day", db::UsageGrouping::Day),
+        ("Per feature", db::UsageGrouping::Feature),
+        ("Per model", db::UsageGrouping::Model),
+        ("Top users", db::UsageGrouping::User),
+        ("Per guild", db::UsageGrouping::Guild),
+    ] {
+        match db::get_usage(&state.db, grouping, days).await {
+            Ok(rows) => sections.push(UsageSection { heading, rows }),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+        }
+    }
+    let mut context = Context::new();
+    context.insert("sections", &sections);
+    context.insert("days", &days);
+    context.insert("title", "AI Usage");
+    match state.templates.render("usage.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            tracing::error!("Template error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response()
+        }
+    }
+}
+
+/// Traces shown per page
+const TRACES_PER_PAGE: i64 = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct TraceQuery {
+    /// Text to look for in the prompt, reply, model or persona
+    pub q: Option<String>,
+    pub page: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RerunForm {
+    pub model: String,
+}
+
+pub async fn list_traces(State(state): State<AppState>, Query(query): Query<TraceQuery>) -> Response {
+    let search = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    // Bounded so the offset can't overflow
+    let page = query.page.unwrap_or(1).clamp(1, 10_000);
+    let traces = match db::search_ai_traces(&state.db, search, TRACES_PER_PAGE, (page - 1) * TRACES_PER_PAGE).await {
+        Ok(t) => t,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    let mut context = Context::new();
+    context.insert("has_next", &(traces.len() as i64 == TRACES_PER_PAGE));
+    context.insert("traces", &traces);
+    context.insert("q", search.unwrap_or_default());
+    context.insert("page", &page);
+    context.insert("enabled", &state.config.ai_traces);
+    context.insert("retention_days", &state.config.ai_trace_retention_days);
+    context.insert("title", "AI Traces");
+    match state.templates.render("traces.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response(),
+    }
+}
+
+pub async fn view_trace(State(state): State<AppState>, Path(trace_id): Path<i64>) -> Response {
+    let trace = match db::get_ai_trace(&state.db, trace_id).await {
+        Ok(Some(t)) => t,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Trace not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    let mut models = vec![state.config.openrouter_model.clone()];
+    models.extend(
+        state
+            .config
+            .openrouter_fallback_models
+            .iter()
+            .filter(|model| !model.trim().is_empty())
+            .cloned(),
+    );
+    let mut context = Context::new();
+    context.insert("trace", &trace);
+    context.insert("models", &models);
+    context.insert("can_rerun", &state.llm.is_some());
+    context.insert("title", &format!("Trace #{}", trace.id));
+    match state.templates.render("trace.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response(),
+    }
+}
+
+/// Sends the traced prompt to another model and shows the new trace.
+pub async fn rerun_trace(
+    State(state): State<AppState>,
+    Path(trace_id): Path<i64>,
+    Form(form): Form<RerunForm>,
+) -> Response {
+    let Some(llm) = &state.llm else {
+        return (StatusCode::BAD_REQUEST, "No chat model configured").into_response();
+    };
+    let model = form.model.trim();
+    if model.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Model is required").into_response();
+    }
+    let trace = match db::get_ai_trace(&state.db, trace_id).await {
+        Ok(Some(t)) => t,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Trace not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    match ai_trace::rerun(&state.db, llm.as_ref(), &state.config, &trace, model).await {
+        Ok(id) => axum::response::Redirect::to(&format!("/trace/{}", id)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
+pub async fn list_summaries(State(state): State<AppState>) -> Response {
+    let summaries = match db::get_channel_summaries(&state.db).await {
+        Ok(s) => s,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    };
+    let mut context = Context::new();
+    context.insert("summaries", &summaries);
+    context.insert("every_messages", &state.config.channel_summary_messages);
+    context.insert("every_minutes", &state.config.channel_summary_minutes);
+    context.insert("title", "Channel Summaries");
+    match state.templates.render("summaries.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e)).into_response(),
+    }
+}
+
+/// Forgets the summary of a channel, the next refresh starts a new one.
+pub async fn reset_summary(State(state): State<AppState>, Path(channel_id): Path<u64>) -> Response {
+    match db::delete_channel_summary(&state.db, channel_id).await {
+        Ok(_) => axum::response::Redirect::to("/summaries").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("DB error: {}", e)).into_response(),
+    }
+}
+
 pub async fn serve_css() -> Response {
     let css = include_str!("../../web/static/style.css");
     (
@@ -6,13 +6,23 @@ use deadpool_postgres::Pool;
 use std::sync::Arc;
 use tera::Tera;
 
+use crate::{config::Config, llm::LlmProvider};
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: Pool,
     pub templates: Arc<Tera>,
+    /// For re-running traced prompts, `None` when no model is configured
+    pub llm: Option<Arc<dyn LlmProvider>>,
+    pub config: Arc<Config>,
 }
 
-pub async fn run_web_server(db: Pool, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run_web_server(
+    db: Pool,
+    llm: Option<Arc<dyn LlmProvider>>,
+    config: Arc<Config>,
+    port: u16,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Determine template path based on environment
     // For Nix builds: try to find data directory relative to executable
     let template_path = std::env::current_exe()
@@ -35,6 +45,8 @@ pub async fn run_web_server(db: Pool, port: u16) -> Result<(), Box<dyn std::erro
     let state = AppState {
         db,
         templates: Arc::new(tera),
+        llm,
+        config,
     };
 
     let app = Router::new()
@@ -45,12 +57,29 @@ pub async fn run_web_server(db: Pool, port: u16) -> Result<(), Box<dyn std::erro
         .route("/user/{id}/edit", post(super::routes::update_user))
         .route("/profile-candidate/{id}/approve", post(super::routes::approve_profile_candidate))
         .route("/profile-candidate/{id}/reject", post(super::routes::reject_profile_candidate))
+        .route("/memory-candidate/{id}/approve", post(super::routes::approve_memory_candidate))
+        .route("/memory-candidate/{id}/reject", post(super::routes::reject_memory_candidate))
         .route("/user/{id}/memories", get(super::routes::list_memories))
         .route("/user/{id}/memory/new", get(super::routes::new_memory_form))
         .route("/user/{id}/memory/new", post(super::routes::create_memory))
         .route("/memory/{id}/edit", get(super::routes::edit_memory_form))
         .route("/memory/{id}/edit", post(super::routes::update_memory))
         .route("/memory/{id}/delete", post(super::routes::delete_memory))
+        .route("/quiz", get(super::routes::quiz_leaderboard))
+        .route("/usage", get(super::routes::usage_dashboard))
+        .route("/traces", get(super::routes::list_traces))
+        .route("/trace/{id}", get(super::routes::view_trace))
+        .route("/trace/{id}/rerun", post(super::routes::rerun_trace))
+        .route("/summaries", get(super::routes::list_summaries))
+        .route("/summary/{id}/reset", post(super::routes::reset_summary))
+        .route("/personas", get(super::routes::list_personas))
+        .route("/personas/assign", post(super::routes::assign_persona))
+        .route("/persona/new", get(super::routes::new_persona_form))
+        .route("/persona/new", post(super::routes::create_persona))
+        .route("/persona/{id}/edit", get(super::routes::edit_persona_form))
+        .route("/persona/{id}/edit", post(super::routes::update_persona))
+        .route("/persona/{id}/preview", post(super::routes::preview_persona))
+        .route("/persona/{id}/delete", post(super::routes::delete_persona))
         .route("/export/prompts.json", get(super::routes::export_prompts_json))
         .route("/export/users.csv", get(super::routes::export_users_csv))
         .route("/static/style.css", get(super::routes::serve_css))
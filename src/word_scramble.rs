@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use rand::{
+    seq::{IndexedRandom, SliceRandom},
+    Rng,
+};
+
+use crate::{
+    quiz::{normalize_answer, Grade, Quiz, QuizContext, QuizKind, QUIZ_DATA},
+    quiz_difficulty::Difficulty,
+    structs::Command,
+};
+
+#[derive(Debug, Clone)]
+pub struct WordScramble {
+    pub word: String,
+    pub scrambled: String,
+    pub difficulty: Difficulty,
+}
+
+impl WordScramble {
+    /// Inclusive word lengths used for a difficulty.
+    fn word_lengths(difficulty: Difficulty) -> (usize, usize) {
+        match difficulty {
+            Difficulty::Easy => (4, 5),
+            Difficulty::Medium => (6, 7),
+            Difficulty::Hard => (8, 9),
+            Difficulty::Expert => (10, usize::MAX),
+        }
+    }
+
+    pub fn random(rng: &mut impl Rng, difficulty: Difficulty) -> Result<Self> {
+        let (min, max) = Self::word_lengths(difficulty);
+        let words = QUIZ_DATA
+            .scramble_words
+            .iter()
+            .filter(|word| (min..=max).contains(&word.chars().count()))
+            .collect::<Vec<_>>();
+        let word = words
+            .choose(rng)
+            .ok_or_else(|| eyre!("No scramble words for {} difficulty", difficulty.as_str()))?
+            .to_lowercase();
+
+        Ok(Self {
+            scrambled: scramble(&word, rng),
+            word,
+            difficulty,
+        })
+    }
+}
+
+/// Shuffles the letters of `word`, retrying a few times so the result differs from the original.
+fn scramble(word: &str, rng: &mut impl Rng) -> String {
+    let mut letters = word.chars().collect::<Vec<_>>();
+    for _ in 0..10 {
+        letters.shuffle(rng);
+        let scrambled = letters.iter().collect::<String>();
+        if scrambled != word {
+            return scrambled;
+        }
+    }
+    letters.into_iter().rev().collect()
+}
+
+/// Whether `guess` uses exactly the same letters as `word`.
+fn is_anagram(word: &str, guess: &str) -> bool {
+    let mut word = word.chars().collect::<Vec<_>>();
+    let mut guess = guess.chars().collect::<Vec<_>>();
+    word.sort_unstable();
+    guess.sort_unstable();
+    word == guess
+}
+
+impl Quiz for WordScramble {
+    async fn generate(ctx: QuizContext<'_>) -> Result<Self> {
+        Self::random(ctx.rng, ctx.difficulty)
+    }
+
+    fn kind(&self) -> QuizKind {
+        QuizKind::WordScramble
+    }
+
+    fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    fn time_limit(&self) -> Duration {
+        Duration::from_secs(45)
+    }
+
+    fn render(&self, user_id: u64) -> Result<Command> {
+        Ok(Command::text(format!(
+            "<@{}> **WORD SCRAMBLE!** ({}) Unscramble this word in {} seconds:\n`{}`",
+            user_id,
+            self.difficulty.as_str(),
+            self.time_limit().as_secs(),
+            self.scrambled.to_uppercase()
+        )))
+    }
+
+    fn validate(&self, answer: &str, _elapsed: Duration) -> Option<Grade> {
+        let answer = normalize_answer(answer);
+        if answer == self.word {
+            return Some(Grade::full("Correct! Well done."));
+        }
+        // Another real word made from the same letters counts too
+        let known = QUIZ_DATA
+            .scramble_words
+            .iter()
+            .any(|word| word.to_lowercase() == answer);
+        (known && is_anagram(&self.word, &answer)).then(|| Grade::full("Correct! That works too."))
+    }
+
+    fn question(&self) -> String {
+        self.scrambled.clone()
+    }
+
+    fn answer(&self) -> String {
+        self.word.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    #[test]
+    fn scrambles_are_anagrams_of_a_fitting_word() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        for difficulty in Difficulty::ALL {
+            let (min, max) = WordScramble::word_lengths(difficulty);
+            for _ in 0..50 {
+                let quiz = WordScramble::random(&mut rng, difficulty).unwrap();
+                assert!((min..=max).contains(&quiz.word.len()), "{}", quiz.word);
+                assert_ne!(quiz.scrambled, quiz.word);
+                assert!(is_anagram(&quiz.word, &quiz.scrambled));
+                assert!(quiz.validate(&quiz.word.to_uppercase(), Duration::ZERO).is_some());
+                assert!(quiz.validate(&quiz.scrambled, Duration::ZERO).is_none());
+            }
+        }
+    }
+}